@@ -0,0 +1,89 @@
+//! Compares tracing camera rays one at a time with tracing them in packets, through
+//! bvhs of each width.
+//!
+//! Run with `cargo bench --bench packet_traversal`.
+
+use rand::rngs::ThreadRng;
+use std::time::{Duration, Instant};
+
+use rust_raytracer::bvh::{Bvh, BvhBuildOptions};
+use rust_raytracer::packet::PACKET_SIZE;
+use rust_raytracer::ray::Ray;
+use rust_raytracer::scene::{default_camera, load_builtin};
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 600;
+const NUM_RUNS: usize = 5;
+
+/// Returns the fastest of `NUM_RUNS` runs of `f`, along with the number of hits.
+fn time<F: Fn() -> usize>(f: F) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut num_hits = 0;
+    for _ in 0..NUM_RUNS {
+        let start = Instant::now();
+        num_hits = f();
+        best = best.min(start.elapsed());
+    }
+    (best, num_hits)
+}
+
+fn main() {
+    for &scene in &["suzanne", "random_spheres"] {
+        let camera = default_camera(WIDTH, HEIGHT);
+        // Rays in the order that the renderer traces them, down each column.
+        let rays: Vec<Ray> = (0..WIDTH)
+            .flat_map(|x| (0..HEIGHT).map(move |y| (x, y)))
+            .map(|(x, y)| camera.generate_ray(x, y, None::<&mut ThreadRng>))
+            .collect();
+        println!("{} ({} rays)", scene, rays.len());
+        let rays_per_second = |duration: Duration| rays.len() as f64 / duration.as_secs_f64();
+
+        for &width in &[2, 4, 8] {
+            let (objects, _) = load_builtin(scene).unwrap();
+            let options = BvhBuildOptions {
+                width,
+                ..Default::default()
+            };
+            let bvh = Bvh::with_options(objects, 1, options, None);
+            let (single_time, single_hits) = time(|| {
+                rays.iter()
+                    .filter(|ray| bvh.get_closest_intersection(ray).is_some())
+                    .count()
+            });
+            let (packet_time, packet_hits) = time(|| {
+                rays.chunks(PACKET_SIZE)
+                    .flat_map(|rays| bvh.get_closest_intersections(rays))
+                    .filter(Option::is_some)
+                    .count()
+            });
+            assert_eq!(single_hits, packet_hits);
+            // Packets find exactly the same surfaces as single rays, not just as many.
+            for rays in rays.chunks(PACKET_SIZE) {
+                for (ray, packet_hit) in rays.iter().zip(bvh.get_closest_intersections(rays)) {
+                    let single_hit = bvh.get_closest_intersection(ray);
+                    assert_eq!(
+                        packet_hit.map(|(_, interaction)| interaction),
+                        single_hit.map(|(_, interaction)| interaction)
+                    );
+                }
+            }
+
+            println!("  bvh width {} ({} hits)", width, single_hits);
+            println!(
+                "    single rays: {:>8.2} ms, {:>6.2} Mrays/s",
+                single_time.as_secs_f64() * 1000.0,
+                rays_per_second(single_time) / 1e6
+            );
+            println!(
+                "    packets of {}: {:>7.2} ms, {:>6.2} Mrays/s",
+                PACKET_SIZE,
+                packet_time.as_secs_f64() * 1000.0,
+                rays_per_second(packet_time) / 1e6
+            );
+            println!(
+                "    speedup: {:.2}x",
+                single_time.as_secs_f64() / packet_time.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::borrow::Cow;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::bvh::{build_mesh_bvhs, Bvh, BvhBuildOptions, BvhReport};
+use super::grid::Grid;
+use super::interaction::SurfaceInteraction;
+use super::kdtree::KdTree;
+use super::object::Object;
+use super::ray::Ray;
+
+/// A data structure over the objects of a scene that finds the objects a ray hits
+/// without testing every object.
+///
+/// This is only the top level over the objects themselves. Meshes always find
+/// their faces with their own bvh.
+pub trait Accelerator: Send + Sync {
+    /// If `ray` instersects some object, returns `Some((object, interaction))` where
+    /// `interaction` describes the surface of `object` at the closest hit. Otherwise
+    /// returns `None`.
+    ///
+    /// When a mesh is hit, `object` is the face that was hit. See `Object::get_face`.
+    fn get_closest_intersection(&self, ray: &Ray) -> Option<(Cow<'_, Object>, SurfaceInteraction)>;
+
+    /// Returns `get_closest_intersection` for each of `rays`. Accelerators that can
+    /// trace coherent rays faster together, such as those from the camera, do so.
+    fn get_closest_intersections(
+        &self,
+        rays: &[Ray],
+    ) -> Vec<Option<(Cow<'_, Object>, SurfaceInteraction)>> {
+        rays.iter()
+            .map(|ray| self.get_closest_intersection(ray))
+            .collect()
+    }
+
+    /// Returns true if `get_closest_intersections` traces rays together in packets.
+    /// Otherwise it only calls `get_closest_intersection` for each ray, so callers are
+    /// better off tracing rays one at a time.
+    fn traces_packets(&self) -> bool {
+        false
+    }
+
+    /// Returns true if `ray` hits any object at some `t <= max_t` within the range of
+    /// the ray, stopping at the first hit found.
+    fn is_occluded(&self, ray: &Ray, max_t: f32) -> bool;
+
+    /// Returns true if some objects let light pass through them.
+    fn has_transparent_objects(&self) -> bool;
+
+    /// Returns statistics about the bvhs that this accelerator uses.
+    fn get_bvh_report(&self) -> BvhReport;
+}
+
+/// The kind of `Accelerator` to render a scene with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AcceleratorType {
+    /// A bounding volume hierarchy. See `Bvh`.
+    Bvh,
+    /// A uniform grid. See `Grid`.
+    Grid,
+    /// A kd-tree built with the surface area heuristic. See `KdTree`.
+    KdTree,
+}
+
+impl FromStr for AcceleratorType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bvh" => Ok(AcceleratorType::Bvh),
+            "grid" => Ok(AcceleratorType::Grid),
+            "kdtree" => Ok(AcceleratorType::KdTree),
+            _ => Err(format!("Unknown accelerator {}", s)),
+        }
+    }
+}
+
+impl AcceleratorType {
+    /// Builds an accelerator of this type over `objects`.
+    ///
+    /// Whatever the type, the bvhs of meshes are built with `bvh_build_options` using
+    /// `num_threads` threads, and cached in `bvh_cache_dir` if given. So is the bvh
+    /// over the objects for `AcceleratorType::Bvh`. See `Bvh::with_options`.
+    pub fn build(
+        self,
+        objects: Vec<Object>,
+        num_threads: usize,
+        bvh_build_options: BvhBuildOptions,
+        bvh_cache_dir: Option<&Path>,
+    ) -> Box<dyn Accelerator> {
+        let build_meshes = |objects: &[Object]| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .unwrap();
+            pool.install(|| build_mesh_bvhs(objects, &bvh_build_options, bvh_cache_dir));
+        };
+        match self {
+            AcceleratorType::Bvh => Box::new(Bvh::with_options(
+                objects,
+                num_threads,
+                bvh_build_options,
+                bvh_cache_dir,
+            )),
+            AcceleratorType::Grid => {
+                build_meshes(&objects);
+                Box::new(Grid::new(objects))
+            }
+            AcceleratorType::KdTree => {
+                build_meshes(&objects);
+                Box::new(KdTree::new(objects))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Accelerator, AcceleratorType};
+    use crate::bvh::{Bvh, BvhBuildOptions};
+    use crate::test_scenes::{objects, ray_batches};
+
+    #[test]
+    fn test_accelerator_type() {
+        assert_eq!("bvh".parse(), Ok(AcceleratorType::Bvh));
+        assert_eq!("grid".parse(), Ok(AcceleratorType::Grid));
+        assert_eq!("kdtree".parse(), Ok(AcceleratorType::KdTree));
+        assert!("octree".parse::<AcceleratorType>().is_err());
+    }
+
+    #[test]
+    fn test_accelerators_match_bvh() {
+        let bvh = Bvh::new(objects());
+        for &accelerator_type in &[AcceleratorType::Grid, AcceleratorType::KdTree] {
+            let options = BvhBuildOptions::default();
+            let accelerator = accelerator_type.build(objects(), 1, options, None);
+            assert!(accelerator.get_bvh_report().objects.is_none());
+            assert_eq!(accelerator.get_bvh_report().meshes.len(), 1);
+            // Neither has a packet path, so the renderer traces rays one at a time.
+            assert!(!accelerator.traces_packets());
+
+            let mut num_hits = 0;
+            for ray in ray_batches().iter().flatten() {
+                let expected = bvh.get_closest_intersection(ray);
+                let actual = accelerator.get_closest_intersection(ray);
+                assert_eq!(actual.is_some(), expected.is_some());
+                if let (
+                    Some((actual, actual_interaction)),
+                    Some((expected, expected_interaction)),
+                ) = (actual, expected)
+                {
+                    num_hits += 1;
+                    assert_eq!(actual_interaction, expected_interaction);
+                    assert_eq!(actual.get_bounding_box(), expected.get_bounding_box());
+                }
+                for &max_t in &[1.0, 4.0, f32::INFINITY] {
+                    assert_eq!(
+                        accelerator.is_occluded(ray, max_t),
+                        bvh.is_occluded(ray, max_t)
+                    );
+                }
+            }
+            assert!(num_hits > 100);
+
+            let empty = accelerator_type.build(vec![], 1, options, None);
+            for ray in ray_batches().iter().flatten() {
+                assert!(empty.get_closest_intersection(ray).is_none());
+                assert!(!empty.is_occluded(ray, f32::INFINITY));
+            }
+        }
+    }
+
+    #[test]
+    fn test_packets_match_single_rays() {
+        let bvh = Bvh::new(objects());
+        assert!(bvh.traces_packets());
+        let mut num_hits = 0;
+        for rays in ray_batches() {
+            let packet_hits = bvh.get_closest_intersections(&rays);
+            assert_eq!(packet_hits.len(), rays.len());
+            for (ray, packet_hit) in rays.iter().zip(packet_hits) {
+                let expected = bvh.get_closest_intersection(ray);
+                assert_eq!(packet_hit.is_some(), expected.is_some());
+                if let (
+                    Some((actual, actual_interaction)),
+                    Some((expected, expected_interaction)),
+                ) = (packet_hit, expected)
+                {
+                    num_hits += 1;
+                    assert_eq!(actual_interaction, expected_interaction);
+                    assert_eq!(actual.get_bounding_box(), expected.get_bounding_box());
+                }
+            }
+        }
+        assert!(num_hits > 300);
+    }
+}
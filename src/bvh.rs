@@ -1,49 +1,1079 @@
-use super::object::Object;
+use super::accelerator::Accelerator;
+use super::interaction::SurfaceInteraction;
+use super::object::{Hit, Object};
+use super::packet::{self, Mask, RayPacket, PACKET_SIZE};
 use super::ray::Ray;
-use super::utils::component_wise_range;
+use super::utils::{component_wise_range, Fnv1aHasher};
+use super::wide_bvh::WideTree;
 use cgmath::{EuclideanSpace, Point3};
-use time;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
 /// Bounding Volume Hierarchy
+///
+/// This is the top level of a two-level hierarchy. Meshes have their own bvh over
+/// their faces, so instances of a mesh are single objects here.
 pub struct Bvh {
-    bvh_tree: BvhTree,
+    tree: LinearBvh,
+    objects: Vec<Object>,
+    has_transparent_objects: bool,
+    num_threads: usize,
+    options: BvhBuildOptions,
+    /// Where rebuilt bvhs are cached, as in `Bvh::with_cache`.
+    cache_dir: Option<PathBuf>,
+    stats: BvhStats,
+    /// The cost of `tree` when it was built. See `LinearBvh::get_cost`.
+    built_cost: f32,
 }
 
 impl Bvh {
+    /// Builds a bvh on a single thread.
     pub fn new(objects: Vec<Object>) -> Self {
-        let instant = time::Instant::now();
-        let num_objects = objects.len();
-        let bvh_tree = BvhTree::new(objects);
-        assert_eq!(bvh_tree.get_num_objects(), num_objects);
+        Bvh::with_num_threads(objects, 1)
+    }
+
+    /// Builds a bvh using `num_threads` threads. The resulting tree is the same as
+    /// the one built by `Bvh::new`.
+    pub fn with_num_threads(objects: Vec<Object>, num_threads: usize) -> Self {
+        Bvh::with_options(objects, num_threads, BvhBuildOptions::default(), None)
+    }
+
+    /// Loads the bvh for `objects` from `cache_dir` if it was saved there before, so
+    /// that the tree does not have to be built again. Otherwise builds the bvh using
+    /// `num_threads` threads and saves it to `cache_dir` for next time. The bvhs of
+    /// meshes are cached the same way.
+    ///
+    /// Cache files are named after a hash of the geometry of `objects` and the split
+    /// settings, so changing either builds a new bvh. Materials are not part of the
+    /// hash since they do not affect the tree.
+    pub fn with_cache<P>(objects: Vec<Object>, num_threads: usize, cache_dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let options = BvhBuildOptions::default();
+        Bvh::with_options(objects, num_threads, options, Some(cache_dir.as_ref()))
+    }
+
+    /// Builds a bvh with the given options using `num_threads` threads, caching it in
+    /// `cache_dir` if given as in `Bvh::with_cache`. The bvhs of meshes are built with
+    /// the same options, unless they were already built for another scene.
+    ///
+    /// Panics if `options` has a `max_leaf_size` of 0, fewer than 2 `num_buckets`, or a
+    /// `width` other than 2, 4, or 8.
+    pub fn with_options(
+        objects: Vec<Object>,
+        num_threads: usize,
+        options: BvhBuildOptions,
+        cache_dir: Option<&Path>,
+    ) -> Self {
+        let has_transparent_objects = !objects.iter().all(Object::is_opaque);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        let (tree, stats) = pool.install(|| {
+            build_mesh_bvhs(&objects, &options, cache_dir);
+            let bounding_boxes = objects
+                .par_iter()
+                .with_min_len(PARALLEL_BUILD_THRESHOLD)
+                .map(Object::get_bounding_box)
+                .collect();
+            let geometry_hash = get_geometry_hash(&objects);
+            build_or_load(bounding_boxes, geometry_hash, &options, cache_dir)
+        });
+        let built_cost = tree.get_cost();
+        Bvh {
+            tree,
+            objects,
+            has_transparent_objects,
+            num_threads,
+            options,
+            cache_dir: cache_dir.map(Path::to_path_buf),
+            stats,
+            built_cost,
+        }
+    }
+
+    /// Returns statistics about this bvh and the bvhs of the meshes that its objects
+    /// are instances of. The statistics of this bvh describe it as it was last refit
+    /// by `update_objects`, while the build time is that of the last build.
+    pub fn get_report(&self) -> BvhReport {
+        BvhReport {
+            objects: Some(self.stats.clone()),
+            meshes: get_mesh_bvh_stats(&self.objects),
+        }
+    }
+
+    /// Calls `update` on every object along with its index in the list the bvh was
+    /// built from, so that objects can be moved between the frames of an animation.
+    ///
+    /// The bounding boxes of the bvh are then refit around the moved objects, which
+    /// is much faster than building a new bvh but makes the tree worse as objects
+    /// move away from where they were when it was built. The bvh is rebuilt instead
+    /// once its cost grows by more than `REBUILD_THRESHOLD`, and cached in the same
+    /// directory as the first one, if any. Returns true if it was rebuilt.
+    pub fn update_objects<F>(&mut self, mut update: F) -> bool
+    where
+        F: FnMut(usize, &mut Object),
+    {
+        for (index, object) in self.objects.iter_mut().enumerate() {
+            update(index, object);
+        }
+        self.has_transparent_objects = !self.objects.iter().all(Object::is_opaque);
+
+        let objects = &self.objects;
+        self.tree.refit(|index| objects[index].get_bounding_box());
+        let cost = self.tree.get_cost();
+        if cost <= REBUILD_THRESHOLD * self.built_cost {
+            self.stats = self.tree.get_stats(
+                self.objects.len(),
+                &self.options,
+                self.stats.build_seconds,
+                self.stats.loaded_from_cache,
+            );
+            return false;
+        }
         debug!(
-            "Generated a bvh tree of {} objects with depth {} and total_sa {} in {} seconds.",
-            bvh_tree.get_num_objects(),
-            bvh_tree.get_depth(),
-            bvh_tree.total_sa(),
-            instant.elapsed().as_seconds_f32()
+            "Rebuilding the bvh since refitting raised its cost from {} to {}.",
+            self.built_cost, cost
+        );
+        let objects = std::mem::take(&mut self.objects);
+        let cache_dir = self.cache_dir.take();
+        *self = Bvh::with_options(
+            objects,
+            self.num_threads,
+            self.options,
+            cache_dir.as_deref(),
         );
-        Bvh { bvh_tree }
+        true
     }
 
-    /// If `ray` instersects some object, returns `Some((object, t))` such that the
-    /// intersection point is at `ray.get_point_on_ray(t)` on `object`. Otherwise
+    /// If `ray` instersects some object, returns `Some((object, interaction))` where
+    /// `interaction` describes the surface of `object` at the closest hit. Otherwise
     /// returns `None`.
     ///
-    /// Both `ray` and `t` are in world space coordinates.
-    pub fn get_closest_intersection(&self, ray: &Ray) -> Option<(&Object, f32)> {
-        self.bvh_tree.get_closest_intersection(ray)
+    /// When a mesh is hit, `object` is the face that was hit. See `Object::get_face`.
+    ///
+    /// Both `ray` and `interaction` are in world space coordinates.
+    pub fn get_closest_intersection(
+        &self,
+        ray: &Ray,
+    ) -> Option<(Cow<'_, Object>, SurfaceInteraction)> {
+        self.tree
+            .get_closest_intersection(ray, |index, ray| {
+                self.objects[index]
+                    .get_face_intersection(ray)
+                    .map(|hit| (hit.t, hit))
+            })
+            .map(|(index, _, hit)| self.shade_hit(index, ray, &hit))
+    }
+
+    /// Like `get_closest_intersection` for each of `rays`, but traces them in packets
+    /// of `PACKET_SIZE` rays that visit the nodes of the bvh together, which is faster
+    /// for coherent rays such as those from the camera.
+    pub fn get_closest_intersections(
+        &self,
+        rays: &[Ray],
+    ) -> Vec<Option<(Cow<'_, Object>, SurfaceInteraction)>> {
+        // Collecting a `flat_map` would grow the vector packet by packet.
+        let mut closest = Vec::with_capacity(rays.len());
+        for rays in rays.chunks(PACKET_SIZE) {
+            let packet = RayPacket::new(rays);
+            let hits = self
+                .tree
+                .get_closest_intersections(&packet, |index, packet| {
+                    let hits = self.objects[index].get_face_intersections(packet);
+                    packet::lanes(|lane| hits[lane].map(|hit| (hit.t, hit)))
+                });
+            closest.extend(
+                rays.iter()
+                    .zip(hits)
+                    .map(|(ray, hit)| hit.map(|(index, _, hit)| self.shade_hit(index, ray, &hit))),
+            );
+        }
+        closest
+    }
+
+    /// Returns the face of object `index` that `ray` makes `hit` on, along with the
+    /// surface there.
+    fn shade_hit(
+        &self,
+        index: usize,
+        ray: &Ray,
+        hit: &Hit,
+    ) -> (Cow<'_, Object>, SurfaceInteraction) {
+        let object = &self.objects[index];
+        (
+            object.get_face(hit.face),
+            object.get_surface_interaction(ray, hit),
+        )
+    }
+
+    /// Returns true if `ray` hits any object at some `t <= max_t` within the range of
+    /// the ray. Unlike `get_closest_intersection`, this stops at the first hit found.
+    ///
+    /// `ray` and `max_t` are in world space coordinates.
+    pub fn is_occluded(&self, ray: &Ray, max_t: f32) -> bool {
+        let t_max = ray.get_t_max().min(max_t);
+        if t_max < ray.get_t_min() {
+            return false;
+        }
+        let ray = ray.clone().with_range(ray.get_t_min(), t_max);
+        self.tree
+            .is_occluded(&ray, |index, ray| self.objects[index].intersects(ray))
+    }
+
+    /// Returns true if some objects let light pass through them.
+    pub fn has_transparent_objects(&self) -> bool {
+        self.has_transparent_objects
+    }
+}
+
+impl Accelerator for Bvh {
+    fn get_closest_intersection(&self, ray: &Ray) -> Option<(Cow<'_, Object>, SurfaceInteraction)> {
+        Bvh::get_closest_intersection(self, ray)
+    }
+
+    fn get_closest_intersections(
+        &self,
+        rays: &[Ray],
+    ) -> Vec<Option<(Cow<'_, Object>, SurfaceInteraction)>> {
+        Bvh::get_closest_intersections(self, rays)
+    }
+
+    fn traces_packets(&self) -> bool {
+        true
+    }
+
+    fn is_occluded(&self, ray: &Ray, max_t: f32) -> bool {
+        Bvh::is_occluded(self, ray, max_t)
+    }
+
+    fn has_transparent_objects(&self) -> bool {
+        Bvh::has_transparent_objects(self)
+    }
+
+    fn get_bvh_report(&self) -> BvhReport {
+        self.get_report()
+    }
+}
+
+/// Builds the bvhs of the meshes that `objects` are instances of using the current
+/// rayon thread pool. Instances only need the bounding box of their mesh, but
+/// building the bvhs of meshes up front keeps that work out of the render.
+pub(crate) fn build_mesh_bvhs(
+    objects: &[Object],
+    options: &BvhBuildOptions,
+    cache_dir: Option<&Path>,
+) {
+    for object in objects {
+        object.build_mesh_bvh(options, cache_dir);
+    }
+}
+
+/// Returns the statistics of the bvh of each mesh that `objects` are instances of,
+/// once per mesh.
+pub(crate) fn get_mesh_bvh_stats(objects: &[Object]) -> Vec<BvhStats> {
+    let mut meshes = HashSet::new();
+    objects
+        .iter()
+        .filter_map(Object::get_mesh)
+        .filter(|mesh| meshes.insert(Arc::as_ptr(mesh)))
+        .filter_map(|mesh| mesh.get_bvh_stats().cloned())
+        .collect()
+}
+
+/// Settings for building a bvh.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BvhBuildOptions {
+    pub split_type: SplitType,
+    /// Nodes with at most this many primitives become leaves. Must be at least 1.
+    pub max_leaf_size: usize,
+    /// The number of buckets that SAH splits choose between. Must be at least 2.
+    pub num_buckets: usize,
+    /// The cost of visiting a node relative to intersecting a primitive, which SAH
+    /// splits and `BvhStats::sah_cost` use.
+    pub traversal_cost: f32,
+    /// The maximum number of children of the nodes that rays traverse, which
+    /// must be 2, 4, or 8. Bvhs are always built as binary trees, which are then
+    /// collapsed into wider ones so that several bounding boxes are tested at once.
+    ///
+    /// Cache files hold the binary tree, so the width is not part of their key.
+    pub width: usize,
+}
+
+impl Default for BvhBuildOptions {
+    fn default() -> Self {
+        BvhBuildOptions {
+            split_type: SplitType::SAH,
+            max_leaf_size: BVH_LEAF_SIZE,
+            num_buckets: N_BUCKETS,
+            traversal_cost: TRAVERSAL_COST,
+            width: BVH_WIDTH,
+        }
+    }
+}
+
+/// Statistics about a built bvh. See `Bvh::get_report`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BvhStats {
+    pub num_primitives: usize,
+    /// Greater than `num_primitives` when spatial splits divide primitives between
+    /// several leaves.
+    pub num_references: usize,
+    pub num_nodes: usize,
+    pub num_leaves: usize,
+    /// The number of leaves with each number of primitives, indexed by that number.
+    pub leaf_size_histogram: Vec<usize>,
+    /// The number of edges on the longest path from the root to a leaf.
+    pub depth: usize,
+    /// The maximum number of children of the nodes that rays traverse. See
+    /// `BvhBuildOptions::width`.
+    pub width: usize,
+    /// The number of nodes of the tree collapsed to `width`, or `None` for a width
+    /// of 2.
+    pub num_wide_nodes: Option<usize>,
+    /// The expected cost of tracing a ray that hits the root, in units of primitive
+    /// intersections, according to the surface area heuristic.
+    pub sah_cost: f32,
+    /// The time it took to build the bvh, or to load it from the cache.
+    pub build_seconds: f32,
+    pub loaded_from_cache: bool,
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} primitives in {} references, {} nodes, {} leaves, depth {}",
+            self.num_primitives, self.num_references, self.num_nodes, self.num_leaves, self.depth
+        )?;
+        if let Some(num_wide_nodes) = self.num_wide_nodes {
+            writeln!(
+                f,
+                "Collapsed to {} nodes of up to {} children",
+                num_wide_nodes, self.width
+            )?;
+        }
+        let action = if self.loaded_from_cache {
+            "loaded from cache"
+        } else {
+            "built"
+        };
+        writeln!(
+            f,
+            "SAH cost {:.3}, {} in {:.3} seconds",
+            self.sah_cost, action, self.build_seconds
+        )?;
+        let leaf_sizes: Vec<String> = self
+            .leaf_size_histogram
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(size, count)| format!("{}: {}", size, count))
+            .collect();
+        write!(f, "Leaves by size: {}", leaf_sizes.join(", "))
+    }
+}
+
+/// Statistics about the bvhs used to render a scene.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BvhReport {
+    /// The top level bvh over the objects of the scene, or `None` if the scene was
+    /// rendered with another accelerator. See `AcceleratorType`.
+    pub objects: Option<BvhStats>,
+    /// The bvh of each mesh that the objects are instances of.
+    pub meshes: Vec<BvhStats>,
+}
+
+impl BvhReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl fmt::Display for BvhReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let indent = |stats: &BvhStats| stats.to_string().replace('\n', "\n  ");
+        let mut sections = vec![];
+        if let Some(objects) = &self.objects {
+            sections.push(format!("Objects bvh:\n  {}", indent(objects)));
+        }
+        for (i, mesh) in self.meshes.iter().enumerate() {
+            sections.push(format!("Mesh {} bvh:\n  {}", i, indent(mesh)));
+        }
+        if sections.is_empty() {
+            write!(f, "No bvhs were built.")
+        } else {
+            write!(f, "{}", sections.join("\n"))
+        }
+    }
+}
+
+/// A bvh stored as a flat array of nodes in depth first order, as in pbrt's
+/// `LinearBVHNode`. Primitives are referred to by their index in the list that the
+/// bvh was built from, which is kept by the owner of the bvh.
+pub(crate) struct LinearBvh {
+    pub(crate) nodes: Vec<LinearBvhNode>,
+    /// The primitives of each leaf, stored contiguously. A primitive appears more
+    /// than once if spatial splits divided it between several leaves.
+    primitive_indices: Vec<usize>,
+    /// This tree collapsed into a wider one, which rays traverse instead when it is
+    /// built with a `BvhBuildOptions::width` above 2.
+    wide: Option<WideTree>,
+}
+
+impl LinearBvh {
+    /// Builds a bvh over `primitives` using the current rayon thread pool.
+    pub(crate) fn new<T: Bounded + Clone>(primitives: Vec<T>, options: &BvhBuildOptions) -> Self {
+        assert!(
+            options.max_leaf_size >= 1,
+            "max_leaf_size must be at least 1"
+        );
+        assert!(options.num_buckets >= 2, "num_buckets must be at least 2");
+        assert!(
+            matches!(options.width, 2 | 4 | 8),
+            "width must be 2, 4, or 8"
+        );
+        let num_primitives = primitives.len();
+        let indexed_primitives: Vec<(usize, T)> = primitives.into_iter().enumerate().collect();
+        let mut bvh = LinearBvh {
+            nodes: vec![],
+            primitive_indices: vec![],
+            wide: None,
+        };
+        if options.split_type == SplitType::Spatial {
+            let references: Vec<Reference<(usize, T)>> =
+                indexed_primitives.into_iter().map(Reference::new).collect();
+            let root_area = AABB::union(references.iter().map(|r| r.aabb).collect()).surface_area();
+            let budget = (SPATIAL_SPLIT_BUDGET * num_primitives as f32) as usize;
+            let bvh_tree = BvhTree::new_spatial(references, options, root_area, budget, 0);
+            let mut references = Vec::with_capacity(bvh_tree.get_num_objects());
+            bvh.flatten(bvh_tree, &mut references);
+            bvh.primitive_indices = references.into_iter().map(|r| r.primitive.0).collect();
+        } else {
+            let bvh_tree = BvhTree::new(indexed_primitives, options);
+            assert_eq!(bvh_tree.get_num_objects(), num_primitives);
+            let mut ordered_primitives = Vec::with_capacity(num_primitives);
+            bvh.flatten(bvh_tree, &mut ordered_primitives);
+            bvh.primitive_indices = ordered_primitives.into_iter().map(|(i, _)| i).collect();
+        }
+        bvh.collapse(options.width);
+        bvh
+    }
+
+    /// Collapses this tree into one with up to `width` children per node for single
+    /// rays to traverse. See `WideBvh::new`.
+    fn collapse(&mut self, width: usize) {
+        self.wide = WideTree::new(self, width);
+    }
+
+    /// Appends `bvh_tree` to the nodes in depth first order, and its primitives to
+    /// `primitives`, and returns the index of its root node.
+    fn flatten<T>(&mut self, bvh_tree: BvhTree<T>, primitives: &mut Vec<T>) -> usize {
+        let index = self.nodes.len();
+        match bvh_tree {
+            BvhTree::Node(aabb, left, right, _size, axis) => {
+                // The second child offset is filled in once the left subtree is added.
+                self.nodes.push(LinearBvhNode {
+                    aabb,
+                    node_type: LinearBvhNodeType::Interior {
+                        second_child_offset: 0,
+                        axis,
+                    },
+                });
+                self.flatten(*left, primitives);
+                let offset = self.flatten(*right, primitives);
+                self.nodes[index].node_type = LinearBvhNodeType::Interior {
+                    second_child_offset: offset,
+                    axis,
+                };
+            }
+            BvhTree::Leaf(aabb, leaf_primitives, size) => {
+                self.nodes.push(LinearBvhNode {
+                    aabb,
+                    node_type: LinearBvhNodeType::Leaf {
+                        primitives_offset: primitives.len(),
+                        num_primitives: size,
+                    },
+                });
+                primitives.extend(leaf_primitives);
+            }
+        }
+        index
+    }
+
+    /// Returns the index of the primitive with the closest intersection for which
+    /// `intersect` returns `Some((t, hit))`, along with `t` and `hit`.
+    ///
+    /// `intersect` is called with the index of a primitive and a copy of `ray` whose
+    /// range ends at the closest hit found so far.
+    pub(crate) fn get_closest_intersection<F, H>(
+        &self,
+        ray: &Ray,
+        mut intersect: F,
+    ) -> Option<(usize, f32, H)>
+    where
+        F: FnMut(usize, &Ray) -> Option<(f32, H)>,
+    {
+        if let Some(wide) = &self.wide {
+            return wide.get_closest_intersection(&self.primitive_indices, ray, intersect);
+        }
+        let direction = ray.get_direction();
+        // Shrink the range of the ray as closer hits are found so that nodes and
+        // primitives beyond the closest hit are skipped.
+        let mut ray = ray.clone();
+        let mut closest: Option<(usize, f32, H)> = None;
+        // Indexes of nodes that still need to be visited.
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = match self.nodes.get(index) {
+                Some(node) => node,
+                // An empty bvh has no nodes.
+                None => break,
+            };
+            if node.aabb.intersect(&ray).is_none() {
+                continue;
+            }
+            match node.node_type {
+                LinearBvhNodeType::Interior {
+                    second_child_offset,
+                    axis,
+                } => {
+                    // Visit the nearer child first. The first child is stored right
+                    // after its parent and is on the negative side of `axis`.
+                    if direction[axis] < 0.0 {
+                        stack.push(index + 1);
+                        stack.push(second_child_offset);
+                    } else {
+                        stack.push(second_child_offset);
+                        stack.push(index + 1);
+                    }
+                }
+                LinearBvhNodeType::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => {
+                    let primitives = primitives_offset..primitives_offset + num_primitives;
+                    for &primitive in &self.primitive_indices[primitives] {
+                        if let Some((t, hit)) = intersect(primitive, &ray) {
+                            if closest
+                                .as_ref()
+                                .is_none_or(|(_, closest_t, _)| t < *closest_t)
+                            {
+                                closest = Some((primitive, t, hit));
+                                let t_min = ray.get_t_min();
+                                ray = ray.with_range(t_min, t);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    /// Like `get_closest_intersection` for each active ray of `packet`, visiting the
+    /// nodes that any of them hit together. Returns `None` for inactive rays.
+    ///
+    /// `intersect` is called with the index of a primitive and a copy of `packet` with
+    /// only the rays that hit its leaf active, whose ranges end at the closest hits
+    /// found so far.
+    pub(crate) fn get_closest_intersections<F, H>(
+        &self,
+        packet: &RayPacket,
+        mut intersect: F,
+    ) -> [Option<(usize, f32, H)>; PACKET_SIZE]
+    where
+        F: FnMut(usize, &RayPacket) -> [Option<(f32, H)>; PACKET_SIZE],
+    {
+        if let Some(wide) = &self.wide {
+            return wide.get_closest_intersections(&self.primitive_indices, packet, intersect);
+        }
+        let mut packet = *packet;
+        let mut closest: [Option<(usize, f32, H)>; PACKET_SIZE] = packet::lanes(|_| None);
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = match self.nodes.get(index) {
+                Some(node) => node,
+                None => break,
+            };
+            let mask = node.aabb.intersect_packet(&packet);
+            let first_lane = match mask.iter().position(|&hit| hit) {
+                Some(lane) => lane,
+                None => continue,
+            };
+            match node.node_type {
+                LinearBvhNodeType::Interior {
+                    second_child_offset,
+                    axis,
+                } => {
+                    // Rays in a packet mostly point the same way, so visit the child
+                    // that is nearer for the first of them first.
+                    if packet.direction[axis][first_lane] < 0.0 {
+                        stack.push(index + 1);
+                        stack.push(second_child_offset);
+                    } else {
+                        stack.push(second_child_offset);
+                        stack.push(index + 1);
+                    }
+                }
+                LinearBvhNodeType::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => {
+                    let primitives = primitives_offset..primitives_offset + num_primitives;
+                    for &primitive in &self.primitive_indices[primitives] {
+                        let hits = intersect(primitive, &packet.with_active(mask));
+                        for (lane, hit) in IntoIterator::into_iter(hits).enumerate() {
+                            if let Some((t, hit)) = hit {
+                                if closest[lane]
+                                    .as_ref()
+                                    .is_none_or(|(_, closest_t, _)| t < *closest_t)
+                                {
+                                    closest[lane] = Some((primitive, t, hit));
+                                    packet.t_max[lane] = t;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    /// Returns true if `intersects` returns true for any primitive in a leaf that
+    /// `ray` passes through, stopping at the first one.
+    pub(crate) fn is_occluded<F>(&self, ray: &Ray, mut intersects: F) -> bool
+    where
+        F: FnMut(usize, &Ray) -> bool,
+    {
+        if let Some(wide) = &self.wide {
+            return wide.is_occluded(&self.primitive_indices, ray, intersects);
+        }
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = match self.nodes.get(index) {
+                Some(node) => node,
+                None => break,
+            };
+            if node.aabb.intersect(ray).is_none() {
+                continue;
+            }
+            match node.node_type {
+                LinearBvhNodeType::Interior {
+                    second_child_offset,
+                    ..
+                } => {
+                    stack.push(second_child_offset);
+                    stack.push(index + 1);
+                }
+                LinearBvhNodeType::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => {
+                    let primitives = primitives_offset..primitives_offset + num_primitives;
+                    if self.primitive_indices[primitives]
+                        .iter()
+                        .any(|&primitive| intersects(primitive, ray))
+                    {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Returns the number of edges on the longest path from the root to a leaf.
+    fn get_depth(&self) -> usize {
+        let mut depth = 0;
+        let mut stack = vec![(0, 0)];
+        while let Some((index, node_depth)) = stack.pop() {
+            match self.nodes.get(index).map(|node| node.node_type) {
+                Some(LinearBvhNodeType::Interior {
+                    second_child_offset,
+                    ..
+                }) => {
+                    stack.push((index + 1, node_depth + 1));
+                    stack.push((second_child_offset, node_depth + 1));
+                }
+                Some(LinearBvhNodeType::Leaf { .. }) => depth = depth.max(node_depth),
+                None => {}
+            }
+        }
+        depth
     }
+
+    /// Returns the statistics of this bvh, which was built over `num_primitives`
+    /// primitives with `options` in `build_seconds`.
+    fn get_stats(
+        &self,
+        num_primitives: usize,
+        options: &BvhBuildOptions,
+        build_seconds: f32,
+        loaded_from_cache: bool,
+    ) -> BvhStats {
+        let root_area = self
+            .nodes
+            .first()
+            .map_or(0.0, |root| root.aabb.surface_area());
+        // The chance that a ray through the root also passes through a node.
+        let get_probability = |node: &LinearBvhNode| {
+            if root_area > 0.0 {
+                node.aabb.surface_area() / root_area
+            } else {
+                1.0
+            }
+        };
+        let mut leaf_size_histogram = vec![];
+        let mut sah_cost = 0.0;
+        for node in &self.nodes {
+            match node.node_type {
+                LinearBvhNodeType::Interior { .. } => {
+                    sah_cost += options.traversal_cost * get_probability(node);
+                }
+                LinearBvhNodeType::Leaf { num_primitives, .. } => {
+                    if leaf_size_histogram.len() <= num_primitives {
+                        leaf_size_histogram.resize(num_primitives + 1, 0);
+                    }
+                    leaf_size_histogram[num_primitives] += 1;
+                    sah_cost += num_primitives as f32 * get_probability(node);
+                }
+            }
+        }
+        BvhStats {
+            num_primitives,
+            num_references: self.primitive_indices.len(),
+            num_nodes: self.nodes.len(),
+            num_leaves: leaf_size_histogram.iter().sum(),
+            leaf_size_histogram,
+            depth: self.get_depth(),
+            width: options.width,
+            num_wide_nodes: self.wide.as_ref().map(WideTree::get_num_nodes),
+            sah_cost,
+            build_seconds,
+            loaded_from_cache,
+        }
+    }
+
+    /// Total surface area of all the bounding boxes in this bvh
+    fn total_sa(&self) -> f32 {
+        self.nodes.iter().map(|node| node.aabb.surface_area()).sum()
+    }
+
+    /// Returns `total_sa` relative to the surface area of the root, which is
+    /// proportional to the expected number of nodes that a ray through the root
+    /// visits. Unlike `total_sa`, this does not change when the whole scene is scaled.
+    fn get_cost(&self) -> f32 {
+        match self.nodes.first() {
+            Some(root) if root.aabb.surface_area() > 0.0 => {
+                self.total_sa() / root.aabb.surface_area()
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Recomputes the bounding boxes of all the nodes from the bottom up, keeping the
+    /// structure of the tree. `get_bounding_box` returns the bounding box of a
+    /// primitive given its index.
+    ///
+    /// Primitives that spatial splits divided between leaves are refit as a whole, so
+    /// each of those leaves grows to contain all of the primitive.
+    fn refit<F>(&mut self, get_bounding_box: F)
+    where
+        F: Fn(usize) -> (Point3<f32>, Point3<f32>),
+    {
+        // Children are stored after their parents, so they are refit first.
+        for index in (0..self.nodes.len()).rev() {
+            let aabbs = match self.nodes[index].node_type {
+                LinearBvhNodeType::Interior {
+                    second_child_offset,
+                    ..
+                } => vec![
+                    self.nodes[index + 1].aabb,
+                    self.nodes[second_child_offset].aabb,
+                ],
+                LinearBvhNodeType::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => self.primitive_indices[primitives_offset..primitives_offset + num_primitives]
+                    .iter()
+                    .map(|&primitive| {
+                        let (min, max) = get_bounding_box(primitive);
+                        AABB::new(min, max)
+                    })
+                    .collect(),
+            };
+            self.nodes[index].aabb = AABB::union(aabbs);
+        }
+        if let Some(width) = self.wide.as_ref().map(WideTree::get_width) {
+            self.collapse(width);
+        }
+    }
+
+    /// Writes this bvh to a cache file at `path`.
+    ///
+    /// The file is written next to `path` and then renamed so that other renders
+    /// never read a partially written file.
+    fn save(&self, path: &Path, key: u64, num_primitives: usize) -> std::io::Result<()> {
+        let temp_path = path.with_extension(format!("bvh.{}", std::process::id()));
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        writer.write_all(CACHE_MAGIC)?;
+        write_u64(&mut writer, key)?;
+        write_u64(&mut writer, num_primitives as u64)?;
+        write_u64(&mut writer, self.primitive_indices.len() as u64)?;
+        write_u64(&mut writer, self.nodes.len() as u64)?;
+        for &index in &self.primitive_indices {
+            write_u64(&mut writer, index as u64)?;
+        }
+        for node in &self.nodes {
+            for point in &[node.aabb.min, node.aabb.max] {
+                let coordinates: &[f32; 3] = point.as_ref();
+                for x in coordinates {
+                    writer.write_all(&x.to_le_bytes())?;
+                }
+            }
+            let (tag, a, b) = match node.node_type {
+                LinearBvhNodeType::Interior {
+                    second_child_offset,
+                    axis,
+                } => (0, second_child_offset, axis),
+                LinearBvhNodeType::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => (1, primitives_offset, num_primitives),
+            };
+            writer.write_all(&[tag])?;
+            write_u64(&mut writer, a as u64)?;
+            write_u64(&mut writer, b as u64)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp_path, path)
+    }
+
+    /// Reads a bvh saved by `LinearBvh::save`, checking that the file was saved for
+    /// `num_primitives` primitives with the given key, that every primitive is in
+    /// some leaf, and that all of its offsets are valid.
+    fn load(path: &Path, key: u64, num_primitives: usize) -> Result<Self, Box<dyn Error>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CACHE_MAGIC {
+            return Err("not a bvh cache file of this version".into());
+        }
+        if read_u64(&mut reader)? != key || read_u64(&mut reader)? != num_primitives as u64 {
+            return Err("the cache file is for different primitives".into());
+        }
+        let num_references = read_u64(&mut reader)? as usize;
+        if num_references < num_primitives || num_references > 2 * num_primitives {
+            return Err("invalid number of primitive references".into());
+        }
+        let num_nodes = read_u64(&mut reader)? as usize;
+        if num_nodes > 2 * num_references.max(1) {
+            return Err("too many nodes".into());
+        }
+
+        let mut is_used = vec![false; num_primitives];
+        let mut primitive_indices = Vec::with_capacity(num_references);
+        for _ in 0..num_references {
+            let index = read_u64(&mut reader)? as usize;
+            if index >= num_primitives {
+                return Err("invalid primitive index".into());
+            }
+            is_used[index] = true;
+            primitive_indices.push(index);
+        }
+        if !is_used.into_iter().all(|is_used| is_used) {
+            return Err("some primitives are not in any leaf".into());
+        }
+
+        let mut nodes = Vec::with_capacity(num_nodes);
+        for index in 0..num_nodes {
+            let mut coordinates = [0.0; 6];
+            for x in coordinates.iter_mut() {
+                *x = read_f32(&mut reader)?;
+            }
+            let [min_x, min_y, min_z, max_x, max_y, max_z] = coordinates;
+            if !(min_x <= max_x && min_y <= max_y && min_z <= max_z) {
+                return Err("invalid bounding box".into());
+            }
+            let aabb = AABB::new((min_x, min_y, min_z).into(), (max_x, max_y, max_z).into());
+            let mut tag = [0];
+            reader.read_exact(&mut tag)?;
+            let a = read_u64(&mut reader)? as usize;
+            let b = read_u64(&mut reader)? as usize;
+            let node_type = match tag[0] {
+                0 if index + 1 < a && a < num_nodes && b < 3 => LinearBvhNodeType::Interior {
+                    second_child_offset: a,
+                    axis: b,
+                },
+                1 if a <= num_references && b <= num_references - a => LinearBvhNodeType::Leaf {
+                    primitives_offset: a,
+                    num_primitives: b,
+                },
+                _ => return Err("invalid node".into()),
+            };
+            nodes.push(LinearBvhNode { aabb, node_type });
+        }
+        Ok(LinearBvh {
+            nodes,
+            primitive_indices,
+            wide: None,
+        })
+    }
+}
+
+/// Builds a bvh over `primitives` using the current rayon thread pool, or loads it
+/// from `cache_dir` if it was saved there before. Newly built bvhs are saved to
+/// `cache_dir`.
+///
+/// `geometry_hash` must identify the primitives. Returns the bvh along with its
+/// statistics.
+pub(crate) fn build_or_load<T: Bounded + Clone>(
+    primitives: Vec<T>,
+    geometry_hash: u64,
+    options: &BvhBuildOptions,
+    cache_dir: Option<&Path>,
+) -> (LinearBvh, BvhStats) {
+    let instant = time::Instant::now();
+    let num_primitives = primitives.len();
+    let key = get_cache_key(geometry_hash, options);
+    let path = cache_dir.map(|cache_dir| cache_dir.join(format!("{:016x}.bvh", key)));
+    if let Some(path) = &path {
+        match LinearBvh::load(path, key, num_primitives) {
+            Ok(mut loaded) => {
+                loaded.collapse(options.width);
+                let seconds = instant.elapsed().as_seconds_f32();
+                debug!(
+                    "Loaded a bvh tree of {} primitives from {} in {} seconds.",
+                    num_primitives,
+                    path.display(),
+                    seconds
+                );
+                let stats = loaded.get_stats(num_primitives, options, seconds, true);
+                return (loaded, stats);
+            }
+            Err(err) => debug!("Unable to load bvh from {}: {}", path.display(), err),
+        }
+    }
+
+    let bvh = LinearBvh::new(primitives, options);
+    let stats = bvh.get_stats(
+        num_primitives,
+        options,
+        instant.elapsed().as_seconds_f32(),
+        false,
+    );
+    debug!(
+        "Generated a bvh tree of {} primitives ({} references) with depth {} and SAH cost {} in {} seconds using {} threads.",
+        num_primitives,
+        stats.num_references,
+        stats.depth,
+        stats.sah_cost,
+        stats.build_seconds,
+        rayon::current_num_threads()
+    );
+    if let (Some(cache_dir), Some(path)) = (cache_dir, &path) {
+        let saved = fs::create_dir_all(cache_dir).and_then(|_| bvh.save(path, key, num_primitives));
+        if let Err(err) = saved {
+            warn!("Unable to save bvh to {}: {}", path.display(), err);
+        }
+    }
+    (bvh, stats)
+}
+
+/// How many times its cost when built the cost of a refit bvh can grow to before it
+/// is rebuilt. See `Bvh::update_objects`.
+pub const REBUILD_THRESHOLD: f32 = 1.5;
+
+/// Identifies bvh cache files. The last byte is the version of the file format.
+const CACHE_MAGIC: &[u8; 8] = b"RTBVH\0\0\x02";
+
+/// Returns a hash of the geometry of `objects`, in order.
+fn get_geometry_hash(objects: &[Object]) -> u64 {
+    let mut hasher = Fnv1aHasher::default();
+    hasher.write_usize(objects.len());
+    for object in objects {
+        object.hash_geometry(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Returns the key of the cache file for the bvh of primitives with the given
+/// geometry, which also depends on the split settings.
+fn get_cache_key(geometry_hash: u64, options: &BvhBuildOptions) -> u64 {
+    let mut hasher = Fnv1aHasher::default();
+    hasher.write_usize(options.max_leaf_size);
+    hasher.write_usize(options.num_buckets);
+    hasher.write_u32(options.traversal_cost.to_bits());
+    hasher.write_u8(options.split_type as u8);
+    hasher.write_u64(geometry_hash);
+    hasher.finish()
+}
+
+fn write_u64<W: Write>(writer: &mut W, x: u64) -> std::io::Result<()> {
+    writer.write_all(&x.to_le_bytes())
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32<R: Read>(reader: &mut R) -> std::io::Result<f32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum LinearBvhNodeType {
+    /// The first child directly follows its parent in `LinearBvh::nodes`. Primitives
+    /// in the first child have smaller centroids along `axis` than those in the second,
+    /// except for the naive and x axis splits, whose first child only comes first
+    /// along `axis` as a whole.
+    Interior {
+        second_child_offset: usize,
+        axis: usize,
+    },
+    /// Refers to the primitives `primitives_offset..primitives_offset + num_primitives`.
+    Leaf {
+        primitives_offset: usize,
+        num_primitives: usize,
+    },
+}
+
+/// A node of the flattened bvh.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct LinearBvhNode {
+    pub(crate) aabb: AABB,
+    pub(crate) node_type: LinearBvhNodeType,
 }
 
 /// Axis-aligned Minimum Bounding Box
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone)]
-struct AABB {
-    min: Point3<f32>,
-    max: Point3<f32>,
+pub(crate) struct AABB {
+    pub(crate) min: Point3<f32>,
+    pub(crate) max: Point3<f32>,
 }
 
 impl AABB {
-    fn new(min: Point3<f32>, max: Point3<f32>) -> Self {
+    pub(crate) fn new(min: Point3<f32>, max: Point3<f32>) -> Self {
         debug_assert!(min.x <= max.x);
         debug_assert!(min.y <= max.y);
         debug_assert!(min.z <= max.z);
@@ -55,14 +1085,61 @@ impl AABB {
         AABB::new((0.0, 0.0, 0.0).into(), (0.0, 0.0, 0.0).into())
     }
 
-    /// Returns `Some(t)` if `ray` intersects this bounding box at a point give by
-    /// `ray.get_point_on_ray(t)`. Otherwise returns `None`.
+    /// Returns the smallest bounding box that contains both boxes.
+    fn merge(&self, other: &AABB) -> Self {
+        AABB::new(
+            (
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            )
+                .into(),
+            (
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            )
+                .into(),
+        )
+    }
+
+    /// Returns the part of this bounding box that is also in `other`, if any.
+    fn intersection(&self, other: &AABB) -> Option<Self> {
+        let min: Point3<f32> = (
+            self.min.x.max(other.min.x),
+            self.min.y.max(other.min.y),
+            self.min.z.max(other.min.z),
+        )
+            .into();
+        let max: Point3<f32> = (
+            self.max.x.min(other.max.x),
+            self.max.y.min(other.max.y),
+            self.max.z.min(other.max.z),
+        )
+            .into();
+        if min.x <= max.x && min.y <= max.y && min.z <= max.z {
+            Some(AABB::new(min, max))
+        } else {
+            None
+        }
+    }
+
+    /// Returns `Some(t)` if `ray` intersects this bounding box within the range of the
+    /// ray, where `ray.get_point_on_ray(t)` is the first such point inside the box.
+    /// Otherwise returns `None`.
     fn intersect(&self, ray: &Ray) -> Option<f32> {
+        self.get_ray_range(ray).map(|(t_min, _)| t_min)
+    }
+
+    /// Returns `Some((t_min, t_max))` if `ray` intersects this bounding box within
+    /// the range of the ray, where `[t_min, t_max]` is the part of that range for
+    /// which the ray is inside the box. Otherwise returns `None`.
+    pub(crate) fn get_ray_range(&self, ray: &Ray) -> Option<(f32, f32)> {
         enum Interval {
             Infinite,
             Closed(f32, f32),
             Empty,
-        };
+        }
 
         impl Interval {
             /// Construct the interval that a ray intersects some axis on an AABB.
@@ -120,22 +1197,42 @@ impl AABB {
         match t_interval {
             Interval::Infinite => unreachable!(),
             Interval::Closed(t_min, t_max) => {
-                if t_min < 0. {
-                    Some(t_max)
+                // Only the part of the box within the range of the ray counts.
+                let t_min = t_min.max(ray.get_t_min());
+                let t_max = t_max.min(ray.get_t_max());
+                if t_min <= t_max {
+                    Some((t_min, t_max))
                 } else {
-                    Some(t_min)
+                    None
                 }
             }
             Interval::Empty => None,
         }
     }
 
+    /// Returns which active rays of `packet` intersect this bounding box within their
+    /// ranges, as `intersect` would for each of them.
+    fn intersect_packet(&self, packet: &RayPacket) -> Mask {
+        let mut t_min = packet.t_min;
+        let mut t_max = packet.t_max;
+        let (min, max): ([f32; 3], [f32; 3]) = (self.min.into(), self.max.into());
+        for axis in 0..3 {
+            let position = &packet.position[axis];
+            let inverse_direction = &packet.inverse_direction[axis];
+            let t0 = packet::lanes(|lane| (min[axis] - position[lane]) * inverse_direction[lane]);
+            let t1 = packet::lanes(|lane| (max[axis] - position[lane]) * inverse_direction[lane]);
+            t_min = packet::lanes(|lane| t_min[lane].max(t0[lane].min(t1[lane])));
+            t_max = packet::lanes(|lane| t_max[lane].min(t0[lane].max(t1[lane])));
+        }
+        packet::lanes(|lane| packet.active[lane] && t_min[lane] <= t_max[lane])
+    }
+
     /// Return the union of all the bounding boxes.
-    fn union(aabbs: Vec<AABB>) -> Self {
+    pub(crate) fn union(aabbs: Vec<AABB>) -> Self {
         if aabbs.is_empty() {
             AABB::empty()
         } else {
-            let points = aabbs
+            let points: Vec<Point3<f32>> = aabbs
                 .iter()
                 .flat_map(|aabb| vec![aabb.min, aabb.max])
                 .collect();
@@ -144,7 +1241,7 @@ impl AABB {
         }
     }
 
-    fn surface_area(&self) -> f32 {
+    pub(crate) fn surface_area(&self) -> f32 {
         let diff = self.max - self.min;
         2. * diff.x * diff.y + 2. * diff.x * diff.z + 2. * diff.z * diff.y
     }
@@ -156,8 +1253,151 @@ impl Default for AABB {
     }
 }
 
+/// Anything with a bounding box can be sorted into a bvh.
+pub(crate) trait Bounded: Send + Sync {
+    fn get_bounding_box(&self) -> (Point3<f32>, Point3<f32>);
+
+    /// Returns the bounding box of the part of this primitive between `min` and `max`
+    /// along `axis`, or `None` if no part of it is. Used by spatial splits.
+    ///
+    /// Clips the bounding box by default, which is correct for any primitive but can
+    /// be much larger than the clipped primitive itself.
+    fn get_clipped_bounding_box(
+        &self,
+        axis: usize,
+        min: f32,
+        max: f32,
+    ) -> Option<(Point3<f32>, Point3<f32>)> {
+        clip_bounding_box(self.get_bounding_box(), axis, min, max)
+    }
+}
+
+/// Returns the part of `bounding_box` between `min` and `max` along `axis`, if any.
+pub(crate) fn clip_bounding_box(
+    bounding_box: (Point3<f32>, Point3<f32>),
+    axis: usize,
+    min: f32,
+    max: f32,
+) -> Option<(Point3<f32>, Point3<f32>)> {
+    let (mut box_min, mut box_max) = bounding_box;
+    box_min[axis] = box_min[axis].max(min);
+    box_max[axis] = box_max[axis].min(max);
+    if box_min[axis] <= box_max[axis] {
+        Some((box_min, box_max))
+    } else {
+        None
+    }
+}
+
+/// Returns the bounding box of the part of the convex polygon with the given
+/// vertices that lies between `min` and `max` along `axis`, if any.
+pub(crate) fn clip_polygon(
+    vertices: &[Point3<f32>],
+    axis: usize,
+    min: f32,
+    max: f32,
+) -> Option<(Point3<f32>, Point3<f32>)> {
+    // Sutherland-Hodgman clipping against each side of the slab. `distance` is
+    // positive on the side that is kept.
+    let clip = |vertices: Vec<Point3<f32>>, distance: &dyn Fn(&Point3<f32>) -> f32| {
+        let mut clipped = Vec::with_capacity(vertices.len() + 1);
+        for (i, a) in vertices.iter().enumerate() {
+            let b = vertices[(i + 1) % vertices.len()];
+            let (distance_a, distance_b) = (distance(a), distance(&b));
+            if distance_a >= 0.0 {
+                clipped.push(*a);
+            }
+            if (distance_a >= 0.0) != (distance_b >= 0.0) {
+                let t = distance_a / (distance_a - distance_b);
+                clipped.push(a + (b - a) * t);
+            }
+        }
+        clipped
+    };
+    let vertices = clip(vertices.to_vec(), &|p| p[axis] - min);
+    let vertices = clip(vertices, &|p| max - p[axis]);
+    if vertices.is_empty() {
+        return None;
+    }
+    let (mut clipped_min, mut clipped_max) = component_wise_range(&vertices);
+    // Rounding can put new vertices just outside the slab.
+    clipped_min[axis] = clipped_min[axis].max(min);
+    clipped_max[axis] = clipped_max[axis].min(max).max(clipped_min[axis]);
+    Some((clipped_min, clipped_max))
+}
+
+impl Bounded for Object {
+    fn get_bounding_box(&self) -> (Point3<f32>, Point3<f32>) {
+        Object::get_bounding_box(self)
+    }
+}
+
+/// The bounding box itself, as a `(min, max)` pair.
+impl Bounded for (Point3<f32>, Point3<f32>) {
+    fn get_bounding_box(&self) -> (Point3<f32>, Point3<f32>) {
+        *self
+    }
+}
+
+/// A primitive along with its index in the list that the bvh is built from.
+impl<T: Bounded> Bounded for (usize, T) {
+    fn get_bounding_box(&self) -> (Point3<f32>, Point3<f32>) {
+        self.1.get_bounding_box()
+    }
+
+    fn get_clipped_bounding_box(
+        &self,
+        axis: usize,
+        min: f32,
+        max: f32,
+    ) -> Option<(Point3<f32>, Point3<f32>)> {
+        self.1.get_clipped_bounding_box(axis, min, max)
+    }
+}
+
+/// The part of a primitive inside `aabb`. Spatial splits divide a primitive into
+/// several references, one for each leaf it ends up in.
+#[derive(Clone)]
+struct Reference<T> {
+    primitive: T,
+    aabb: AABB,
+}
+
+impl<T: Bounded> Reference<T> {
+    /// A reference to the whole primitive.
+    fn new(primitive: T) -> Self {
+        let (min, max) = primitive.get_bounding_box();
+        Reference {
+            primitive,
+            aabb: AABB::new(min, max),
+        }
+    }
+}
+
+impl<T: Bounded> Bounded for Reference<T> {
+    fn get_bounding_box(&self) -> (Point3<f32>, Point3<f32>) {
+        (self.aabb.min, self.aabb.max)
+    }
+
+    fn get_clipped_bounding_box(
+        &self,
+        axis: usize,
+        min: f32,
+        max: f32,
+    ) -> Option<(Point3<f32>, Point3<f32>)> {
+        let min = min.max(self.aabb.min[axis]);
+        let max = max.min(self.aabb.max[axis]);
+        if min > max {
+            return None;
+        }
+        let (clipped_min, clipped_max) = self.primitive.get_clipped_bounding_box(axis, min, max)?;
+        let clipped = AABB::new(clipped_min, clipped_max).intersection(&self.aabb)?;
+        Some((clipped.min, clipped.max))
+    }
+}
+
 /// Splits objects arbitrarily into two halves
-fn bvh_split_naive(objects: Vec<Object>) -> (Vec<Object>, Vec<Object>) {
+fn bvh_split_naive<T>(objects: Vec<T>) -> (Vec<T>, Vec<T>) {
     let mid = objects.len() / 2;
     let mut left = objects;
     let right = left.split_off(mid);
@@ -165,7 +1405,7 @@ fn bvh_split_naive(objects: Vec<Object>) -> (Vec<Object>, Vec<Object>) {
 }
 
 /// Splits objects into two halves after sorting by min x coordinate
-fn bvh_split_by_x_axis(mut objects: Vec<Object>) -> (Vec<Object>, Vec<Object>) {
+fn bvh_split_by_x_axis<T: Bounded>(mut objects: Vec<T>) -> (Vec<T>, Vec<T>) {
     objects.sort_by(|a, b| {
         let (amin, _amax) = a.get_bounding_box();
         let (bmin, _bmax) = b.get_bounding_box();
@@ -177,28 +1417,78 @@ fn bvh_split_by_x_axis(mut objects: Vec<Object>) -> (Vec<Object>, Vec<Object>) {
     (left, right)
 }
 
-#[derive(PartialEq, Eq)]
-enum SplitType {
+/// How the primitives of a bvh node are divided between its two children.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitType {
+    /// Splits the primitives in half in the order they were given.
+    Naive,
+    /// Splits the primitives in half after sorting them by their minimum x coordinate.
+    XAxis,
+    /// Splits at the midpoint of the primitive centroids.
     Basic,
+    /// Splits where the surface area heuristic estimates that rays are cheapest to
+    /// trace.
     SAH,
+    /// An SBVH, which also considers spatial splits that divide primitives crossing
+    /// the split plane between both children when that lowers the SAH cost. Takes
+    /// longer to build and uses more memory than `SAH`, but traces faster when long
+    /// thin primitives make the children of object splits overlap.
+    Spatial,
+}
+
+impl FromStr for SplitType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(SplitType::Naive),
+            "x_axis" => Ok(SplitType::XAxis),
+            "basic" => Ok(SplitType::Basic),
+            "sah" => Ok(SplitType::SAH),
+            "spatial" => Ok(SplitType::Spatial),
+            _ => Err(format!("Unknown split type {}", s)),
+        }
+    }
 }
 
 /// Splits objects into two halves along the dimension with largest range in
-/// object centroid positions.
+/// object centroid positions, and returns the halves and that dimension.
 /// If SplitType is Basic, splits down the midpoint (as in pbrt book section 4.4.1)
 /// If SplitType is SAH, splits using bucketing and a surface area heuristic (pbrt book section 4.4.2)
+/// SplitType Spatial splits the same way as SAH, since spatial splits are made by
+/// `BvhTree::new_spatial` rather than by partitioning objects.
+/// SplitType XAxis ignores the centroids and returns the x axis, and Naive returns
+/// the axis along which its halves are furthest apart, see `order_halves`.
+/// If a split would leave either half empty, such as when all of the centroids
+/// coincide, the objects are split at their median centroid instead.
 ///
 /// Reference material from pbrt:
 /// book https://www.pbrt.org/chapters/pbrt-2ed-chap4.pdf
 /// code https://github.com/mmp/pbrt-v3/blob/master/src/accelerators/bvh.cpp
 /// original SAH bucketing paper http://www.sci.utah.edu/~wald/Publications/2007/ParallelBVHBuild/fastbuild.pdf
-fn bvh_split(mut objects: Vec<Object>, split_type: SplitType) -> (Vec<Object>, Vec<Object>) {
-    let centroids = objects
-        .iter()
+fn bvh_split<T: Bounded>(
+    mut objects: Vec<T>,
+    options: &BvhBuildOptions,
+) -> (Vec<T>, Vec<T>, usize) {
+    match options.split_type {
+        SplitType::Naive => {
+            let (left, right) = bvh_split_naive(objects);
+            return order_halves(left, right);
+        }
+        SplitType::XAxis => {
+            let (left, right) = bvh_split_by_x_axis(objects);
+            return (left, right, 0);
+        }
+        _ => {}
+    }
+
+    let centroids: Vec<Point3<f32>> = objects
+        .par_iter()
+        .with_min_len(PARALLEL_BUILD_THRESHOLD)
         .map(|obj| {
             let (min, max) = obj.get_bounding_box();
-            let c = Point3::centroid(&[min, max]);
-            c
+            Point3::centroid(&[min, max])
         })
         .collect();
 
@@ -218,16 +1508,71 @@ fn bvh_split(mut objects: Vec<Object>, split_type: SplitType) -> (Vec<Object>, V
 
     // partition depending on split_type
     // for small sets of objects we always use the basic split strategy
-    if objects.len() <= 4 || split_type == SplitType::Basic {
-        let (left, right) = objects.drain(..).partition(|obj| {
+    // the SAH buckets need centroids that span some range along `maxdim`
+    let (left, right) = if objects.len() <= 4
+        || options.split_type == SplitType::Basic
+        || max_c[maxdim] == min_c[maxdim]
+    {
+        objects.drain(..).partition(|obj| {
             let (min, max) = obj.get_bounding_box();
             let c = Point3::centroid(&[min, max]);
             c[maxdim] < max_axis_midpoint
-        });
-        (left, right)
+        })
     } else {
-        bvh_split_by_sah(objects, &centroids, AABB::new(min_c, max_c), maxdim)
+        let centroid_bounds = AABB::new(min_c, max_c);
+        bvh_split_by_sah(objects, &centroids, centroid_bounds, maxdim, options)
+    };
+    if left.is_empty() || right.is_empty() {
+        // Splitting the same objects again would recurse forever.
+        let objects = left.into_iter().chain(right).collect();
+        let (left, right) = bvh_split_at_median(objects, maxdim);
+        return (left, right, maxdim);
     }
+    (left, right, maxdim)
+}
+
+/// Returns the axis along which the centers of the bounding boxes of `left` and
+/// `right` are furthest apart, with the halves swapped if needed so that `left` comes
+/// first along it. Traversal visits the children of a node in this order, so halves
+/// split in arbitrary order are still visited roughly front to back.
+fn order_halves<T: Bounded>(left: Vec<T>, right: Vec<T>) -> (Vec<T>, Vec<T>, usize) {
+    let get_center = |objects: &[T]| {
+        let aabbs = objects
+            .iter()
+            .map(|object| {
+                let (min, max) = object.get_bounding_box();
+                AABB::new(min, max)
+            })
+            .collect();
+        let aabb = AABB::union(aabbs);
+        Point3::centroid(&[aabb.min, aabb.max])
+    };
+    if left.is_empty() || right.is_empty() {
+        return (left, right, 0);
+    }
+    let offset = get_center(&right) - get_center(&left);
+    let axis = (0..3)
+        .max_by(|&a, &b| offset[a].abs().partial_cmp(&offset[b].abs()).unwrap())
+        .unwrap();
+    if offset[axis] < 0.0 {
+        (right, left, axis)
+    } else {
+        (left, right, axis)
+    }
+}
+
+/// Splits objects into two halves after sorting them by their centroids along `axis`,
+/// which leaves objects in both halves even when the centroids coincide.
+fn bvh_split_at_median<T: Bounded>(mut objects: Vec<T>, axis: usize) -> (Vec<T>, Vec<T>) {
+    let get_centroid = |object: &T| {
+        let (min, max) = object.get_bounding_box();
+        Point3::centroid(&[min, max])[axis]
+    };
+    objects.sort_by(|a, b| get_centroid(a).partial_cmp(&get_centroid(b)).unwrap());
+    let mid = objects.len() / 2;
+    let mut left = objects;
+    let right = left.split_off(mid);
+    (left, right)
 }
 
 // #[derive(Copy, Clone)]
@@ -238,40 +1583,73 @@ struct SplitBucket {
     obj_indexes: Vec<usize>,
 }
 
-const N_BUCKETS: u8 = 12;
+impl SplitBucket {
+    fn add(&mut self, index: usize, aabb: AABB) {
+        self.count += 1;
+        self.aabb = AABB::union(vec![self.aabb, aabb]);
+        self.obj_indexes.push(index);
+    }
+
+    /// Adds the objects of `other`, which must come after the objects of `self`.
+    fn merge(&mut self, other: SplitBucket) {
+        self.count += other.count;
+        self.aabb = AABB::union(vec![self.aabb, other.aabb]);
+        self.obj_indexes.extend(other.obj_indexes);
+    }
+}
+
+const N_BUCKETS: usize = 12;
+
+/// The default `BvhBuildOptions::traversal_cost`.
+const TRAVERSAL_COST: f32 = 0.125;
+
+/// Work on fewer objects than this is not worth splitting between threads.
+const PARALLEL_BUILD_THRESHOLD: usize = 1024;
 
 /// Splits objects into two halves in order to minimize the expected cost
 /// of a ray intersection query using the Surface Area Heuristic (SAH).
-fn bvh_split_by_sah(
-    mut objects: Vec<Object>,
-    centroids: &Vec<Point3<f32>>,
+fn bvh_split_by_sah<T: Bounded>(
+    mut objects: Vec<T>,
+    centroids: &[Point3<f32>],
     global_bb: AABB,
     dim: usize,
-) -> (Vec<Object>, Vec<Object>) {
-    let mut buckets: [SplitBucket; N_BUCKETS as usize] = Default::default();
-
+    options: &BvhBuildOptions,
+) -> (Vec<T>, Vec<T>) {
     // initialize SAH partition buckets
+    // Each thread fills its own buckets for a contiguous range of objects. Merging
+    // them in order gives exactly the buckets of a serial pass.
     let dim_range = global_bb.max[dim] - global_bb.min[dim];
-    for (i, c) in centroids.iter().enumerate() {
-        let b: f32 = f32::from(N_BUCKETS) * (c[dim] - global_bb.min[dim]) / dim_range;
-        let mut b_ind = b.trunc() as i32;
-        if b_ind == N_BUCKETS.into() {
-            b_ind -= 1;
-        }
+    let num_buckets = options.num_buckets;
+    let new_buckets =
+        || -> Vec<SplitBucket> { (0..num_buckets).map(|_| SplitBucket::default()).collect() };
+    let buckets = centroids
+        .par_iter()
+        .enumerate()
+        .with_min_len(PARALLEL_BUILD_THRESHOLD)
+        .fold(new_buckets, |mut buckets, (i, c)| {
+            let b: f32 = num_buckets as f32 * (c[dim] - global_bb.min[dim]) / dim_range;
+            let mut b_ind = b.trunc() as i32;
+            if b_ind == num_buckets as i32 {
+                b_ind -= 1;
+            }
 
-        debug_assert!(b_ind >= 0);
-        debug_assert!(b_ind < N_BUCKETS.into());
-        let b_ind = b_ind as usize;
+            debug_assert!(b_ind >= 0);
+            debug_assert!(b_ind < num_buckets as i32);
+            let b_ind = b_ind as usize;
 
-        let (obj_min, obj_max) = objects[i].get_bounding_box();
-        let obj_bb: AABB = AABB::new(obj_min, obj_max);
-        buckets[b_ind].count += 1;
-        buckets[b_ind].aabb = AABB::union(vec![buckets[b_ind].aabb, obj_bb]);
-        buckets[b_ind].obj_indexes.push(i);
-    }
+            let (obj_min, obj_max) = objects[i].get_bounding_box();
+            buckets[b_ind].add(i, AABB::new(obj_min, obj_max));
+            buckets
+        })
+        .reduce(new_buckets, |mut left, right| {
+            for (left_bucket, right_bucket) in left.iter_mut().zip(right) {
+                left_bucket.merge(right_bucket);
+            }
+            left
+        });
 
     // compute cost to split for each bucket
-    let range = 0..(N_BUCKETS - 1) as usize;
+    let range = 0..num_buckets - 1;
     let costs: Vec<f32> = range
         .map(|i| {
             // NOTE: there probably exists a much neater, rustic way to compute the
@@ -286,7 +1664,7 @@ fn bvh_split_by_sah(
                 .map(|(_j, bucket)| bucket.count as f32)
                 .sum();
 
-            0.125
+            options.traversal_cost
                 + (count_left * bb_left.surface_area() + count_right * bb_right.surface_area())
                     / global_bb.surface_area()
         })
@@ -320,9 +1698,12 @@ fn bvh_split_by_sah(
     (left, right)
 }
 
-enum BvhTree {
-    Node(AABB, Box<BvhTree>, Box<BvhTree>, usize),
-    Leaf(AABB, Vec<Object>, usize),
+/// The tree produced by splitting objects, before it is flattened into a `Bvh`.
+enum BvhTree<T> {
+    /// The last field is the axis the objects were split along, which traversal
+    /// visits the children in order of. See `LinearBvhNodeType::Interior`.
+    Node(AABB, Box<BvhTree<T>>, Box<BvhTree<T>>, usize, usize),
+    Leaf(AABB, Vec<T>, usize),
 }
 
 // PBRT uses a leaf size of 1, but empirically from a range of scenes, it seems
@@ -330,10 +1711,15 @@ enum BvhTree {
 // but provides a large reduction in BVH generation time for very large scenes).
 const BVH_LEAF_SIZE: usize = 4;
 
-impl BvhTree {
-    fn new(objects: Vec<Object>) -> Self {
+/// The default `BvhBuildOptions::width`. Wide trees can be faster for single rays,
+/// but packets of camera rays are traced faster through binary ones.
+const BVH_WIDTH: usize = 2;
+
+impl<T: Bounded> BvhTree<T> {
+    /// Builds the tree using the current rayon thread pool.
+    fn new(objects: Vec<T>, options: &BvhBuildOptions) -> Self {
         let size = objects.len();
-        if size <= BVH_LEAF_SIZE {
+        if size <= options.max_leaf_size {
             let aabbs = objects
                 .iter()
                 .map(|object| {
@@ -346,93 +1732,297 @@ impl BvhTree {
         } else {
             let size = objects.len();
             // let (left_objects, right_objects) = bvh_split_naive(objects);
-            let (left_objects, right_objects) = bvh_split(objects, SplitType::SAH);
-            let left = BvhTree::new(left_objects);
-            let right = BvhTree::new(right_objects);
+            let (left_objects, right_objects, axis) = bvh_split(objects, options);
+            let (left, right) = if size >= PARALLEL_BUILD_THRESHOLD {
+                rayon::join(
+                    || BvhTree::new(left_objects, options),
+                    || BvhTree::new(right_objects, options),
+                )
+            } else {
+                (
+                    BvhTree::new(left_objects, options),
+                    BvhTree::new(right_objects, options),
+                )
+            };
 
             let aabb = AABB::union(vec![left.get_aabb(), right.get_aabb()]);
 
-            BvhTree::Node(aabb, Box::new(left), Box::new(right), size)
+            BvhTree::Node(aabb, Box::new(left), Box::new(right), size, axis)
         }
     }
 
-    fn get_closest_intersection(&self, ray: &Ray) -> Option<(&Object, f32)> {
+    fn get_aabb(&self) -> AABB {
         match self {
-            BvhTree::Node(aabb, left, right, _size) => {
-                if let Some(_) = aabb.intersect(ray) {
-                    [left, right]
-                        .iter()
-                        .filter_map(|bvh| bvh.get_closest_intersection(ray))
-                        // Just a hacky way to find the smallest t value.
-                        .min_by(|(_, t_left), (_, t_right)| {
-                            t_left
-                                .partial_cmp(t_right)
-                                .unwrap_or(std::cmp::Ordering::Equal)
-                        })
-                } else {
-                    None
-                }
-            }
-            BvhTree::Leaf(aabb, objects, _size) => {
-                if let Some(_) = aabb.intersect(ray) {
-                    objects
-                        .iter()
-                        .filter_map(|object| match object.get_intersection(ray) {
-                            Some(t) => Some((object, t)),
-                            None => None,
-                        })
-                        // Just a hacky way to find the smallest t value.
-                        .min_by(|(_, t_left), (_, t_right)| {
-                            t_left
-                                .partial_cmp(t_right)
-                                .unwrap_or(std::cmp::Ordering::Equal)
-                        })
-                } else {
-                    None
-                }
-            }
+            BvhTree::Node(aabb, _, _, _, _) => *aabb,
+            BvhTree::Leaf(aabb, _, _) => *aabb,
         }
     }
 
-    fn get_aabb(&self) -> AABB {
+    fn get_num_objects(&self) -> usize {
         match self {
-            BvhTree::Node(aabb, _, _, _) => *aabb,
-            BvhTree::Leaf(aabb, _, _) => *aabb,
+            BvhTree::Node(_, _, _, size, _) => *size,
+            BvhTree::Leaf(_, _, size) => *size,
         }
     }
+}
 
-    fn get_depth(&self) -> usize {
-        match self {
-            BvhTree::Node(_, left, right, _) => 1 + left.get_depth().max(right.get_depth()),
-            BvhTree::Leaf(_, _, _) => 0,
+/// Spatial splits are only tried where the children of the object split overlap by
+/// more than this fraction of the surface area of the root, as in the SBVH paper.
+const SPATIAL_SPLIT_ALPHA: f32 = 1e-5;
+
+/// Spatial splits may add at most this many references per primitive in total.
+const SPATIAL_SPLIT_BUDGET: f32 = 1.0;
+
+/// The number of candidate planes along each axis is one less than this.
+const N_SPATIAL_BINS: usize = 16;
+
+/// Nodes this deep are always leaves, since spatial splits can keep splitting
+/// primitives that overlap everywhere.
+const MAX_SPATIAL_DEPTH: usize = 64;
+
+/// Part of a node between two candidate planes of a spatial split.
+#[derive(Default, Clone)]
+struct SpatialBin {
+    /// The bounds of the parts of the references that are in the bin.
+    aabb: Option<AABB>,
+    /// The number of references that start in this bin.
+    entries: usize,
+    /// The number of references that end in this bin.
+    exits: usize,
+}
+
+/// Returns the SAH cost of splitting a node into `left` and `right`, leaving out the
+/// traversal cost and the division by the surface area of the node, which are the
+/// same for every split of that node.
+fn get_split_cost(num_left: usize, left: &AABB, num_right: usize, right: &AABB) -> f32 {
+    num_left as f32 * left.surface_area() + num_right as f32 * right.surface_area()
+}
+
+/// Returns the bounding box of the bounding boxes of `references`.
+fn get_references_aabb<T: Bounded>(references: &[Reference<T>]) -> AABB {
+    AABB::union(references.iter().map(|reference| reference.aabb).collect())
+}
+
+/// Finds the spatial split of a node with bounding box `aabb` with the lowest cost
+/// according to `get_split_cost`, trying `N_SPATIAL_BINS - 1` evenly spaced planes
+/// along each axis. Returns the cost, the axis, and the position of the plane.
+///
+/// Each reference is clipped to every bin it overlaps, so that the bins only grow by
+/// the parts of primitives that are actually in them.
+fn find_spatial_split<T: Bounded>(
+    references: &[&Reference<T>],
+    aabb: &AABB,
+) -> Option<(f32, usize, f32)> {
+    let mut best: Option<(f32, usize, f32)> = None;
+    for axis in 0..3 {
+        let min = aabb.min[axis];
+        let extent = aabb.max[axis] - min;
+        if extent <= 0.0 {
+            continue;
+        }
+        let bin_size = extent / N_SPATIAL_BINS as f32;
+        let get_bin = |x: f32| (((x - min) / bin_size) as usize).min(N_SPATIAL_BINS - 1);
+        let get_plane = |i: usize| {
+            if i == N_SPATIAL_BINS {
+                aabb.max[axis]
+            } else {
+                min + i as f32 * bin_size
+            }
+        };
+
+        let mut bins = vec![SpatialBin::default(); N_SPATIAL_BINS];
+        for reference in references {
+            let first = get_bin(reference.aabb.min[axis]);
+            let last = get_bin(reference.aabb.max[axis]);
+            for (i, bin) in bins.iter_mut().enumerate().take(last + 1).skip(first) {
+                let clipped =
+                    reference.get_clipped_bounding_box(axis, get_plane(i), get_plane(i + 1));
+                if let Some((clipped_min, clipped_max)) = clipped {
+                    let clipped = AABB::new(clipped_min, clipped_max);
+                    bin.aabb = Some(bin.aabb.map_or(clipped, |aabb| aabb.merge(&clipped)));
+                }
+            }
+            bins[first].entries += 1;
+            bins[last].exits += 1;
+        }
+
+        // The bounds and reference counts to the right of each plane, from the right.
+        let mut right = vec![(None, 0); N_SPATIAL_BINS];
+        let mut right_aabb: Option<AABB> = None;
+        let mut num_right = 0;
+        for i in (1..N_SPATIAL_BINS).rev() {
+            right_aabb = match (right_aabb, bins[i].aabb) {
+                (Some(a), Some(b)) => Some(a.merge(&b)),
+                (a, b) => a.or(b),
+            };
+            num_right += bins[i].exits;
+            right[i] = (right_aabb, num_right);
+        }
+        let mut left_aabb: Option<AABB> = None;
+        let mut num_left = 0;
+        for i in 0..N_SPATIAL_BINS - 1 {
+            left_aabb = match (left_aabb, bins[i].aabb) {
+                (Some(a), Some(b)) => Some(a.merge(&b)),
+                (a, b) => a.or(b),
+            };
+            num_left += bins[i].entries;
+            if let (Some(left_aabb), (Some(right_aabb), num_right)) = (left_aabb, right[i + 1]) {
+                let cost = get_split_cost(num_left, &left_aabb, num_right, &right_aabb);
+                if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, axis, get_plane(i + 1)));
+                }
+            }
         }
     }
+    best
+}
 
-    fn get_num_objects(&self) -> usize {
-        match self {
-            BvhTree::Node(_, _, _, size) => *size,
-            BvhTree::Leaf(_, _, size) => *size,
+/// Divides `references` between the two sides of the plane at `position` along
+/// `axis`. References that cross the plane are clipped to each side.
+fn split_references<T: Bounded + Clone>(
+    references: Vec<Reference<T>>,
+    axis: usize,
+    position: f32,
+) -> (Vec<Reference<T>>, Vec<Reference<T>>) {
+    let mut left = vec![];
+    let mut right = vec![];
+    for reference in references {
+        if reference.aabb.max[axis] <= position {
+            left.push(reference);
+        } else if reference.aabb.min[axis] >= position {
+            right.push(reference);
+        } else {
+            let clip = |min, max| {
+                reference
+                    .get_clipped_bounding_box(axis, min, max)
+                    .map(|(min, max)| AABB::new(min, max))
+            };
+            match (
+                clip(f32::NEG_INFINITY, position),
+                clip(position, f32::INFINITY),
+            ) {
+                (Some(left_aabb), Some(right_aabb)) => {
+                    left.push(Reference {
+                        primitive: reference.primitive.clone(),
+                        aabb: left_aabb,
+                    });
+                    right.push(Reference {
+                        primitive: reference.primitive,
+                        aabb: right_aabb,
+                    });
+                }
+                (Some(aabb), None) => left.push(Reference { aabb, ..reference }),
+                (None, Some(aabb)) => right.push(Reference { aabb, ..reference }),
+                // Only rounding can lose the primitive, so keep all of it.
+                (None, None) => left.push(reference),
+            }
         }
     }
+    (left, right)
+}
 
-    /// Total surface area of this bvh (recursively computed)
-    fn total_sa(&self) -> f32 {
-        match self {
-            BvhTree::Leaf(aabb, _objs, _size) => aabb.surface_area(),
-            BvhTree::Node(aabb, left, right, _size) => {
-                aabb.surface_area() + left.total_sa() + right.total_sa()
+impl<T: Bounded + Clone> BvhTree<Reference<T>> {
+    /// Builds the tree of an SBVH using the current rayon thread pool, as in "Spatial
+    /// Splits in Bounding Volume Hierarchies" by Stich et al.
+    ///
+    /// Each node is split with whichever of the SAH object split and the best spatial
+    /// split is cheaper. Spatial splits are only tried where the children of the
+    /// object split overlap, and may add at most `budget` references to this subtree.
+    /// `root_area` is the surface area of the root of the tree.
+    fn new_spatial(
+        references: Vec<Reference<T>>,
+        options: &BvhBuildOptions,
+        root_area: f32,
+        budget: usize,
+        depth: usize,
+    ) -> Self {
+        let size = references.len();
+        let aabb = get_references_aabb(&references);
+        if size <= options.max_leaf_size || depth >= MAX_SPATIAL_DEPTH {
+            return BvhTree::Leaf(aabb, references, size);
+        }
+
+        let object_split_options = BvhBuildOptions {
+            split_type: SplitType::SAH,
+            ..*options
+        };
+        let (left, right, axis) = bvh_split(references, &object_split_options);
+        let (left_aabb, right_aabb) = (get_references_aabb(&left), get_references_aabb(&right));
+        let object_cost = if left.is_empty() || right.is_empty() {
+            f32::INFINITY
+        } else {
+            get_split_cost(left.len(), &left_aabb, right.len(), &right_aabb)
+        };
+        let overlap = left_aabb
+            .intersection(&right_aabb)
+            .map_or(0.0, |overlap| overlap.surface_area());
+        let spatial_split = if budget > 0
+            && (object_cost.is_infinite() || overlap > SPATIAL_SPLIT_ALPHA * root_area)
+        {
+            let all: Vec<&Reference<T>> = left.iter().chain(&right).collect();
+            find_spatial_split(&all, &aabb).filter(|&(cost, spatial_axis, position)| {
+                // Only references that cross the plane are divided.
+                let num_crossing = all
+                    .iter()
+                    .filter(|reference| {
+                        reference.aabb.min[spatial_axis] < position
+                            && position < reference.aabb.max[spatial_axis]
+                    })
+                    .count();
+                cost < object_cost && num_crossing <= budget
+            })
+        } else {
+            None
+        };
+
+        let (left, right, axis, budget) = match spatial_split {
+            Some((_, spatial_axis, position)) => {
+                let references = left.into_iter().chain(right).collect();
+                let (left, right) = split_references(references, spatial_axis, position);
+                let added = left.len() + right.len() - size;
+                (left, right, spatial_axis, budget - added)
             }
+            None => (left, right, axis, budget),
+        };
+        if left.is_empty() || right.is_empty() {
+            let references: Vec<Reference<T>> = left.into_iter().chain(right).collect();
+            let size = references.len();
+            return BvhTree::Leaf(aabb, references, size);
         }
+
+        let size = left.len() + right.len();
+        let left_budget = budget * left.len() / size;
+        let right_budget = budget - left_budget;
+        let (left, right) = if size >= PARALLEL_BUILD_THRESHOLD {
+            rayon::join(
+                || BvhTree::new_spatial(left, options, root_area, left_budget, depth + 1),
+                || BvhTree::new_spatial(right, options, root_area, right_budget, depth + 1),
+            )
+        } else {
+            (
+                BvhTree::new_spatial(left, options, root_area, left_budget, depth + 1),
+                BvhTree::new_spatial(right, options, root_area, right_budget, depth + 1),
+            )
+        };
+        let aabb = left.get_aabb().merge(&right.get_aabb());
+        BvhTree::Node(aabb, Box::new(left), Box::new(right), size, axis)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{bvh_split, bvh_split_by_x_axis, bvh_split_naive, Bvh, SplitType, AABB};
+    use super::{
+        bvh_split, bvh_split_by_x_axis, bvh_split_naive, clip_polygon, get_cache_key,
+        get_geometry_hash, Bvh, BvhBuildOptions, LinearBvh, SplitType, AABB, REBUILD_THRESHOLD,
+    };
     use crate::material::{Material, MaterialType, TextureType};
-    use crate::object::Object;
+    use crate::object::{Object, ObjectType};
+    use crate::packet::RayPacket;
     use crate::ray::Ray;
-    use cgmath::Point3;
+    use crate::scene::load_random_spheres;
+    use cgmath::{Matrix4, Point3, Vector3};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     #[test]
     fn test_aabb_surface_area() {
@@ -480,7 +2070,7 @@ mod tests {
         assert_eq!(aabb.intersect(&ray), Some(1.));
 
         let ray = Ray::new((-0.5, -0.5, 0.5).into(), (0.5, 0.5, 0.).into());
-        assert_eq!(aabb.intersect(&ray), Some(1. / (2. as f32).sqrt()));
+        assert_eq!(aabb.intersect(&ray), Some(1. / 2_f32.sqrt()));
 
         // ray grazes a corner
         let ray = Ray::new((-1.0, -1.0, 0.).into(), (1., 0.5, 0.).into());
@@ -488,7 +2078,40 @@ mod tests {
 
         // ray starts in the middle and shoots out
         let ray = Ray::new((0.5, 0.5, 0.5).into(), (1., 0., 0.).into());
-        assert_eq!(aabb.intersect(&ray), Some(0.5));
+        assert_eq!(aabb.intersect(&ray), Some(0.));
+
+        // box is behind the ray
+        let ray = Ray::new((2.0, 0.5, 0.5).into(), (1., 0., 0.).into());
+        assert!(aabb.intersect(&ray).is_none());
+
+        // box is beyond the range of the ray
+        let ray = Ray::new((-1.0, 0.5, 0.5).into(), (1., 0., 0.).into());
+        assert!(aabb.intersect(&ray.clone().with_range(0., 0.5)).is_none());
+        assert_eq!(aabb.intersect(&ray.with_range(1.5, 5.)), Some(1.5));
+    }
+
+    #[test]
+    fn test_aabb_intersect_packet() {
+        let aabb = AABB::new((0., 0., 0.).into(), (1.0, 1.0, 1.0).into());
+        let rays = vec![
+            Ray::new((-0.5, -0.5, -0.5).into(), (0.5, 0.5, 0.5).into()),
+            Ray::new((-0.5, -0.5, -0.5).into(), (-0.5, 0.5, 0.5).into()),
+            Ray::new((-1.0, 0.5, 0.5).into(), (1.0, 0., 0.).into()),
+            Ray::new((0.5, 0.5, 0.5).into(), (1., 0., 0.).into()),
+            Ray::new((2.0, 0.5, 0.5).into(), (1., 0., 0.).into()),
+            Ray::new((-1.0, 0.5, 0.5).into(), (1., 0., 0.).into()).with_range(0., 0.5),
+            // Parallel to the box, along one face and just outside another.
+            Ray::new((0.0, 0.5, -1.0).into(), (0., 0., 1.).into()),
+            Ray::new((1.01, 0.5, -1.0).into(), (0., 0., 1.).into()),
+        ];
+        let hits = aabb.intersect_packet(&RayPacket::new(&rays));
+        for (lane, ray) in rays.iter().enumerate() {
+            assert_eq!(hits[lane], aabb.intersect(ray).is_some());
+        }
+        assert_eq!(
+            &hits[..rays.len()],
+            &[true, false, true, true, false, false, true, false]
+        );
     }
 
     #[test]
@@ -567,12 +2190,310 @@ mod tests {
         assert_eq!(left.len(), 2);
         assert_eq!(right.len(), 3);
 
-        let (left, right) = bvh_split(objects(), SplitType::Basic);
+        let options = |split_type| BvhBuildOptions {
+            split_type,
+            ..Default::default()
+        };
+        // The naive halves are ordered along the axis they are furthest apart on.
+        let mut reversed = objects();
+        reversed.reverse();
+        let (left, right, axis) = bvh_split(reversed, &options(SplitType::Naive));
+        assert_eq!(axis, 1);
+        assert_eq!(left.len(), 3);
+        assert_eq!(right.len(), 2);
+        assert!(left
+            .iter()
+            .all(|object| object.get_bounding_box().1.y <= 8.0));
+
+        let (left, right, axis) = bvh_split(objects(), &options(SplitType::Basic));
         assert_eq!(left.len(), 1);
         assert_eq!(right.len(), 4);
+        assert_eq!(axis, 1);
 
-        let (left, right) = bvh_split(objects(), SplitType::SAH);
+        let (left, right, axis) = bvh_split(objects(), &options(SplitType::SAH));
         assert_eq!(left.len(), 1);
         assert_eq!(right.len(), 4);
+        assert_eq!(axis, 1);
+    }
+
+    #[test]
+    fn test_parallel_bvh() {
+        // The random spheres are generated with a fixed seed.
+        let serial = Bvh::new(load_random_spheres(5000).0);
+        let parallel = Bvh::with_num_threads(load_random_spheres(5000).0, 4);
+        assert_eq!(serial.tree.total_sa(), parallel.tree.total_sa());
+        assert_eq!(serial.tree.get_depth(), parallel.tree.get_depth());
+
+        let mut rng = StdRng::seed_from_u64(248);
+        for _ in 0..100 {
+            let direction = (rng.gen_range(-1.0, 1.0), -1.0, rng.gen_range(-1.0, 1.0));
+            let ray = Ray::new((0.0, 10.0, 0.0).into(), direction.into());
+            let t = |bvh: &Bvh| {
+                bvh.get_closest_intersection(&ray)
+                    .map(|(_, interaction)| interaction.t)
+            };
+            assert_eq!(t(&serial), t(&parallel));
+        }
+    }
+
+    #[test]
+    fn test_bvh_matches_brute_force() {
+        let bvh = Bvh::new(load_random_spheres(500).0);
+        assert_matches_brute_force(&bvh);
+    }
+
+    fn assert_matches_brute_force(bvh: &Bvh) {
+        let mut rng = StdRng::seed_from_u64(248);
+        for _ in 0..100 {
+            let position = (rng.gen_range(-5.0, 5.0), 3.0, rng.gen_range(-5.0, 5.0));
+            let direction = (rng.gen_range(-1.0, 1.0), -1.0, rng.gen_range(-1.0, 1.0));
+            let ray = Ray::new(position.into(), direction.into());
+            let expected = bvh
+                .objects
+                .iter()
+                .filter_map(|object| object.get_intersection(&ray))
+                .fold(None, |closest: Option<f32>, t| {
+                    Some(closest.map_or(t, |closest| closest.min(t)))
+                });
+            let actual = bvh
+                .get_closest_intersection(&ray)
+                .map(|(_, interaction)| interaction.t);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_clip_polygon() {
+        let triangle = [
+            (0.0, 0.0, 0.0).into(),
+            (4.0, 0.0, 0.0).into(),
+            (0.0, 4.0, 1.0).into(),
+        ];
+        assert_eq!(
+            clip_polygon(&triangle, 0, 1.0, 2.0),
+            Some(((1.0, 0.0, 0.0).into(), (2.0, 3.0, 0.75).into()))
+        );
+        assert_eq!(
+            clip_polygon(&triangle, 1, 3.0, f32::INFINITY),
+            Some(((0.0, 3.0, 0.75).into(), (1.0, 4.0, 1.0).into()))
+        );
+        assert_eq!(clip_polygon(&triangle, 2, 2.0, 3.0), None);
+    }
+
+    #[test]
+    fn test_spatial_splits() {
+        // Long thin diagonal triangles side by side, whose bounding boxes overlap a lot
+        // even though the triangles do not.
+        let mut rng = StdRng::seed_from_u64(248);
+        let triangles: Vec<ObjectType> = (0..1000)
+            .map(|_| {
+                let offset = rng.gen_range(-5.0, 5.0);
+                let a = Point3::new(offset - 5.0, -offset - 5.0, rng.gen_range(-1.0, 1.0));
+                let b = a + Vector3::new(10.0, 10.0, 0.0);
+                let c = a + Vector3::new(-0.05, 0.05, 0.0);
+                ObjectType::Triangle(a, b, c)
+            })
+            .collect();
+        let sah = LinearBvh::new(triangles.clone(), &BvhBuildOptions::default());
+        let spatial_options = BvhBuildOptions {
+            split_type: SplitType::Spatial,
+            ..Default::default()
+        };
+        let spatial = LinearBvh::new(triangles.clone(), &spatial_options);
+        assert!(spatial.total_sa() < sah.total_sa());
+        // Spatial splits divide triangles between leaves, within the budget.
+        assert!(spatial.primitive_indices.len() > triangles.len());
+        assert!(spatial.primitive_indices.len() <= 2 * triangles.len());
+
+        // Both trees find the same hits, but the spatial splits need fewer tests.
+        let mut num_tests = [0, 0];
+        let mut num_hits = 0;
+        for _ in 0..500 {
+            let position = (rng.gen_range(-8.0, 8.0), rng.gen_range(-8.0, 8.0), 5.0);
+            let direction = (rng.gen_range(-0.2, 0.2), rng.gen_range(-0.2, 0.2), -1.0);
+            let ray = Ray::new(position.into(), direction.into());
+            let t = |bvh: &LinearBvh, num_tests: &mut usize| {
+                bvh.get_closest_intersection(&ray, |index, ray| {
+                    *num_tests += 1;
+                    triangles[index]
+                        .get_intersection(ray, true)
+                        .map(|hit| (hit.t, ()))
+                })
+                .map(|(index, t, _)| (index, t))
+            };
+            let expected = t(&sah, &mut num_tests[0]);
+            assert_eq!(t(&spatial, &mut num_tests[1]), expected);
+            num_hits += expected.is_some() as usize;
+        }
+        assert!(num_hits > 100);
+        assert!(num_tests[1] < num_tests[0]);
+    }
+
+    #[test]
+    fn test_build_options() {
+        for split_type in ["naive", "x_axis", "basic", "sah", "spatial"] {
+            for (&max_leaf_size, &width) in [1, 8].iter().zip(&[8, 2]) {
+                let options = BvhBuildOptions {
+                    split_type: split_type.parse().unwrap(),
+                    max_leaf_size,
+                    num_buckets: 4,
+                    traversal_cost: 0.5,
+                    width,
+                };
+                let objects = load_random_spheres(500).0;
+                let num_objects = objects.len();
+                let bvh = Bvh::with_options(objects, 1, options, None);
+                assert_matches_brute_force(&bvh);
+
+                let stats = bvh.get_report().objects.unwrap();
+                assert_eq!(stats.num_primitives, num_objects);
+                assert_eq!(stats.num_nodes, bvh.tree.nodes.len());
+                assert_eq!(stats.num_nodes, 2 * stats.num_leaves - 1);
+                assert_eq!(stats.depth, bvh.tree.get_depth());
+                assert_eq!(stats.num_wide_nodes.is_some(), width > 2);
+                assert!(stats.leaf_size_histogram.len() <= max_leaf_size + 1);
+                let num_references: usize = stats
+                    .leaf_size_histogram
+                    .iter()
+                    .enumerate()
+                    .map(|(size, count)| size * count)
+                    .sum();
+                assert_eq!(num_references, stats.num_references);
+                assert!(stats.sah_cost >= 1.0);
+                assert!(!stats.loaded_from_cache);
+            }
+        }
+        assert!("octree".parse::<SplitType>().is_err());
+    }
+
+    #[test]
+    fn test_coincident_objects() {
+        // Duplicate objects have the same centroid, so no plane separates them.
+        let m = Material::new(MaterialType::None, TextureType::None);
+        for split_type in ["naive", "x_axis", "basic", "sah", "spatial"] {
+            for &num_objects in &[2, 10] {
+                let objects = (0..num_objects)
+                    .map(|_| Object::new_sphere((0.0, 0.0, 0.0).into(), 1.0, m.clone()))
+                    .collect();
+                let options = BvhBuildOptions {
+                    split_type: split_type.parse().unwrap(),
+                    max_leaf_size: 1,
+                    ..Default::default()
+                };
+                let (left, right, _) = bvh_split(objects, &options);
+                assert_eq!(left.len(), num_objects / 2);
+                assert_eq!(right.len(), num_objects / 2);
+
+                let objects = left.into_iter().chain(right).collect();
+                let bvh = Bvh::with_options(objects, 1, options, None);
+                assert_matches_brute_force(&bvh);
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_occluded() {
+        let bvh = Bvh::new(load_random_spheres(500).0);
+        let mut rng = StdRng::seed_from_u64(248);
+        for _ in 0..100 {
+            let position = (rng.gen_range(-5.0, 5.0), 3.0, rng.gen_range(-5.0, 5.0));
+            let direction = (rng.gen_range(-1.0, 1.0), -1.0, rng.gen_range(-1.0, 1.0));
+            let ray = Ray::new(position.into(), direction.into());
+            let max_t = rng.gen_range(0.0, 5.0);
+            let expected = bvh
+                .get_closest_intersection(&ray)
+                .is_some_and(|(_, interaction)| interaction.t <= max_t);
+            assert_eq!(bvh.is_occluded(&ray, max_t), expected);
+        }
+    }
+
+    #[test]
+    fn test_bvh_cache() {
+        let cache_dir = std::env::temp_dir().join("rust_raytracer_test_bvh_cache");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let objects = || load_random_spheres(500).0;
+        let options = BvhBuildOptions::default();
+        let key = get_cache_key(get_geometry_hash(&objects()), &options);
+        let path = cache_dir.join(format!("{:016x}.bvh", key));
+
+        let built = Bvh::with_cache(objects(), 1, &cache_dir);
+        assert!(path.exists());
+        let loaded = Bvh::with_cache(objects(), 1, &cache_dir);
+        assert!(!built.get_report().objects.unwrap().loaded_from_cache);
+        assert!(loaded.get_report().objects.unwrap().loaded_from_cache);
+        assert_eq!(built.tree.nodes.len(), loaded.tree.nodes.len());
+        assert_eq!(built.tree.primitive_indices, loaded.tree.primitive_indices);
+        assert_eq!(built.tree.total_sa(), loaded.tree.total_sa());
+        let mut rng = StdRng::seed_from_u64(248);
+        for _ in 0..100 {
+            let direction = (rng.gen_range(-1.0, 1.0), -1.0, rng.gen_range(-1.0, 1.0));
+            let ray = Ray::new((0.0, 10.0, 0.0).into(), direction.into());
+            let t = |bvh: &Bvh| {
+                bvh.get_closest_intersection(&ray)
+                    .map(|(_, interaction)| interaction.t)
+            };
+            assert_eq!(t(&built), t(&loaded));
+        }
+
+        // Moving an object changes the key.
+        let mut moved = objects();
+        let last = moved.pop().unwrap();
+        moved.push(last.transform(cgmath::Matrix4::from_scale(2.0)));
+        assert_ne!(get_cache_key(get_geometry_hash(&moved), &options), key);
+        // So does changing the split type.
+        let spatial = BvhBuildOptions {
+            split_type: SplitType::Spatial,
+            ..Default::default()
+        };
+        assert_ne!(get_cache_key(get_geometry_hash(&objects()), &spatial), key);
+
+        // A corrupt cache file is rebuilt instead of being loaded.
+        std::fs::write(&path, b"RTBVH").unwrap();
+        let rebuilt = Bvh::with_cache(objects(), 1, &cache_dir);
+        assert_eq!(built.tree.primitive_indices, rebuilt.tree.primitive_indices);
+        assert!(std::fs::metadata(&path).unwrap().len() > 5);
+
+        // Bvhs rebuilt after objects move are cached in the same directory.
+        let num_files = || std::fs::read_dir(&cache_dir).unwrap().count();
+        let num_cached = num_files();
+        let mut bvh = Bvh::with_cache(objects(), 1, &cache_dir);
+        let rebuilt = bvh.update_objects(|index, object| {
+            let offset = if index % 2 == 0 { -4.0 } else { 4.0 };
+            object.set_object_to_world(Matrix4::from_translation((offset, 0.0, 0.0).into()));
+        });
+        assert!(rebuilt);
+        assert_eq!(num_files(), num_cached + 1);
+    }
+
+    #[test]
+    fn test_refit() {
+        let mut bvh = Bvh::new(load_random_spheres(500).0);
+        let built_cost = bvh.tree.get_cost();
+        let mut rng = StdRng::seed_from_u64(248);
+
+        // Small movements only need the bounding boxes to be refit.
+        let rebuilt = bvh.update_objects(|_, object| {
+            let offset = (rng.gen_range(-0.1, 0.1), 0.0, rng.gen_range(-0.1, 0.1));
+            object.set_object_to_world(Matrix4::from_translation(offset.into()));
+        });
+        assert!(!rebuilt);
+        assert!(bvh.tree.get_cost() > built_cost);
+        assert_matches_brute_force(&bvh);
+        // The statistics describe the refit tree.
+        let stats = bvh.get_report().objects.unwrap();
+        let num_objects = bvh.objects.len();
+        let expected = bvh
+            .tree
+            .get_stats(num_objects, &bvh.options, stats.build_seconds, false);
+        assert_eq!(stats, expected);
+
+        // Scattering the objects makes the old tree too slow, so it is rebuilt.
+        let rebuilt = bvh.update_objects(|index, object| {
+            let offset = if index % 2 == 0 { -4.0 } else { 4.0 };
+            object.set_object_to_world(Matrix4::from_translation((offset, 0.0, 0.0).into()));
+        });
+        assert!(rebuilt);
+        assert!(bvh.tree.get_cost() <= REBUILD_THRESHOLD * bvh.built_cost);
+        assert_matches_brute_force(&bvh);
     }
 }
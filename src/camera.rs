@@ -1,48 +1,278 @@
 use super::ray::Ray;
-use cgmath::{Matrix4, Point3, SquareMatrix, Vector3};
+use super::utils::sample_disk;
+use cgmath::{Angle, Deg, Matrix4, Point3, Rad, SquareMatrix, Vector3};
 use rand::Rng;
+use std::f32::consts::PI;
+
+/// The vertical field of view used by the built-in scenes.
+///
+/// This is the field of view of an image plane of height 1 at distance 1 from the eye.
+pub const DEFAULT_VERTICAL_FOV: Deg<f32> = Deg(53.130_1);
+
+/// How directions from the camera are mapped onto the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// A standard pinhole (or thin lens) camera covering `Camera::vertical_fov`.
+    Perspective,
+    /// Parallel rays covering a view of the given height in world units.
+    Orthographic(f32),
+    /// A full 360 by 180 degree panorama, best rendered at a 2:1 aspect ratio.
+    Equirectangular,
+    /// An equidistant fisheye lens where the angle from the view direction grows
+    /// linearly with the distance from the image center, covering
+    /// `Camera::vertical_fov` from the top to the bottom edge.
+    Fisheye,
+}
 
 pub struct Camera {
     camera_to_world: Matrix4<f32>,
     pub width: u32,
     pub height: u32,
+    /// The angle between the top and bottom edges of the image. Must be less than
+    /// 180 degrees unless the projection is `Projection::Fisheye`.
+    pub vertical_fov: Deg<f32>,
+    pub projection: Projection,
+    /// The radius of the lens. Zero gives a pinhole camera where everything is in focus.
+    pub aperture: f32,
+    /// The distance from the camera to the plane that is in perfect focus.
+    pub focus_distance: f32,
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         width: u32,
         height: u32,
         eye: Point3<f32>,
         at: Point3<f32>,
         up: Vector3<f32>,
+        vertical_fov: Deg<f32>,
+        aperture: f32,
+        focus_distance: f32,
     ) -> Camera {
+        debug_assert!(Deg(0.0) < vertical_fov && vertical_fov < Deg(360.0));
+        debug_assert!(aperture >= 0.0);
+        debug_assert!(focus_distance > 0.0);
         let world_to_camera = Matrix4::look_at(eye, at, up);
         let camera_to_world = world_to_camera.invert().unwrap();
         Camera {
             width,
             height,
             camera_to_world,
+            vertical_fov,
+            projection: Projection::Perspective,
+            aperture,
+            focus_distance,
         }
     }
 
+    /// Returns the height of the area that a perspective view covers at `distance`
+    /// from the camera.
+    pub fn get_view_height_at(&self, distance: f32) -> f32 {
+        2.0 * distance * (self.vertical_fov / 2.0).tan()
+    }
+
     /// Generates a ray for the given pixel location.
     /// pixel_x should be in (0, width)
     /// pixel_y should be in (0, height)
     /// rng can be None if no randomness should be added, else a rng
+    ///
+    /// When `rng` is given and the aperture is not zero, perspective rays start from a
+    /// random point on the lens and pass through the focal plane where the pinhole ray
+    /// would, so objects away from the focal plane are blurred. Other projections
+    /// ignore the aperture.
     pub fn generate_ray<R: Rng>(&self, pixel_x: u32, pixel_y: u32, rng: Option<&mut R>) -> Ray {
-        // TODO: This only works for a square screen
-        let (dx, dy) = match rng {
-            None => (0., 0.),
-            Some(rng) => (rng.gen::<f32>() / 2., rng.gen::<f32>() / 2.),
+        let (dx, dy, lens) = match rng {
+            None => (0., 0., None),
+            Some(rng) => {
+                let (dx, dy) = (rng.gen::<f32>() / 2., rng.gen::<f32>() / 2.);
+                let lens = if self.aperture > 0.0 && self.projection == Projection::Perspective {
+                    Some(sample_disk(self.aperture, rng))
+                } else {
+                    None
+                };
+                (dx, dy, lens)
+            }
+        };
+        // Pixel (0, 0) is in the top left corner. Map pixels to [-1, 1] with +y up.
+        let ndc_x = 2.0 * (pixel_x as f32 + dx) / (self.width as f32) - 1.0;
+        let ndc_y = 1.0 - 2.0 * (pixel_y as f32 + dy) / (self.height as f32);
+        let aspect_ratio = self.width as f32 / self.height as f32;
+        let ray = match self.projection {
+            Projection::Perspective => {
+                // The image plane is at distance 1 in front of the eye, so its size is
+                // given by the field of view and the aspect ratio.
+                let half_height = self.get_view_height_at(1.0) / 2.0;
+                let x = ndc_x * half_height * aspect_ratio;
+                let y = ndc_y * half_height;
+                let dist = -1.0;
+                match lens {
+                    None => Ray::new((x, y, dist).into(), (x, y, dist).into()),
+                    Some((lens_x, lens_y)) => {
+                        let lens_position = Point3::new(lens_x, lens_y, 0.0);
+                        let focus_position = Point3::new(x, y, dist) * self.focus_distance;
+                        let direction = focus_position - lens_position;
+                        // Start the ray on the image plane like the pinhole rays do.
+                        let position = lens_position + direction / self.focus_distance;
+                        Ray::new(position, direction)
+                    }
+                }
+            }
+            Projection::Orthographic(view_height) => {
+                let x = ndc_x * view_height / 2.0 * aspect_ratio;
+                let y = ndc_y * view_height / 2.0;
+                Ray::new((x, y, 0.0).into(), (0.0, 0.0, -1.0).into())
+            }
+            Projection::Equirectangular => {
+                let longitude = Rad(ndc_x * PI);
+                let latitude = Rad(ndc_y * PI / 2.0);
+                let direction = Vector3::new(
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                    -latitude.cos() * longitude.cos(),
+                );
+                Ray::new((0.0, 0.0, 0.0).into(), direction)
+            }
+            Projection::Fisheye => {
+                let (x, y) = (ndc_x * aspect_ratio, ndc_y);
+                let r = (x * x + y * y).sqrt();
+                let direction = if r == 0.0 {
+                    Vector3::new(0.0, 0.0, -1.0)
+                } else {
+                    // Points past the image circle of a full fisheye look behind the camera.
+                    let angle = Rad::from(self.vertical_fov / 2.0) * r;
+                    Vector3::new(angle.sin() * x / r, angle.sin() * y / r, -angle.cos())
+                };
+                Ray::new((0.0, 0.0, 0.0).into(), direction)
+            }
         };
-        // Pixel (0, 0) is in the top left corner.
-        let x = (pixel_x as f32 + dx) / (self.width as f32) - 0.5;
-        let y = 0.5 - (pixel_y as f32 + dy) / (self.height as f32);
-        let dist = -1.0; // TODO: Something something focal length?
-        let position = (x, y, dist).into();
-        let direction = (x, y, dist).into();
-        let ray = Ray::new(position, direction);
         // Transform ray into world space.
         ray.transform_using(&self.camera_to_world)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Camera, Projection, DEFAULT_VERTICAL_FOV};
+    use cgmath::{Deg, InnerSpace, MetricSpace, Point3, Vector3};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn new_camera(aperture: f32) -> Camera {
+        Camera::new(
+            1000,
+            1000,
+            (0.0, 0.0, 5.0).into(),
+            (0.0, 0.0, 0.0).into(),
+            (0.0, 1.0, 0.0).into(),
+            DEFAULT_VERTICAL_FOV,
+            aperture,
+            5.0,
+        )
+    }
+
+    /// Returns the point where `ray` crosses the plane `z = z`.
+    fn point_at_z(ray: &crate::ray::Ray, z: f32) -> Point3<f32> {
+        let origin: Point3<f32> = ray.get_point_on_ray(0.0).into();
+        let t = (z - origin.z) / ray.get_direction().z;
+        ray.get_point_on_ray(t).into()
+    }
+
+    #[test]
+    fn test_pinhole_camera() {
+        let camera = new_camera(0.0);
+        let mut rng = StdRng::seed_from_u64(248);
+        let pinhole = camera.generate_ray::<StdRng>(500, 500, None);
+        for _ in 0..10 {
+            let ray = camera.generate_ray(500, 500, Some(&mut rng));
+            assert!((ray.get_direction() - pinhole.get_direction()).magnitude() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_depth_of_field() {
+        let camera = new_camera(0.5);
+        let mut rng = StdRng::seed_from_u64(248);
+        let pinhole = camera.generate_ray::<StdRng>(500, 500, None);
+        let rays: Vec<_> = (0..10)
+            .map(|_| camera.generate_ray(500, 500, Some(&mut rng)))
+            .collect();
+        // Rays meet at the focal plane and spread apart away from it.
+        let spread_at = |z: f32| {
+            let expected = point_at_z(&pinhole, z);
+            rays.iter()
+                .map(|ray| point_at_z(ray, z).distance(expected))
+                .fold(0.0, f32::max)
+        };
+        assert!(spread_at(0.0) < 1e-2);
+        assert!(spread_at(-5.0) > 0.1);
+        assert!(spread_at(2.5) > 0.1);
+    }
+
+    #[test]
+    fn test_field_of_view() {
+        let camera = Camera::new(
+            400,
+            200,
+            (0.0, 0.0, 0.0).into(),
+            (0.0, 0.0, -1.0).into(),
+            (0.0, 1.0, 0.0).into(),
+            Deg(90.0),
+            0.0,
+            1.0,
+        );
+        let angle_to_view = |x: u32, y: u32| {
+            let ray = camera.generate_ray::<StdRng>(x, y, None);
+            Deg::from(ray.get_direction().angle(Vector3::new(0.0, 0.0, -1.0))).0
+        };
+        // The top edge is 45 degrees above the view direction.
+        assert!((angle_to_view(200, 0) - 45.0).abs() < 1e-3);
+        // The image is twice as wide as it is tall, so the pixels stay square.
+        let left = camera.generate_ray::<StdRng>(0, 100, None).get_direction();
+        assert!((left.x / left.z - 2.0).abs() < 1e-4);
+        let right = camera
+            .generate_ray::<StdRng>(400, 100, None)
+            .get_direction();
+        assert!((right.x / right.z + 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_projections() {
+        let mut camera = Camera::new(
+            200,
+            100,
+            (0.0, 0.0, 0.0).into(),
+            (0.0, 0.0, -1.0).into(),
+            (0.0, 1.0, 0.0).into(),
+            Deg(180.0 - 1e-3),
+            0.0,
+            1.0,
+        );
+        let forward = Vector3::new(0.0, 0.0, -1.0);
+        let direction = |camera: &Camera, x: u32, y: u32| {
+            camera.generate_ray::<StdRng>(x, y, None).get_direction()
+        };
+        let is_close = |a: Vector3<f32>, b: Vector3<f32>| (a - b).magnitude() < 1e-3;
+
+        camera.projection = Projection::Orthographic(4.0);
+        let top_left = camera.generate_ray::<StdRng>(0, 0, None);
+        assert!(is_close(top_left.get_direction(), forward));
+        let origin: Point3<f32> = top_left.get_point_on_ray(0.0).into();
+        assert!(origin.distance(Point3::new(-4.0, 2.0, 0.0)) < 1e-3);
+
+        camera.projection = Projection::Equirectangular;
+        assert!(is_close(direction(&camera, 100, 50), forward));
+        assert!(is_close(direction(&camera, 0, 50), -forward));
+        assert!(is_close(
+            direction(&camera, 50, 50),
+            (-1.0, 0.0, 0.0).into()
+        ));
+        assert!(is_close(direction(&camera, 100, 0), (0.0, 1.0, 0.0).into()));
+
+        // A fisheye lens can see more than a hemisphere.
+        camera.projection = Projection::Fisheye;
+        assert!(is_close(direction(&camera, 100, 50), forward));
+        assert!(is_close(direction(&camera, 100, 0), (0.0, 1.0, 0.0).into()));
+        assert!(direction(&camera, 0, 50).z > 0.0);
+    }
+}
@@ -1,6 +1,10 @@
 use super::utils::clamp;
 use cgmath::Vector4;
 
+/// A linear RGBA color.
+///
+/// The red, green, and blue channels are never negative but have no upper bound, so
+/// colors brighter than white are kept until the image is saved.
 #[derive(Debug, Copy, Clone)]
 pub struct Color {
     r: f32,
@@ -15,22 +19,35 @@ impl Color {
     }
 
     pub fn rgba(r: f32, g: f32, b: f32, a: f32) -> Color {
-        let r = clamp(r, 0.0, 1.0);
-        let g = clamp(g, 0.0, 1.0);
-        let b = clamp(b, 0.0, 1.0);
+        // `f32::max` also turns NaN into zero.
+        let r = r.max(0.0);
+        let g = g.max(0.0);
+        let b = b.max(0.0);
         let a = clamp(a, 0.0, 1.0);
         Color { r, g, b, a }
     }
 
+    /// Returns the 8-bit red, green, and blue channels. Channels brighter than one
+    /// are clipped.
     pub fn get_rgb(&self) -> (u8, u8, u8) {
         (
-            (self.r * 255.0) as u8,
-            (self.g * 255.0) as u8,
-            (self.b * 255.0) as u8,
+            (clamp(self.r, 0.0, 1.0) * 255.0) as u8,
+            (clamp(self.g, 0.0, 1.0) * 255.0) as u8,
+            (clamp(self.b, 0.0, 1.0) * 255.0) as u8,
         )
     }
 
-    pub fn to_vec(&self) -> Vector4<f32> {
+    /// Returns the unclamped linear red, green, and blue channels.
+    pub fn get_rgb_f32(&self) -> (f32, f32, f32) {
+        (self.r, self.g, self.b)
+    }
+
+    /// Returns true if the red, green, and blue channels are all zero.
+    pub fn is_black(&self) -> bool {
+        self.r == 0.0 && self.g == 0.0 && self.b == 0.0
+    }
+
+    pub fn to_vec(self) -> Vector4<f32> {
         Vector4::new(self.r, self.g, self.b, self.a)
     }
 
@@ -123,3 +140,9 @@ impl From<(f32, f32, f32, f32)> for Color {
         Color::rgba(v.0, v.1, v.2, v.3)
     }
 }
+
+impl From<[f32; 3]> for Color {
+    fn from(v: [f32; 3]) -> Self {
+        Color::rgb(v[0], v[1], v[2])
+    }
+}
@@ -0,0 +1,173 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use super::color::Color;
+use super::tone_mapping::DisplayTransform;
+
+/// A rendered image held in memory as unclamped linear colors.
+///
+/// Pixel (0, 0) is in the top left corner.
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<Color>,
+}
+
+impl Framebuffer {
+    /// Creates a black framebuffer of the given size.
+    pub fn new(width: u32, height: u32) -> Self {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Color::black(); (width * height) as usize],
+        }
+    }
+
+    pub fn get_width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Color {
+        self.pixels[self.get_index(x, y)]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
+        let index = self.get_index(x, y);
+        self.pixels[index] = color;
+    }
+
+    fn get_index(&self, x: u32, y: u32) -> usize {
+        debug_assert!(x < self.width);
+        debug_assert!(y < self.height);
+        (y * self.width + x) as usize
+    }
+
+    /// Converts to an 8-bit RGB image using `display_transform`.
+    pub fn to_image(&self, display_transform: &DisplayTransform) -> image::RgbImage {
+        image::ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            display_transform.apply(self.get_pixel(x, y)).into()
+        })
+    }
+
+    /// Saves to an image file. The format is chosen from the extension of `path`.
+    ///
+    /// `.exr` (OpenEXR) and `.hdr` (Radiance HDR) files keep the full range of linear
+    /// colors and ignore `display_transform`. Other formats are saved as 8-bit images
+    /// using `to_image`.
+    pub fn save<P>(
+        &self,
+        path: P,
+        display_transform: &DisplayTransform,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        match extension.as_deref() {
+            Some("exr") => self.save_exr(path),
+            Some("hdr") => self.save_hdr(path),
+            _ => {
+                self.to_image(display_transform).save(path)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn save_exr(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        exr::prelude::write_rgb_file(path, self.width as usize, self.height as usize, |x, y| {
+            self.get_pixel(x as u32, y as u32).get_rgb_f32()
+        })?;
+        Ok(())
+    }
+
+    fn save_hdr(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let pixels: Vec<image::Rgb<f32>> = self
+            .pixels
+            .iter()
+            .map(|color| {
+                let (r, g, b) = color.get_rgb_f32();
+                image::Rgb([r, g, b])
+            })
+            .collect();
+        let writer = BufWriter::new(File::create(path)?);
+        image::codecs::hdr::HdrEncoder::new(writer).encode(
+            &pixels,
+            self.width as usize,
+            self.height as usize,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Framebuffer;
+    use crate::color::Color;
+    use crate::tone_mapping::{DisplayTransform, Encoding, ToneMapping};
+    use std::fs::File;
+    use std::io::BufReader;
+
+    fn new_framebuffer() -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(3, 2);
+        framebuffer.set_pixel(0, 0, Color::rgb(4.0, 0.5, 0.0));
+        framebuffer.set_pixel(2, 1, Color::rgb(0.25, 16.0, 1.0));
+        framebuffer
+    }
+
+    #[test]
+    fn test_save_hdr() {
+        let path = std::env::temp_dir().join("rust_raytracer_test_save.hdr");
+        new_framebuffer()
+            .save(&path, &DisplayTransform::default())
+            .unwrap();
+        let reader = BufReader::new(File::open(&path).unwrap());
+        let decoder = image::hdr::HdrDecoder::new(reader).unwrap();
+        let pixels = decoder.read_image_hdr().unwrap();
+        assert_eq!(pixels.len(), 6);
+        assert_eq!(pixels[0].0, [4.0, 0.5, 0.0]);
+        assert_eq!(pixels[5].0, [0.25, 16.0, 1.0]);
+    }
+
+    #[test]
+    fn test_save_exr() {
+        let path = std::env::temp_dir().join("rust_raytracer_test_save.exr");
+        new_framebuffer()
+            .save(&path, &DisplayTransform::default())
+            .unwrap();
+        let image = exr::prelude::read_first_rgba_layer_from_file(
+            &path,
+            |resolution, _| (resolution.width(), vec![[0.0; 3]; resolution.area()]),
+            |(width, pixels), position, (r, g, b, _): (f32, f32, f32, f32)| {
+                pixels[position.y() * *width + position.x()] = [r, g, b];
+            },
+        )
+        .unwrap();
+        let (width, pixels) = image.layer_data.channel_data.pixels;
+        assert_eq!(width, 3);
+        assert_eq!(pixels[0], [4.0, 0.5, 0.0]);
+        assert_eq!(pixels[5], [0.25, 16.0, 1.0]);
+    }
+
+    #[test]
+    fn test_to_image() {
+        let display_transform = DisplayTransform {
+            exposure: 0.0,
+            tone_mapping: ToneMapping::Clamp,
+            encoding: Encoding::Linear,
+        };
+        let image = new_framebuffer().to_image(&display_transform);
+        assert_eq!(image.get_pixel(0, 0).0, [255, 128, 0]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 0, 0]);
+    }
+}
@@ -0,0 +1,289 @@
+use cgmath::{Point3, Vector3};
+use std::borrow::Cow;
+
+use super::accelerator::Accelerator;
+use super::bvh::{get_mesh_bvh_stats, BvhReport, AABB};
+use super::interaction::SurfaceInteraction;
+use super::object::{Hit, Object};
+use super::ray::Ray;
+
+/// A uniform grid of cells over the objects of a scene. Each cell lists the objects
+/// whose bounding boxes overlap it, and rays visit the cells they pass through in
+/// order.
+///
+/// Grids are quick to build and work well for many objects of similar size spread
+/// evenly through the scene, but a few large or many clustered objects make them
+/// much slower than a bvh.
+pub struct Grid {
+    objects: Vec<Object>,
+    has_transparent_objects: bool,
+    bounds: AABB,
+    /// The number of cells along each axis.
+    resolution: [usize; 3],
+    cell_size: Vector3<f32>,
+    /// The objects of cell `i` are `object_indices[cell_offsets[i]..cell_offsets[i + 1]]`.
+    /// Cells are ordered by x, then y, then z.
+    cell_offsets: Vec<usize>,
+    object_indices: Vec<usize>,
+}
+
+/// The maximum number of cells per object in a grid.
+const MAX_CELLS_PER_OBJECT: f32 = 32.0;
+
+/// The maximum number of cells along each axis of a grid.
+const MAX_RESOLUTION: usize = 128;
+
+impl Grid {
+    /// Builds a grid over `objects`, with cells about as wide as they are deep and
+    /// tall.
+    ///
+    /// Cells are about the size of a typical object, so that a few large objects
+    /// that stretch the grid do not leave all the others in the same few cells.
+    pub fn new(objects: Vec<Object>) -> Self {
+        let has_transparent_objects = !objects.iter().all(Object::is_opaque);
+        let bounding_boxes: Vec<AABB> = objects
+            .iter()
+            .map(|object| {
+                let (min, max) = object.get_bounding_box();
+                AABB::new(min, max)
+            })
+            .collect();
+        let bounds = AABB::union(bounding_boxes.clone());
+
+        let extent = bounds.max - bounds.min;
+        let mut object_sizes: Vec<f32> = bounding_boxes
+            .iter()
+            .map(|aabb| {
+                let size = aabb.max - aabb.min;
+                size.x.max(size.y).max(size.z)
+            })
+            .collect();
+        object_sizes.sort_by(f32::total_cmp);
+        let typical_size = object_sizes.get(object_sizes.len() / 2).copied();
+        let mut cells_per_unit = match typical_size {
+            Some(size) if size > 0.0 => 1.0 / size,
+            _ => 0.0,
+        };
+        let num_cells: f32 = (0..3)
+            .map(|axis| (extent[axis] * cells_per_unit).max(1.0))
+            .product();
+        let max_cells = MAX_CELLS_PER_OBJECT * objects.len() as f32;
+        if num_cells > max_cells {
+            cells_per_unit *= (max_cells / num_cells).cbrt();
+        }
+        let mut resolution = [1; 3];
+        let mut cell_size = extent;
+        for axis in 0..3 {
+            resolution[axis] =
+                ((extent[axis] * cells_per_unit).round() as usize).clamp(1, MAX_RESOLUTION);
+            cell_size[axis] = extent[axis] / resolution[axis] as f32;
+        }
+
+        let mut grid = Grid {
+            objects,
+            has_transparent_objects,
+            bounds,
+            resolution,
+            cell_size,
+            cell_offsets: vec![],
+            object_indices: vec![],
+        };
+
+        // Count the objects of each cell first so that they can be stored contiguously.
+        let num_cells = resolution.iter().product();
+        let cell_ranges: Vec<([usize; 3], [usize; 3])> = bounding_boxes
+            .iter()
+            .map(|aabb| (grid.get_cell(aabb.min), grid.get_cell(aabb.max)))
+            .collect();
+        let mut cell_counts = vec![0; num_cells];
+        for (min, max) in &cell_ranges {
+            grid.for_each_cell(*min, *max, |cell| cell_counts[cell] += 1);
+        }
+        let mut cell_offsets = Vec::with_capacity(num_cells + 1);
+        cell_offsets.push(0);
+        for count in cell_counts {
+            cell_offsets.push(cell_offsets.last().unwrap() + count);
+        }
+        let mut object_indices = vec![0; *cell_offsets.last().unwrap()];
+        let mut next_index = cell_offsets.clone();
+        for (object, (min, max)) in cell_ranges.into_iter().enumerate() {
+            grid.for_each_cell(min, max, |cell| {
+                object_indices[next_index[cell]] = object;
+                next_index[cell] += 1;
+            });
+        }
+        grid.cell_offsets = cell_offsets;
+        grid.object_indices = object_indices;
+        grid
+    }
+
+    /// Returns the coordinates of the cell that contains `point`, clamped to the grid.
+    fn get_cell(&self, point: Point3<f32>) -> [usize; 3] {
+        let mut cell = [0; 3];
+        for axis in 0..3 {
+            if self.cell_size[axis] > 0.0 {
+                let x = (point[axis] - self.bounds.min[axis]) / self.cell_size[axis];
+                cell[axis] = (x.max(0.0) as usize).min(self.resolution[axis] - 1);
+            }
+        }
+        cell
+    }
+
+    fn get_cell_index(&self, cell: [usize; 3]) -> usize {
+        cell[0] + self.resolution[0] * (cell[1] + self.resolution[1] * cell[2])
+    }
+
+    /// Calls `f` with the index of every cell from `min` to `max` inclusive.
+    fn for_each_cell<F: FnMut(usize)>(&self, min: [usize; 3], max: [usize; 3], mut f: F) {
+        for z in min[2]..=max[2] {
+            for y in min[1]..=max[1] {
+                for x in min[0]..=max[0] {
+                    f(self.get_cell_index([x, y, z]));
+                }
+            }
+        }
+    }
+
+    /// Calls `visit` with the objects of each cell that `ray` passes through within
+    /// its range, in order, along with the `t` at which the ray leaves the cell. Stops
+    /// once `visit` returns true.
+    ///
+    /// Cells are stepped through with the 3D DDA of Amanatides and Woo, "A Fast Voxel
+    /// Traversal Algorithm for Ray Tracing".
+    fn visit_cells<F>(&self, ray: &Ray, mut visit: F)
+    where
+        F: FnMut(&[usize], f32) -> bool,
+    {
+        if self.objects.is_empty() {
+            return;
+        }
+        let (t_enter, t_exit) = match self.bounds.get_ray_range(ray) {
+            Some(range) => range,
+            None => return,
+        };
+        let direction = ray.get_direction();
+        let entry_point: Point3<f32> = ray.get_point_on_ray(t_enter).into();
+        let mut cell = self.get_cell(entry_point);
+
+        // For each axis, the `t` at which the ray crosses into the next cell along
+        // that axis, and how much `t` grows from one such crossing to the next.
+        let mut next_crossing = [f32::INFINITY; 3];
+        let mut delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            let cell_min = self.bounds.min[axis] + cell[axis] as f32 * self.cell_size[axis];
+            if direction[axis] > 0.0 {
+                let boundary = cell_min + self.cell_size[axis];
+                next_crossing[axis] = t_enter + (boundary - entry_point[axis]) / direction[axis];
+                delta[axis] = self.cell_size[axis] / direction[axis];
+            } else if direction[axis] < 0.0 {
+                next_crossing[axis] = t_enter + (cell_min - entry_point[axis]) / direction[axis];
+                delta[axis] = -self.cell_size[axis] / direction[axis];
+            }
+        }
+
+        loop {
+            let axis = (0..3)
+                .min_by(|&a, &b| next_crossing[a].total_cmp(&next_crossing[b]))
+                .unwrap();
+            let cell_exit = next_crossing[axis].min(t_exit);
+            let index = self.get_cell_index(cell);
+            let objects =
+                &self.object_indices[self.cell_offsets[index]..self.cell_offsets[index + 1]];
+            if visit(objects, cell_exit) || cell_exit >= t_exit {
+                return;
+            }
+            if direction[axis] > 0.0 {
+                cell[axis] += 1;
+                if cell[axis] == self.resolution[axis] {
+                    return;
+                }
+            } else {
+                if cell[axis] == 0 {
+                    return;
+                }
+                cell[axis] -= 1;
+            }
+            next_crossing[axis] += delta[axis];
+        }
+    }
+}
+
+impl Accelerator for Grid {
+    fn get_closest_intersection(&self, ray: &Ray) -> Option<(Cow<'_, Object>, SurfaceInteraction)> {
+        // Objects can overlap several cells, so a hit found in one cell may lie in a
+        // later one. Only stop once the closest hit is within the cells visited.
+        // The range of `closest_ray` ends at the closest hit found so far.
+        let mut closest: Option<(usize, Hit)> = None;
+        let mut closest_ray = ray.clone();
+        self.visit_cells(ray, |objects, cell_exit| {
+            for &index in objects {
+                let object = &self.objects[index];
+                if let Some(hit) = object.get_face_intersection(&closest_ray) {
+                    closest = Some((index, hit));
+                    let t_min = closest_ray.get_t_min();
+                    closest_ray = closest_ray.clone().with_range(t_min, hit.t);
+                }
+            }
+            closest.is_some_and(|(_, hit)| hit.t <= cell_exit)
+        });
+        closest.map(|(index, hit)| {
+            let object = &self.objects[index];
+            (
+                object.get_face(hit.face),
+                object.get_surface_interaction(ray, &hit),
+            )
+        })
+    }
+
+    fn is_occluded(&self, ray: &Ray, max_t: f32) -> bool {
+        let t_max = ray.get_t_max().min(max_t);
+        if t_max < ray.get_t_min() {
+            return false;
+        }
+        let ray = ray.clone().with_range(ray.get_t_min(), t_max);
+        let mut occluded = false;
+        self.visit_cells(&ray, |objects, _| {
+            occluded = objects
+                .iter()
+                .any(|&index| self.objects[index].intersects(&ray));
+            occluded
+        });
+        occluded
+    }
+
+    fn has_transparent_objects(&self) -> bool {
+        self.has_transparent_objects
+    }
+
+    fn get_bvh_report(&self) -> BvhReport {
+        BvhReport {
+            objects: None,
+            meshes: get_mesh_bvh_stats(&self.objects),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Grid;
+    use crate::material::{Material, MaterialType, TextureType};
+    use crate::object::Object;
+
+    #[test]
+    fn test_grid() {
+        let m = Material::new(MaterialType::None, TextureType::None);
+        // A row of spheres along the x axis.
+        let spheres: Vec<Object> = (0..10)
+            .map(|i| Object::new_sphere((2.0 * i as f32, 0.0, 0.0).into(), 0.5, m.clone()))
+            .collect();
+        let grid = Grid::new(spheres);
+        assert!(grid.resolution[0] > 1);
+        assert_eq!(
+            grid.resolution[0],
+            grid.resolution.iter().product::<usize>()
+        );
+        // Every sphere is in at least one cell, and no more than two.
+        assert!(grid.object_indices.len() >= 10);
+        assert!(grid.object_indices.len() <= 20);
+    }
+}
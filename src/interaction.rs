@@ -0,0 +1,43 @@
+use cgmath::{Point2, Point3, Vector3};
+
+/// Where a ray hits the surface of an object, along with the geometry of the surface
+/// there that materials shade it with. See `Object::get_surface_interaction`.
+///
+/// All points and vectors are in world space coordinates.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SurfaceInteraction {
+    /// How far along the ray the hit is, so that `point` is at `ray.get_point_on_ray(t)`.
+    pub t: f32,
+    pub point: Point3<f32>,
+    /// The unit normal of the surface itself, which always faces against the ray.
+    pub geometric_normal: Vector3<f32>,
+    /// The unit normal to shade with, on the same side of the surface as
+    /// `geometric_normal`. Faces of meshes with vertex normals interpolate them, and
+    /// other surfaces use `geometric_normal`.
+    pub shading_normal: Vector3<f32>,
+    /// The texture coordinates at `point`.
+    pub uv: Point2<f32>,
+    /// How `point` moves along the surface as `u` changes.
+    pub dpdu: Vector3<f32>,
+    /// How `point` moves along the surface as `v` changes.
+    pub dpdv: Vector3<f32>,
+    /// True if the ray hits the outside of the surface: the front of a triangle or
+    /// quad, which is the side from which its vertices are in counterclockwise order,
+    /// or the outside of a sphere. The normals are flipped when this is false.
+    pub front_face: bool,
+    /// The index of the face of a mesh that was hit, or zero for other objects.
+    pub primitive_id: usize,
+}
+
+impl SurfaceInteraction {
+    /// Returns `shading_normal` pointing to the outside of the surface, away from the
+    /// ray if it hits the front and towards it otherwise. Refraction uses it to tell
+    /// rays entering an object from rays leaving it.
+    pub fn get_outward_normal(&self) -> Vector3<f32> {
+        if self.front_face {
+            self.shading_normal
+        } else {
+            -self.shading_normal
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::borrow::Cow;
+
+use super::accelerator::Accelerator;
+use super::bvh::{get_mesh_bvh_stats, BvhReport, AABB};
+use super::interaction::SurfaceInteraction;
+use super::object::{Hit, Object};
+use super::ray::Ray;
+
+/// A kd-tree over the objects of a scene, built with the surface area heuristic as
+/// in pbrt's `KdTreeAccel`.
+///
+/// Unlike a bvh, the nodes of a kd-tree split space rather than objects, so an
+/// object that crosses a split is in both children. In exchange, the children of a
+/// node never overlap and rays can stop at the first node with a hit.
+pub struct KdTree {
+    objects: Vec<Object>,
+    has_transparent_objects: bool,
+    bounds: AABB,
+    /// The nodes in depth first order. The child below the split of an interior node
+    /// comes right after it.
+    nodes: Vec<KdTreeNode>,
+    /// The objects of each leaf, stored contiguously.
+    object_indices: Vec<usize>,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum KdTreeNode {
+    Interior {
+        axis: usize,
+        split: f32,
+        above_child: usize,
+    },
+    Leaf {
+        objects_offset: usize,
+        num_objects: usize,
+    },
+}
+
+/// The cost of intersecting an object relative to visiting a node.
+const INTERSECTION_COST: f32 = 80.0;
+
+/// How much cheaper a split is for each child it leaves empty, as a fraction.
+const EMPTY_BONUS: f32 = 0.5;
+
+/// Nodes with at most this many objects are always leaves.
+const MAX_LEAF_SIZE: usize = 1;
+
+/// The number of splits along a path that may cost more than a leaf would, in case
+/// later splits pay off.
+const MAX_BAD_REFINES: usize = 3;
+
+/// The maximum depth of a kd-tree, which keeps the traversal stack small.
+const MAX_DEPTH: usize = 64;
+
+/// Where the bounding box of an object starts or ends along an axis.
+#[derive(Debug, Copy, Clone)]
+struct BoundEdge {
+    t: f32,
+    is_start: bool,
+}
+
+impl KdTree {
+    /// Builds a kd-tree over `objects` on a single thread.
+    pub fn new(objects: Vec<Object>) -> Self {
+        let has_transparent_objects = !objects.iter().all(Object::is_opaque);
+        let bounding_boxes: Vec<AABB> = objects
+            .iter()
+            .map(|object| {
+                let (min, max) = object.get_bounding_box();
+                AABB::new(min, max)
+            })
+            .collect();
+        let bounds = AABB::union(bounding_boxes.clone());
+        let mut tree = KdTree {
+            objects,
+            has_transparent_objects,
+            bounds,
+            nodes: vec![],
+            object_indices: vec![],
+        };
+        if !tree.objects.is_empty() {
+            let max_depth = (8.0 + 1.3 * (tree.objects.len() as f32).log2()).round() as usize;
+            let indices = (0..tree.objects.len()).collect();
+            tree.build(
+                &bounding_boxes,
+                bounds,
+                indices,
+                max_depth.min(MAX_DEPTH),
+                0,
+            );
+        }
+        tree
+    }
+
+    /// Appends the subtree over `indices` within `node_bounds` to the nodes.
+    fn build(
+        &mut self,
+        bounding_boxes: &[AABB],
+        node_bounds: AABB,
+        indices: Vec<usize>,
+        depth: usize,
+        mut bad_refines: usize,
+    ) {
+        let num_objects = indices.len();
+        let split = if num_objects <= MAX_LEAF_SIZE || depth == 0 {
+            None
+        } else {
+            find_split(bounding_boxes, &node_bounds, &indices)
+        };
+        let leaf_cost = INTERSECTION_COST * num_objects as f32;
+        let (axis, split, cost) = match split {
+            Some(split) => split,
+            None => return self.push_leaf(indices),
+        };
+        if cost > leaf_cost {
+            bad_refines += 1;
+        }
+        if (cost > 4.0 * leaf_cost && num_objects < 16) || bad_refines >= MAX_BAD_REFINES {
+            return self.push_leaf(indices);
+        }
+
+        // Objects that lie flat on the split go on both sides.
+        let (below, above): (Vec<usize>, Vec<usize>) = (
+            indices
+                .iter()
+                .copied()
+                .filter(|&i| {
+                    bounding_boxes[i].min[axis] < split || bounding_boxes[i].max[axis] == split
+                })
+                .collect(),
+            indices
+                .iter()
+                .copied()
+                .filter(|&i| {
+                    bounding_boxes[i].max[axis] > split || bounding_boxes[i].min[axis] == split
+                })
+                .collect(),
+        );
+        let mut below_bounds = node_bounds;
+        below_bounds.max[axis] = split;
+        let mut above_bounds = node_bounds;
+        above_bounds.min[axis] = split;
+
+        let index = self.nodes.len();
+        self.nodes.push(KdTreeNode::Interior {
+            axis,
+            split,
+            above_child: 0,
+        });
+        self.build(bounding_boxes, below_bounds, below, depth - 1, bad_refines);
+        let above_child = self.nodes.len();
+        self.build(bounding_boxes, above_bounds, above, depth - 1, bad_refines);
+        self.nodes[index] = KdTreeNode::Interior {
+            axis,
+            split,
+            above_child,
+        };
+    }
+
+    fn push_leaf(&mut self, indices: Vec<usize>) {
+        self.nodes.push(KdTreeNode::Leaf {
+            objects_offset: self.object_indices.len(),
+            num_objects: indices.len(),
+        });
+        self.object_indices.extend(indices);
+    }
+
+    /// Calls `visit` with the objects of each leaf that `ray` passes through within
+    /// its range, nearest first, along with the `t` at which the ray leaves the leaf.
+    /// Stops once `visit` returns true.
+    fn visit_leaves<F>(&self, ray: &Ray, mut visit: F)
+    where
+        F: FnMut(&[usize], f32) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let (mut t_min, mut t_max) = match self.bounds.get_ray_range(ray) {
+            Some(range) => range,
+            None => return,
+        };
+        let origin: [f32; 3] = ray.get_point_on_ray(0.0).into();
+        let direction = ray.get_direction();
+        // Nodes still to visit, with the range of `t` for which the ray is inside them.
+        let mut stack = Vec::with_capacity(MAX_DEPTH);
+        let mut index = 0;
+        loop {
+            match self.nodes[index] {
+                KdTreeNode::Interior {
+                    axis,
+                    split,
+                    above_child,
+                } => {
+                    let t_split = (split - origin[axis]) / direction[axis];
+                    let below_first =
+                        origin[axis] < split || (origin[axis] == split && direction[axis] <= 0.0);
+                    let (first, second) = if below_first {
+                        (index + 1, above_child)
+                    } else {
+                        (above_child, index + 1)
+                    };
+                    if t_split > t_max || t_split <= 0.0 {
+                        index = first;
+                    } else if t_split < t_min {
+                        index = second;
+                    } else {
+                        stack.push((second, t_split, t_max));
+                        index = first;
+                        t_max = t_split;
+                    }
+                }
+                KdTreeNode::Leaf {
+                    objects_offset,
+                    num_objects,
+                } => {
+                    let objects =
+                        &self.object_indices[objects_offset..objects_offset + num_objects];
+                    if visit(objects, t_max) {
+                        return;
+                    }
+                    match stack.pop() {
+                        Some((next, next_t_min, next_t_max)) => {
+                            index = next;
+                            t_min = next_t_min;
+                            t_max = next_t_max;
+                        }
+                        None => return,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the axis, position, and cost of the cheapest split of a node within
+/// `node_bounds` over the objects `indices`, according to the surface area
+/// heuristic, or `None` if the node cannot be split.
+///
+/// Tries the longest axis of the node first, and the others only if no split along
+/// it is inside the node.
+fn find_split(
+    bounding_boxes: &[AABB],
+    node_bounds: &AABB,
+    indices: &[usize],
+) -> Option<(usize, f32, f32)> {
+    let extent = node_bounds.max - node_bounds.min;
+    let total_area = node_bounds.surface_area();
+    if total_area <= 0.0 {
+        return None;
+    }
+    let longest_axis = (0..3)
+        .max_by(|&a, &b| extent[a].total_cmp(&extent[b]))
+        .unwrap();
+
+    for axis in (0..3).map(|i| (longest_axis + i) % 3) {
+        let mut edges: Vec<BoundEdge> = indices
+            .iter()
+            .flat_map(|&object| {
+                let aabb = &bounding_boxes[object];
+                vec![
+                    BoundEdge {
+                        t: aabb.min[axis],
+                        is_start: true,
+                    },
+                    BoundEdge {
+                        t: aabb.max[axis],
+                        is_start: false,
+                    },
+                ]
+            })
+            .collect();
+        // Starts come before ends at the same position.
+        edges.sort_by(|a, b| a.t.total_cmp(&b.t).then(b.is_start.cmp(&a.is_start)));
+
+        let other_axes = [(axis + 1) % 3, (axis + 2) % 3];
+        let (d0, d1) = (extent[other_axes[0]], extent[other_axes[1]]);
+        let mut best: Option<(f32, f32)> = None;
+        let (mut num_below, mut num_above) = (0, indices.len());
+        for edge in &edges {
+            if !edge.is_start {
+                num_above -= 1;
+            }
+            if node_bounds.min[axis] < edge.t && edge.t < node_bounds.max[axis] {
+                let below_area = 2.0 * (d0 * d1 + (edge.t - node_bounds.min[axis]) * (d0 + d1));
+                let above_area = 2.0 * (d0 * d1 + (node_bounds.max[axis] - edge.t) * (d0 + d1));
+                let empty_bonus = if num_below == 0 || num_above == 0 {
+                    EMPTY_BONUS
+                } else {
+                    0.0
+                };
+                let cost = 1.0
+                    + INTERSECTION_COST
+                        * (1.0 - empty_bonus)
+                        * (below_area * num_below as f32 + above_area * num_above as f32)
+                        / total_area;
+                if best.is_none_or(|(_, best_cost)| cost < best_cost) {
+                    best = Some((edge.t, cost));
+                }
+            }
+            if edge.is_start {
+                num_below += 1;
+            }
+        }
+        if let Some((split, cost)) = best {
+            return Some((axis, split, cost));
+        }
+    }
+    None
+}
+
+impl Accelerator for KdTree {
+    fn get_closest_intersection(&self, ray: &Ray) -> Option<(Cow<'_, Object>, SurfaceInteraction)> {
+        // An object in several leaves can be hit beyond the leaf it was found in, so
+        // only stop once the closest hit is within the leaves visited.
+        let mut closest: Option<(usize, Hit)> = None;
+        let mut closest_ray = ray.clone();
+        self.visit_leaves(ray, |objects, leaf_exit| {
+            for &index in objects {
+                let object = &self.objects[index];
+                if let Some(hit) = object.get_face_intersection(&closest_ray) {
+                    closest = Some((index, hit));
+                    let t_min = closest_ray.get_t_min();
+                    closest_ray = closest_ray.clone().with_range(t_min, hit.t);
+                }
+            }
+            closest.is_some_and(|(_, hit)| hit.t <= leaf_exit)
+        });
+        closest.map(|(index, hit)| {
+            let object = &self.objects[index];
+            (
+                object.get_face(hit.face),
+                object.get_surface_interaction(ray, &hit),
+            )
+        })
+    }
+
+    fn is_occluded(&self, ray: &Ray, max_t: f32) -> bool {
+        let t_max = ray.get_t_max().min(max_t);
+        if t_max < ray.get_t_min() {
+            return false;
+        }
+        let ray = ray.clone().with_range(ray.get_t_min(), t_max);
+        let mut occluded = false;
+        self.visit_leaves(&ray, |objects, _| {
+            occluded = objects
+                .iter()
+                .any(|&index| self.objects[index].intersects(&ray));
+            occluded
+        });
+        occluded
+    }
+
+    fn has_transparent_objects(&self) -> bool {
+        self.has_transparent_objects
+    }
+
+    fn get_bvh_report(&self) -> BvhReport {
+        BvhReport {
+            objects: None,
+            meshes: get_mesh_bvh_stats(&self.objects),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KdTree, KdTreeNode};
+    use crate::material::{Material, MaterialType, TextureType};
+    use crate::object::Object;
+
+    #[test]
+    fn test_kdtree() {
+        let m = Material::new(MaterialType::None, TextureType::None);
+        // A row of spheres along the x axis, which should be split apart.
+        let spheres: Vec<Object> = (0..10)
+            .map(|i| Object::new_sphere((2.0 * i as f32, 0.0, 0.0).into(), 0.5, m.clone()))
+            .collect();
+        let tree = KdTree::new(spheres);
+        let leaves: Vec<usize> = tree
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                KdTreeNode::Leaf { num_objects, .. } => Some(*num_objects),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tree.nodes.len(), 2 * leaves.len() - 1);
+        assert!(leaves.iter().all(|&num_objects| num_objects <= 1));
+        assert_eq!(leaves.iter().sum::<usize>(), 10);
+    }
+}
@@ -0,0 +1,25 @@
+#[macro_use]
+extern crate log;
+
+pub mod accelerator;
+pub mod bvh;
+pub mod camera;
+pub mod color;
+pub mod framebuffer;
+pub mod grid;
+pub mod interaction;
+pub mod kdtree;
+pub mod light;
+pub mod material;
+pub mod mesh;
+pub mod object;
+pub mod packet;
+pub mod ray;
+pub mod scene;
+pub mod scene_file;
+#[cfg(test)]
+mod test_scenes;
+pub mod tone_mapping;
+mod utils;
+pub mod wide_bvh;
+pub mod world;
@@ -1,24 +1,46 @@
-use super::bvh::Bvh;
+use super::accelerator::Accelerator;
 use super::color::Color;
 use super::ray::Ray;
-use super::utils::clamp;
+use super::utils::{clamp, orthonormal_basis, sample_disk};
 use cgmath::{Deg, InnerSpace, MetricSpace, Point3, Vector3};
+use rand::Rng;
+use std::f32::consts::PI;
 
 /// Ambient light has no position or direction
 /// Point lights illumate from a single position
 /// Directional lights represent parallel rays coming from infinitely far away.
 /// Cone lights are a point source in a certain direction, but only illuminate
 ///     within an angle of the direction.
+/// Rectangle, disk, and sphere lights illuminate from every point of their area
+///     and cast soft shadows. Rectangle and disk lights only illuminate the side
+///     their normal points towards.
 pub enum LightType {
     Ambient,
-    Point(Point3<f32>),                        // position
-    Directional(Vector3<f32>),                 // direction of parallel light rays
-    Cone(Point3<f32>, Vector3<f32>, Deg<f32>), // position, direction, angle
+    Point(Point3<f32>),                                 // position
+    Directional(Vector3<f32>),                          // direction of parallel light rays
+    Cone(Point3<f32>, Vector3<f32>, Deg<f32>),          // position, direction, angle
+    Rectangle(Point3<f32>, Vector3<f32>, Vector3<f32>), // corner, edge, edge (normal is edge x edge)
+    Disk(Point3<f32>, Vector3<f32>, f32),               // center, normal, radius
+    Sphere(Point3<f32>, f32),                           // center, radius
 }
 
+/// Shadow rays stop after passing through this many transparent surfaces.
+const MAX_SHADOW_RAY_SURFACES: usize = 16;
+
 pub struct Light {
     pub color: Color,
     pub light_type: LightType,
+    /// The number of points on the light to sample per illuminated point.
+    /// Only area lights use more than one sample.
+    pub samples: u16,
+}
+
+/// Light arriving at a point from a single direction.
+pub struct LightSample {
+    /// Unit vector pointing from the illuminated point towards the light.
+    pub direction: Vector3<f32>,
+    /// The color of the light after falloff.
+    pub color: Color,
 }
 
 impl Light {
@@ -26,6 +48,7 @@ impl Light {
         Light {
             color,
             light_type: LightType::Point(position),
+            samples: 1,
         }
     }
 
@@ -33,6 +56,7 @@ impl Light {
         Light {
             color,
             light_type: LightType::Ambient,
+            samples: 1,
         }
     }
 
@@ -40,6 +64,7 @@ impl Light {
         Light {
             color,
             light_type: LightType::Directional(direction.normalize()),
+            samples: 1,
         }
     }
 
@@ -52,16 +77,55 @@ impl Light {
         Light {
             color,
             light_type: LightType::Cone(position, direction.normalize(), angle),
+            samples: 1,
+        }
+    }
+
+    /// A parallelogram light with corners `corner`, `corner + edge_u`,
+    /// `corner + edge_u + edge_v`, and `corner + edge_v`. It illuminates the side
+    /// that `edge_u.cross(edge_v)` points towards.
+    pub fn new_rectangle(
+        corner: Point3<f32>,
+        edge_u: Vector3<f32>,
+        edge_v: Vector3<f32>,
+        color: Color,
+        samples: u16,
+    ) -> Light {
+        assert!(samples != 0, "Area lights need at least one sample");
+        Light {
+            color,
+            light_type: LightType::Rectangle(corner, edge_u, edge_v),
+            samples,
+        }
+    }
+
+    pub fn new_disk(
+        center: Point3<f32>,
+        normal: Vector3<f32>,
+        radius: f32,
+        color: Color,
+        samples: u16,
+    ) -> Light {
+        assert!(samples != 0, "Area lights need at least one sample");
+        Light {
+            color,
+            light_type: LightType::Disk(center, normal.normalize(), radius),
+            samples,
+        }
+    }
+
+    pub fn new_sphere(center: Point3<f32>, radius: f32, color: Color, samples: u16) -> Light {
+        assert!(samples != 0, "Area lights need at least one sample");
+        Light {
+            color,
+            light_type: LightType::Sphere(center, radius),
+            samples,
         }
     }
 
     pub fn get_falloff_at(&self, point: Point3<f32>) -> f32 {
         match self.light_type {
-            LightType::Point(position) => {
-                let distance_sqrd = (position - point).magnitude2();
-                // TODO: Remove constants here.
-                5.0 / (0.001 + distance_sqrd)
-            }
+            LightType::Point(position) => Light::get_distance_falloff(position, point),
             LightType::Cone(position, direction, angle) => {
                 let cone_falloff = {
                     // TODO: Make this a field.
@@ -70,54 +134,330 @@ impl Light {
                     let angle_delta = angle - angle_to_point;
                     clamp(angle_delta / falloff_range, 0.0, 1.0)
                 };
-                let distance_sqrd = (position - point).magnitude2();
-                // TODO: Remove constants here.
-                cone_falloff * 5.0 / (0.001 + distance_sqrd)
+                cone_falloff * Light::get_distance_falloff(position, point)
             }
             _ => unreachable!(),
         }
     }
 
-    fn in_shadow(
-        point: Point3<f32>,
-        light_position: Point3<f32>,
-        light_direction: Vector3<f32>,
-        bvh: &Bvh,
-    ) -> bool {
-        let light_ray = Ray::new(light_position, light_direction);
-        let light_to_point_t = point.distance(light_position);
-        // TODO: Shadows don't work correctly with reflective or refractive surfaces.
-        if let Some((_, shadow_t)) = bvh.get_closest_intersection(&light_ray) {
-            let epsilon = 1e-4;
-            let is_in_shadow = shadow_t + epsilon < light_to_point_t;
-            !is_in_shadow
-        } else {
-            false
+    fn get_distance_falloff(position: Point3<f32>, point: Point3<f32>) -> f32 {
+        let distance_sqrd = (position - point).magnitude2();
+        // TODO: Remove constants here.
+        5.0 / (0.001 + distance_sqrd)
+    }
+
+    /// Returns the fraction of each color channel of light that travels `distance`
+    /// from `origin` in `direction`.
+    ///
+    /// Transparent objects along the way attenuate and tint the light by their
+    /// color, while opaque objects block it entirely. Shadow rays are not bent by
+    /// refraction.
+    fn get_transmittance(
+        origin: Point3<f32>,
+        direction: Vector3<f32>,
+        distance: f32,
+        accelerator: &dyn Accelerator,
+    ) -> Color {
+        let epsilon = 1e-4;
+        // We move the ray forward slightly so that we don't intersect the starting
+        // object, and stop slightly before the end for the same reason.
+        let shadow_ray = Ray::new(origin, direction).offset(epsilon);
+        let max_t = distance - 2.0 * epsilon;
+        if !accelerator.is_occluded(&shadow_ray, max_t) {
+            return Color::white();
+        } else if !accelerator.has_transparent_objects() {
+            return Color::black();
+        }
+
+        // Walk through the surfaces between the ends in order.
+        let mut transmittance = Color::white();
+        let mut shadow_ray = shadow_ray.with_range(0.0, max_t);
+        for _ in 0..MAX_SHADOW_RAY_SURFACES {
+            match accelerator.get_closest_intersection(&shadow_ray) {
+                Some((object, interaction)) => {
+                    transmittance = transmittance * object.get_transmittance(&interaction);
+                    if transmittance.is_black() {
+                        return transmittance;
+                    }
+                    let remaining_t = shadow_ray.get_t_max() - interaction.t;
+                    shadow_ray = Ray::new(interaction.point, direction)
+                        .with_range(0.0, remaining_t)
+                        .offset(epsilon);
+                }
+                None => return transmittance,
+            }
         }
+        Color::black()
     }
 
-    pub fn reaches_point(&self, point: Point3<f32>, bvh: &Bvh) -> bool {
+    /// Returns a random point on an area light, along with the light's normal at that
+    /// point if the light is flat. For sphere lights, only the half facing `point`
+    /// is sampled.
+    fn sample_position<R: Rng>(
+        &self,
+        point: Point3<f32>,
+        rng: &mut R,
+    ) -> (Point3<f32>, Option<Vector3<f32>>) {
         match self.light_type {
-            LightType::Ambient => true,
-            LightType::Point(light_position) => {
-                let light_direction = point - light_position;
-                Light::in_shadow(point, light_position, light_direction, bvh)
+            LightType::Rectangle(corner, edge_u, edge_v) => {
+                let position = corner + rng.gen::<f32>() * edge_u + rng.gen::<f32>() * edge_v;
+                (position, Some(edge_u.cross(edge_v).normalize()))
             }
-            LightType::Directional(direction) => {
-                // Checks whether a ray starting from the intersection point, going in
-                // the opposite direction of the light, hits another object.
-                let object_to_light = Ray::new(point, -direction);
-                let object_to_light = object_to_light.offset(1e-4);
-                bvh.get_closest_intersection(&object_to_light).is_none()
+            LightType::Disk(center, normal, radius) => {
+                let (u, v) = orthonormal_basis(normal);
+                let (x, y) = sample_disk(radius, rng);
+                let position = center + x * u + y * v;
+                (position, Some(normal))
             }
-            LightType::Cone(light_position, direction, angle) => {
-                let light_direction = point - light_position;
-                if direction.angle(light_direction) > angle.into() {
-                    false
+            LightType::Sphere(center, radius) => {
+                // Uniformly sample a direction, then flip it onto the visible hemisphere.
+                let z = 1.0 - 2.0 * rng.gen::<f32>();
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * PI * rng.gen::<f32>();
+                let direction = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+                let to_point = (point - center).normalize();
+                let direction = if direction.dot(to_point) < 0.0 {
+                    direction - 2.0 * direction.dot(to_point) * to_point
                 } else {
-                    Light::in_shadow(point, light_position, light_direction, bvh)
+                    direction
+                };
+                (center + radius * direction, None)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the light that reaches `point` from a single (random, for area lights)
+    /// point on this light, or `None` if the light is blocked by opaque objects. Ambient
+    /// light has no direction, so it always returns `None`.
+    pub fn sample<R: Rng>(
+        &self,
+        point: Point3<f32>,
+        accelerator: &dyn Accelerator,
+        rng: &mut R,
+    ) -> Option<LightSample> {
+        let (direction, light_position, color) = match self.light_type {
+            LightType::Ambient => return None,
+            LightType::Point(position) => (
+                (position - point).normalize(),
+                Some(position),
+                self.get_falloff_at(point) * self.color,
+            ),
+            LightType::Directional(direction) => (-direction, None, self.color),
+            LightType::Cone(position, direction, angle) => {
+                if direction.angle(point - position) > angle.into() {
+                    return None;
                 }
+                (
+                    (position - point).normalize(),
+                    Some(position),
+                    self.get_falloff_at(point) * self.color,
+                )
+            }
+            LightType::Rectangle(_, _, _) | LightType::Disk(_, _, _) | LightType::Sphere(_, _) => {
+                let (position, normal) = self.sample_position(point, rng);
+                let direction = (position - point).normalize();
+                // A small area light should look like a point light of the same color,
+                // except that flat lights dim when viewed at an angle.
+                let cos_light = match normal {
+                    Some(normal) => -direction.dot(normal),
+                    None => 1.0,
+                };
+                if cos_light <= 0.0 {
+                    return None;
+                }
+                let falloff = cos_light * Light::get_distance_falloff(position, point);
+                (direction, Some(position), falloff * self.color)
+            }
+        };
+        let distance = match light_position {
+            Some(position) => point.distance(position),
+            None => f32::INFINITY,
+        };
+        let transmittance = Light::get_transmittance(point, direction, distance, accelerator);
+        if transmittance.is_black() {
+            None
+        } else {
+            Some(LightSample {
+                direction,
+                color: transmittance * color,
+            })
+        }
+    }
+
+    /// Samples this light `self.samples` times from `point` and returns the samples
+    /// that reach it, each weighted by `1 / self.samples`. Summing the returned
+    /// samples estimates the light reaching `point`, including soft shadows.
+    pub fn get_samples<R: Rng>(
+        &self,
+        point: Point3<f32>,
+        accelerator: &dyn Accelerator,
+        rng: &mut R,
+    ) -> Vec<LightSample> {
+        let weight = 1.0 / f32::from(self.samples);
+        (0..self.samples)
+            .filter_map(|_| self.sample(point, accelerator, rng))
+            .map(|sample| LightSample {
+                direction: sample.direction,
+                color: weight * sample.color,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Light;
+    use crate::bvh::Bvh;
+    use crate::color::Color;
+    use crate::material::{Material, MaterialType, TextureType};
+    use crate::object::Object;
+    use cgmath::InnerSpace;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn ground(material: Material) -> Object {
+        Object::new_quad(
+            (-10.0, 0.0, 10.0).into(),
+            (10.0, 0.0, 10.0).into(),
+            (10.0, 0.0, -10.0).into(),
+            (-10.0, 0.0, -10.0).into(),
+            material,
+        )
+    }
+
+    #[test]
+    fn test_area_light_soft_shadow() {
+        let m = Material::new(MaterialType::None, TextureType::None);
+        // A square blocker between a smaller square light and the ground.
+        let blocker = Object::new_quad(
+            (-0.25, 1.0, 0.25).into(),
+            (0.25, 1.0, 0.25).into(),
+            (0.25, 1.0, -0.25).into(),
+            (-0.25, 1.0, -0.25).into(),
+            m.clone(),
+        );
+        let bvh = Bvh::new(vec![blocker, ground(m)]);
+        let light = Light::new_rectangle(
+            (-0.2, 2.0, -0.2).into(),
+            (0.4, 0.0, 0.0).into(),
+            (0.0, 0.0, 0.4).into(),
+            Color::white(),
+            256,
+        );
+        let mut rng = StdRng::seed_from_u64(248);
+        let mut visibility = |x: f32| {
+            let samples = light.get_samples((x, 0.0, 0.0).into(), &bvh, &mut rng);
+            samples.len() as f32 / f32::from(light.samples)
+        };
+        // Umbra, penumbra, and fully lit.
+        assert_eq!(visibility(0.0), 0.0);
+        let penumbra = visibility(0.6);
+        assert!(0.0 < penumbra && penumbra < 1.0);
+        assert_eq!(visibility(3.0), 1.0);
+
+        // The light only shines downwards.
+        assert!(light
+            .get_samples((0.0, 3.0, 0.0).into(), &bvh, &mut rng)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_area_lights_match_point_light() {
+        let m = Material::new(MaterialType::None, TextureType::None);
+        let bvh = Bvh::new(vec![ground(m)]);
+        let mut rng = StdRng::seed_from_u64(248);
+        let point = (0.0, 0.0, 0.0).into();
+        let point_light = Light::new_point((0.0, 10.0, 0.0).into(), Color::white());
+        let expected = point_light.sample(point, &bvh, &mut rng).unwrap();
+        let lights = vec![
+            Light::new_disk(
+                (0.0, 10.0, 0.0).into(),
+                (0.0, -1.0, 0.0).into(),
+                0.01,
+                Color::white(),
+                4,
+            ),
+            Light::new_sphere((0.0, 10.0, 0.0).into(), 0.01, Color::white(), 4),
+        ];
+        for light in lights {
+            let sample = light.sample(point, &bvh, &mut rng).unwrap();
+            assert!((sample.direction - expected.direction).magnitude() < 1e-2);
+            let (r, _, _) = sample.color.get_rgb();
+            let (expected_r, _, _) = expected.color.get_rgb();
+            assert!((i32::from(r) - i32::from(expected_r)).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_culled_blocker() {
+        let m = Material::new(MaterialType::None, TextureType::None);
+        // A blocker facing the lights above, so that the ground only sees its back.
+        let blocker = Object::new_quad(
+            (-1.0, 1.0, 1.0).into(),
+            (1.0, 1.0, 1.0).into(),
+            (1.0, 1.0, -1.0).into(),
+            (-1.0, 1.0, -1.0).into(),
+            m.clone(),
+        );
+        let lights = [
+            Light::new_point((0.0, 10.0, 0.0).into(), Color::white()),
+            Light::new_directional((0.0, -1.0, 0.0).into(), Color::white()),
+        ];
+        let mut rng = StdRng::seed_from_u64(248);
+        let point = (0.0, 0.0, 0.0).into();
+        // Shadow rays start at the point for every kind of light, so a culled back face
+        // never casts a shadow.
+        for &two_sided in &[true, false] {
+            let bvh = Bvh::new(vec![
+                blocker.clone().two_sided(two_sided),
+                ground(m.clone()),
+            ]);
+            for light in &lights {
+                assert_eq!(light.sample(point, &bvh, &mut rng).is_none(), two_sided);
             }
         }
     }
+
+    #[test]
+    fn test_shadow_through_transparent_surfaces() {
+        let m = Material::new(MaterialType::None, TextureType::None);
+        let pane = |material_type: MaterialType| {
+            Object::new_quad(
+                (-1.0, 1.0, 1.0).into(),
+                (1.0, 1.0, 1.0).into(),
+                (1.0, 1.0, -1.0).into(),
+                (-1.0, 1.0, -1.0).into(),
+                Material::new(material_type, TextureType::Flat(Color::rgb(1.0, 0.5, 0.0))),
+            )
+        };
+        let light = Light::new_point((0.0, 5.0, 0.0).into(), Color::white());
+        let mut rng = StdRng::seed_from_u64(248);
+        let point = (0.0, 0.0, 0.0).into();
+        let unshadowed = light.get_falloff_at(point);
+
+        // Glass tints the light by its color.
+        let bvh = Bvh::new(vec![pane(MaterialType::Refractive(1.5)), ground(m.clone())]);
+        let sample = light.sample(point, &bvh, &mut rng).unwrap();
+        assert_eq!(
+            sample.color.get_rgb(),
+            (unshadowed * Color::rgb(1.0, 0.5, 0.0)).get_rgb()
+        );
+
+        // A partially refractive surface only lets part of the light through.
+        let half_glass = MaterialType::Composition(vec![
+            (MaterialType::Refractive(1.5), 0.5),
+            (MaterialType::Reflective, 0.5),
+        ]);
+        let bvh = Bvh::new(vec![pane(half_glass), ground(m.clone())]);
+        let sample = light.sample(point, &bvh, &mut rng).unwrap();
+        assert_eq!(
+            sample.color.get_rgb(),
+            (unshadowed * Color::rgb(0.5, 0.25, 0.0)).get_rgb()
+        );
+
+        // Opaque surfaces block the light entirely.
+        let bvh = Bvh::new(vec![pane(MaterialType::Reflective), ground(m)]);
+        assert!(light.sample(point, &bvh, &mut rng).is_none());
+    }
 }
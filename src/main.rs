@@ -1,21 +1,16 @@
 #[macro_use]
 extern crate log;
 
-mod bvh;
-mod camera;
-mod color;
-mod light;
-mod material;
-mod object;
-mod ray;
-mod scene;
-mod utils;
-mod world;
-
-use color::Color;
-use scene::*;
-use world::render;
+use rust_raytracer::accelerator::AcceleratorType;
+use rust_raytracer::bvh::BvhBuildOptions;
+use rust_raytracer::camera::Projection;
+use rust_raytracer::color::Color;
+use rust_raytracer::scene::{default_camera, load_builtin};
+use rust_raytracer::scene_file::{check_fov, check_lens, load_scene_file};
+use rust_raytracer::tone_mapping::DisplayTransform;
+use rust_raytracer::world::{Integrator, Renderer};
 
+use cgmath::Deg;
 use clap::{App, Arg};
 
 fn main() {
@@ -31,11 +26,26 @@ fn main() {
                 .short("f")
                 .long("file")
                 .value_name("FILE")
-                .help("File to save rendered image as .png")
+                .help(
+                    "File to save the rendered image to. \
+                     .exr and .hdr files keep the full high dynamic range image.",
+                )
                 .required(false)
                 .default_value("out.png")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("scene")
+                .long("scene")
+                .value_name("FILE")
+                .help(
+                    "Scene file (.toml) to render, or the name of a built-in scene: \
+                     basic, suzanne, or random_spheres.",
+                )
+                .required(false)
+                .default_value("random_spheres")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("threads")
                 .short("t")
@@ -44,6 +54,90 @@ fn main() {
                 .required(false)
                 .default_value("4"),
         )
+        .arg(
+            Arg::with_name("bvh_cache")
+                .long("bvh_cache")
+                .value_name("DIR")
+                .help(
+                    "Directory to save built bvhs in. Later renders of the same geometry load \
+                     the bvh from there instead of building it again.",
+                )
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("accelerator")
+                .long("accelerator")
+                .help(
+                    "How to find the objects that rays hit. \
+                     bvh: a bounding volume hierarchy. \
+                     grid: a uniform grid. \
+                     kdtree: a kd-tree built with the surface area heuristic. \
+                     Meshes always use their own bvh.",
+                )
+                .required(false)
+                .possible_values(&["bvh", "grid", "kdtree"])
+                .default_value("bvh"),
+        )
+        .arg(
+            Arg::with_name("bvh_split")
+                .long("bvh_split")
+                .help(
+                    "How to split the nodes of bvhs. \
+                     naive: in half, in the order of the objects. \
+                     x_axis: in half, sorted by x coordinate. \
+                     basic: at the midpoint of the centroids. \
+                     sah: where the surface area heuristic estimates rays are cheapest. \
+                     spatial: sah, also splitting long thin objects between both children.",
+                )
+                .required(false)
+                .possible_values(&["naive", "x_axis", "basic", "sah", "spatial"])
+                .default_value("sah"),
+        )
+        .arg(
+            Arg::with_name("bvh_leaf_size")
+                .long("bvh_leaf_size")
+                .help("Maximum number of objects in a leaf of a bvh")
+                .required(false)
+                .default_value("4"),
+        )
+        .arg(
+            Arg::with_name("bvh_buckets")
+                .long("bvh_buckets")
+                .help("Number of buckets that sah splits choose between")
+                .required(false)
+                .default_value("12"),
+        )
+        .arg(
+            Arg::with_name("bvh_traversal_cost")
+                .long("bvh_traversal_cost")
+                .help("Cost of visiting a bvh node relative to intersecting an object")
+                .required(false)
+                .default_value("0.125"),
+        )
+        .arg(
+            Arg::with_name("bvh_width")
+                .long("bvh_width")
+                .help(
+                    "Maximum number of children of the bvh nodes that rays traverse. \
+                     Bvhs are built as binary trees and collapsed to this width.",
+                )
+                .required(false)
+                .possible_values(&["2", "4", "8"])
+                .default_value("2"),
+        )
+        .arg(
+            Arg::with_name("bvh_stats")
+                .long("bvh_stats")
+                .value_name("FORMAT")
+                .help(
+                    "Print statistics about the bvhs of the scene after rendering, \
+                     as text or json.",
+                )
+                .required(false)
+                .possible_values(&["text", "json"])
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("samples_per_pixel")
                 .short("s")
@@ -63,34 +157,109 @@ fn main() {
                 .required(false)
                 .default_value("4"),
         )
+        .arg(
+            Arg::with_name("integrator")
+                .short("i")
+                .long("integrator")
+                .help(
+                    "Algorithm used to compute colors. \
+                     whitted: Phong shading with mirror reflection and refraction. \
+                     path: Monte Carlo path tracing with global illumination.",
+                )
+                .required(false)
+                .possible_values(&["whitted", "path"])
+                .default_value("whitted"),
+        )
+        .arg(
+            Arg::with_name("exposure")
+                .long("exposure")
+                .help("Exposure adjustment in stops for .png and other 8-bit images")
+                .required(false)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("tone_mapping")
+                .long("tone_mapping")
+                .help(
+                    "How to fit bright colors into .png and other 8-bit images. \
+                     clamp: clip colors brighter than white. \
+                     reinhard: the Reinhard operator c / (1 + c). \
+                     aces: an approximation of the ACES filmic curve.",
+                )
+                .required(false)
+                .possible_values(&["clamp", "reinhard", "aces"])
+                .default_value("clamp"),
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .long("encoding")
+                .help("Transfer function used to encode .png and other 8-bit images")
+                .required(false)
+                .possible_values(&["srgb", "linear"])
+                .default_value("srgb"),
+        )
         .arg(
             Arg::with_name("pixel_width")
                 .short("w")
                 .long("pixel_width")
+                .help("Width of the outputted image in pixels.")
+                .required(false)
+                .default_value("500"),
+        )
+        .arg(
+            Arg::with_name("pixel_height")
+                .long("height")
+                .help("Height of the outputted image in pixels. Defaults to the width.")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fov")
+                .long("fov")
                 .help(
-                    "Width of the outputted image in pixels. \
-                     Specifically, changes the width/height of the camera viewport.",
+                    "Vertical field of view of the camera in degrees. \
+                     Overrides the scene's camera.",
                 )
                 .required(false)
-                .default_value("500"),
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("projection")
+                .long("projection")
+                .help(
+                    "How the camera maps directions onto the image. \
+                     Overrides the scene's camera. \
+                     orthographic: parallel rays covering the perspective view at the focus distance. \
+                     equirectangular: a 360 degree panorama. \
+                     fisheye: an equidistant fisheye lens covering --fov vertically.",
+                )
+                .required(false)
+                .possible_values(&["perspective", "orthographic", "equirectangular", "fisheye"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("aperture")
+                .long("aperture")
+                .help(
+                    "Radius of the camera lens. \
+                     Objects away from the focus distance are blurred. \
+                     Overrides the scene's camera; 0 gives a pinhole camera.",
+                )
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("focus_distance")
+                .long("focus_distance")
+                .help(
+                    "Distance from the camera to the plane in focus. \
+                     Overrides the scene's camera.",
+                )
+                .required(false)
+                .takes_value(true),
         )
         .get_matches();
 
-    let mut objects = vec![];
-    let mut lights = vec![];
-
-    // let (new_objects, new_lights) = load_basic();
-    // objects.extend(new_objects);
-    // lights.extend(new_lights);
-
-    // let (new_objects, new_lights) = load_suzanne();
-    // objects.extend(new_objects);
-    // lights.extend(new_lights);
-
-    let (new_objects, new_lights) = load_random_spheres(1000);
-    objects.extend(new_objects);
-    lights.extend(new_lights);
-
     let samples_per_pixel = cl_args
         .value_of("samples_per_pixel")
         .unwrap()
@@ -103,16 +272,99 @@ fn main() {
         .unwrap();
     let num_threads = cl_args.value_of("threads").unwrap().parse().unwrap();
     let pixel_width = cl_args.value_of("pixel_width").unwrap().parse().unwrap();
+    let pixel_height = cl_args
+        .value_of("pixel_height")
+        .map_or(pixel_width, |height| height.parse().unwrap());
+    let integrator: Integrator = cl_args.value_of("integrator").unwrap().parse().unwrap();
+    let accelerator: AcceleratorType = cl_args.value_of("accelerator").unwrap().parse().unwrap();
+    let bvh_build_options = BvhBuildOptions {
+        split_type: cl_args.value_of("bvh_split").unwrap().parse().unwrap(),
+        max_leaf_size: cl_args.value_of("bvh_leaf_size").unwrap().parse().unwrap(),
+        num_buckets: cl_args.value_of("bvh_buckets").unwrap().parse().unwrap(),
+        traversal_cost: cl_args
+            .value_of("bvh_traversal_cost")
+            .unwrap()
+            .parse()
+            .unwrap(),
+        width: cl_args.value_of("bvh_width").unwrap().parse().unwrap(),
+    };
 
-    render(
-        default_camera(pixel_width),
-        objects,
-        lights,
-        Color::grayscale(0.2),
-        samples_per_pixel,
-        max_ray_bounces,
-        cl_args.value_of("file").unwrap(),
-        num_threads,
-    )
-    .unwrap();
+    let scene = cl_args.value_of("scene").unwrap();
+    let (mut camera, objects, lights, background_color) = match load_builtin(scene) {
+        Some((objects, lights)) => (
+            default_camera(pixel_width, pixel_height),
+            objects,
+            lights,
+            Color::grayscale(0.2),
+        ),
+        None => match load_scene_file(scene, pixel_width, pixel_height) {
+            Ok(scene) => (
+                scene.camera,
+                scene.objects,
+                scene.lights,
+                scene.background_color,
+            ),
+            Err(err) => {
+                error!("Unable to load scene {}: {}", scene, err);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    if let Some(fov) = cl_args.value_of("fov") {
+        camera.vertical_fov = Deg(fov.parse().unwrap());
+    }
+    if let Some(aperture) = cl_args.value_of("aperture") {
+        camera.aperture = aperture.parse().unwrap();
+    }
+    if let Some(focus_distance) = cl_args.value_of("focus_distance") {
+        camera.focus_distance = focus_distance.parse().unwrap();
+    }
+    if let Err(err) = check_lens(camera.aperture, camera.focus_distance) {
+        error!("Invalid camera: {}", err);
+        std::process::exit(1);
+    }
+    if let Some(projection) = cl_args.value_of("projection") {
+        camera.projection = match projection {
+            "perspective" => Projection::Perspective,
+            "orthographic" => {
+                Projection::Orthographic(camera.get_view_height_at(camera.focus_distance))
+            }
+            "equirectangular" => Projection::Equirectangular,
+            "fisheye" => Projection::Fisheye,
+            _ => unreachable!(),
+        };
+    }
+    // The fov is checked against the projection that the camera ends up with.
+    let is_fisheye = matches!(camera.projection, Projection::Fisheye);
+    if let Err(err) = check_fov(camera.vertical_fov.0, is_fisheye) {
+        error!("Invalid camera: {}", err);
+        std::process::exit(1);
+    }
+
+    let mut renderer = Renderer::new(camera, objects, lights)
+        .background_color(background_color)
+        .samples_per_pixel(samples_per_pixel)
+        .max_ray_bounces(max_ray_bounces)
+        .num_threads(num_threads)
+        .integrator(integrator)
+        .accelerator(accelerator)
+        .bvh_build_options(bvh_build_options);
+    if let Some(bvh_cache_dir) = cl_args.value_of("bvh_cache") {
+        renderer = renderer.bvh_cache_dir(bvh_cache_dir);
+    }
+    let (framebuffer, bvh_report) = renderer.render_with_bvh_report().unwrap();
+    match cl_args.value_of("bvh_stats") {
+        Some("text") => println!("{}", bvh_report),
+        Some("json") => println!("{}", bvh_report.to_json()),
+        _ => {}
+    }
+    let display_transform = DisplayTransform {
+        exposure: cl_args.value_of("exposure").unwrap().parse().unwrap(),
+        tone_mapping: cl_args.value_of("tone_mapping").unwrap().parse().unwrap(),
+        encoding: cl_args.value_of("encoding").unwrap().parse().unwrap(),
+    };
+    framebuffer
+        .save(cl_args.value_of("file").unwrap(), &display_transform)
+        .unwrap();
 }
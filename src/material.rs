@@ -1,14 +1,15 @@
-use cgmath::{InnerSpace, Point3, Vector3};
-use image;
+use cgmath::{InnerSpace, Point2, Vector3, Zero};
+use rand::Rng;
 use std::error::Error;
+use std::f32::consts::PI;
 use std::path::Path;
 use std::sync::Arc;
 
 use super::color::Color;
+use super::interaction::SurfaceInteraction;
 use super::light::{Light, LightType};
-use super::object::Object;
 use super::ray::Ray;
-use super::utils::{clamp, reflect, refract};
+use super::utils::{clamp, reflect, refract, sample_cosine_hemisphere, sample_cosine_power};
 use super::world::World;
 
 pub enum TextureType {
@@ -36,13 +37,34 @@ pub struct Material {
     texture_type: TextureType,
 }
 
+/// A direction sampled from a material's BSDF.
+pub struct BsdfSample {
+    /// Unit vector that the path continues in.
+    pub direction: Vector3<f32>,
+    /// `bsdf * cos(theta) / pdf` for each color channel, i.e. the factor that the
+    /// throughput of the path is scaled by.
+    pub weight: Vector3<f32>,
+}
+
+/// Returns `normal` flipped, if needed, to face against `incoming_direction`.
+fn face_forward(normal: Vector3<f32>, incoming_direction: Vector3<f32>) -> Vector3<f32> {
+    if incoming_direction.dot(normal) > 0.0 {
+        -normal
+    } else {
+        normal
+    }
+}
+
+/// The albedo of `MaterialType::None`, matching the color it is drawn with.
+const NONE_ALBEDO: f32 = 0.5;
+
 impl TextureType {
     pub fn new_texture<P>(path: P) -> Result<Self, Box<dyn Error>>
     where
         P: AsRef<Path>,
     {
         let image = image::io::Reader::open(path)?.decode()?;
-        let buf = image.to_rgb();
+        let buf = image.to_rgb8();
         Ok(TextureType::Texture(Arc::new(buf)))
     }
 
@@ -50,13 +72,13 @@ impl TextureType {
         TextureType::Flat(color)
     }
 
-    fn sample(&self, object: &Object, intersection_point: Point3<f32>) -> Color {
+    fn sample(&self, uv: Point2<f32>) -> Color {
         match self {
             TextureType::Texture(buf) => {
                 // TODO: Add options for wrapping/clamping and filter type.
                 let width = buf.width() as f32;
                 let height = buf.height() as f32;
-                let (u, v) = object.get_uv(intersection_point).into();
+                let (u, v) = uv.into();
                 // Wrap uv coordinates.
                 let (u, v) = (u.rem_euclid(1.0), v.rem_euclid(1.0));
                 let x = (u * width).trunc();
@@ -112,15 +134,203 @@ impl MaterialType {
         diffuse * diffuse_intensity + specular * specular_intensity.powf(shininess)
     }
 
-    /// Returns the color of `object` at the point given by `incoming_ray.get_point_on_ray(t)`.
+    /// The energy normalized Phong BSDF, where `cos_alpha` is the cosine of the angle
+    /// between the direction to the light and the perfect reflection direction.
+    fn get_phong_bsdf(diffuse: f32, specular: f32, shininess: f32, cos_alpha: f32) -> f32 {
+        diffuse / PI
+            + specular * (shininess + 2.0) / (2.0 * PI) * cos_alpha.max(0.0).powf(shininess)
+    }
+
+    /// The pdf of the directions returned by `sample_bsdf` for Phong materials.
+    fn get_phong_pdf(
+        diffuse: f32,
+        specular: f32,
+        shininess: f32,
+        cos_theta: f32,
+        cos_alpha: f32,
+    ) -> f32 {
+        let diffuse_probability = diffuse / (diffuse + specular);
+        diffuse_probability * cos_theta / PI
+            + (1.0 - diffuse_probability) * (shininess + 1.0) / (2.0 * PI)
+                * cos_alpha.max(0.0).powf(shininess)
+    }
+
+    /// Returns `bsdf * cos(theta)` for light arriving from `dir_to_light` that leaves
+    /// in the direction opposite to `incoming_direction`.
+    ///
+    /// Perfectly specular materials always return zero since light can only reach
+    /// them from a single direction.
+    fn eval_bsdf(
+        &self,
+        surface_color: Vector3<f32>,
+        incoming_direction: Vector3<f32>,
+        normal: Vector3<f32>,
+        dir_to_light: Vector3<f32>,
+    ) -> Vector3<f32> {
+        let normal = face_forward(normal, incoming_direction);
+        let cos_theta = dir_to_light.dot(normal);
+        match self {
+            MaterialType::Composition(materials) => materials
+                .iter()
+                .map(|(material, coefficient)| {
+                    *coefficient
+                        * material.eval_bsdf(
+                            surface_color,
+                            incoming_direction,
+                            normal,
+                            dir_to_light,
+                        )
+                })
+                .fold(Vector3::zero(), |acc, x| acc + x),
+            MaterialType::Phong {
+                diffuse,
+                specular,
+                shininess,
+            } => {
+                if cos_theta <= 0.0 {
+                    Vector3::zero()
+                } else {
+                    let reflection_direction = reflect(incoming_direction, normal);
+                    let cos_alpha = dir_to_light.dot(reflection_direction);
+                    let bsdf =
+                        MaterialType::get_phong_bsdf(*diffuse, *specular, *shininess, cos_alpha);
+                    surface_color * (bsdf * cos_theta)
+                }
+            }
+            MaterialType::Reflective | MaterialType::Refractive(_) => Vector3::zero(),
+            MaterialType::None => {
+                let cos_theta = cos_theta.max(0.0);
+                Vector3::new(NONE_ALBEDO, NONE_ALBEDO, NONE_ALBEDO) * (cos_theta / PI)
+            }
+        }
+    }
+
+    /// Samples the direction that a path arriving along `incoming_direction` continues
+    /// in. Returns `None` if the path is absorbed.
+    ///
+    /// `normal` points to the outside of the surface, which tells refraction whether
+    /// the path is entering or leaving an object.
+    fn sample_bsdf<R: Rng>(
+        &self,
+        surface_color: Vector3<f32>,
+        incoming_direction: Vector3<f32>,
+        normal: Vector3<f32>,
+        rng: &mut R,
+    ) -> Option<BsdfSample> {
+        match self {
+            MaterialType::Composition(materials) => {
+                // Pick a single material with probability proportional to its coefficient.
+                let total: f32 = materials.iter().map(|(_, coefficient)| coefficient).sum();
+                if total <= 0.0 {
+                    return None;
+                }
+                let mut x = rng.gen::<f32>() * total;
+                let (material, _) = materials
+                    .iter()
+                    .find(|(_, coefficient)| {
+                        x -= coefficient;
+                        x < 0.0
+                    })
+                    .unwrap_or_else(|| materials.last().unwrap());
+                material
+                    .sample_bsdf(surface_color, incoming_direction, normal, rng)
+                    .map(|sample| BsdfSample {
+                        direction: sample.direction,
+                        weight: total * sample.weight,
+                    })
+            }
+            MaterialType::Phong {
+                diffuse,
+                specular,
+                shininess,
+            } => {
+                if diffuse + specular <= 0.0 {
+                    return None;
+                }
+                let normal = face_forward(normal, incoming_direction);
+                let reflection_direction = reflect(incoming_direction, normal);
+                let direction = if rng.gen::<f32>() * (diffuse + specular) < *diffuse {
+                    sample_cosine_hemisphere(normal, rng)
+                } else {
+                    sample_cosine_power(reflection_direction, *shininess, rng)
+                };
+                let cos_theta = direction.dot(normal);
+                if cos_theta <= 0.0 {
+                    return None;
+                }
+                let cos_alpha = direction.dot(reflection_direction);
+                let bsdf = MaterialType::get_phong_bsdf(*diffuse, *specular, *shininess, cos_alpha);
+                let pdf = MaterialType::get_phong_pdf(
+                    *diffuse, *specular, *shininess, cos_theta, cos_alpha,
+                );
+                Some(BsdfSample {
+                    direction,
+                    weight: surface_color * (bsdf * cos_theta / pdf),
+                })
+            }
+            MaterialType::Reflective => Some(BsdfSample {
+                direction: reflect(incoming_direction, normal),
+                weight: Vector3::new(1.0, 1.0, 1.0),
+            }),
+            MaterialType::Refractive(refraction_index) => {
+                let direction = refract(incoming_direction, normal, *refraction_index);
+                // Snell's law has no solution under total internal reflection.
+                let direction = if direction.x.is_nan() {
+                    reflect(incoming_direction, normal)
+                } else {
+                    direction
+                };
+                Some(BsdfSample {
+                    direction,
+                    weight: Vector3::new(1.0, 1.0, 1.0),
+                })
+            }
+            MaterialType::None => {
+                let normal = face_forward(normal, incoming_direction);
+                Some(BsdfSample {
+                    direction: sample_cosine_hemisphere(normal, rng),
+                    weight: Vector3::new(NONE_ALBEDO, NONE_ALBEDO, NONE_ALBEDO),
+                })
+            }
+        }
+    }
+
+    /// Returns the fraction of each color channel of light that passes straight
+    /// through a surface of this material.
+    fn get_transmittance(&self, surface_color: Color) -> Color {
+        match self {
+            MaterialType::Composition(materials) => materials
+                .iter()
+                .map(|(material, coefficient)| {
+                    *coefficient * material.get_transmittance(surface_color)
+                })
+                .fold(Color::black(), |acc, x| acc + x),
+            MaterialType::Refractive(_) => surface_color,
+            MaterialType::Phong { .. } | MaterialType::Reflective | MaterialType::None => {
+                Color::black()
+            }
+        }
+    }
+
+    /// Returns true if no light passes through a surface of this material.
+    fn is_opaque(&self) -> bool {
+        match self {
+            MaterialType::Composition(materials) => materials
+                .iter()
+                .all(|(material, coefficient)| *coefficient == 0.0 || material.is_opaque()),
+            MaterialType::Refractive(_) => false,
+            MaterialType::Phong { .. } | MaterialType::Reflective | MaterialType::None => true,
+        }
+    }
+
+    /// Returns the color of the surface where `incoming_ray` hits it at `interaction`.
     ///
     /// All arguments are in world space coordinates.
     pub fn get_color(
         &self,
         surface_color: Color,
         incoming_ray: &Ray,
-        t: f32,
-        object: &Object,
+        interaction: &SurfaceInteraction,
         lights: Vec<&Light>,
         world: &World,
         max_depth: u16,
@@ -133,8 +343,7 @@ impl MaterialType {
                         * material.get_color(
                             surface_color,
                             incoming_ray,
-                            t,
-                            object,
+                            interaction,
                             lights.clone(),
                             world,
                             max_depth,
@@ -146,45 +355,37 @@ impl MaterialType {
                 specular,
                 shininess,
             } => {
-                let intersection_point = incoming_ray.get_point_on_ray(t).into();
-                let normal = object.get_normal(intersection_point);
+                let intersection_point = interaction.point;
+                let normal = interaction.shading_normal;
+                let mut rng = rand::thread_rng();
                 lights
                     .iter()
                     .map(|light| {
                         let light_color = match light.light_type {
                             LightType::Ambient => light.color,
-                            LightType::Point(position) | LightType::Cone(position, _, _) => {
-                                let light_dir = position - intersection_point;
-                                let falloff = light.get_falloff_at(intersection_point);
-                                let phong_multiple = MaterialType::get_phong_multiple(
-                                    light_dir.normalize(),
-                                    normal,
-                                    incoming_ray.get_direction(),
-                                    *diffuse,
-                                    *specular,
-                                    *shininess,
-                                );
-                                phong_multiple * (falloff * light.color)
-                            }
-                            LightType::Directional(direction) => {
-                                let phong_multiple = MaterialType::get_phong_multiple(
-                                    -direction,
-                                    normal,
-                                    incoming_ray.get_direction(),
-                                    *diffuse,
-                                    *specular,
-                                    *shininess,
-                                );
-                                phong_multiple * light.color
-                            }
+                            _ => light
+                                .get_samples(intersection_point, world.get_accelerator(), &mut rng)
+                                .iter()
+                                .map(|sample| {
+                                    let phong_multiple = MaterialType::get_phong_multiple(
+                                        sample.direction,
+                                        normal,
+                                        incoming_ray.get_direction(),
+                                        *diffuse,
+                                        *specular,
+                                        *shininess,
+                                    );
+                                    phong_multiple * sample.color
+                                })
+                                .fold((0.0, 0.0, 0.0, 0.0).into(), |acc, x| acc + x),
                         };
                         surface_color * light_color
                     })
                     .fold((0.0, 0.0, 0.0, 0.0).into(), |acc, x| acc + x)
             }
             MaterialType::Reflective => {
-                let intersection_point = incoming_ray.get_point_on_ray(t).into();
-                let normal = object.get_normal(intersection_point);
+                let intersection_point = interaction.point;
+                let normal = interaction.shading_normal;
                 let reflection_direction = reflect(incoming_ray.get_direction(), normal);
                 let reflected_ray = Ray::new(intersection_point, reflection_direction);
                 // We move the ray forward slightly so that we don't intersect the same location.
@@ -192,8 +393,8 @@ impl MaterialType {
                 world.trace_ray(&reflected_ray, max_depth)
             }
             MaterialType::Refractive(refraction_index) => {
-                let intersection_point = incoming_ray.get_point_on_ray(t).into();
-                let normal = object.get_normal(intersection_point);
+                let intersection_point = interaction.point;
+                let normal = interaction.get_outward_normal();
                 let refraction_direction =
                     refract(incoming_ray.get_direction(), normal, *refraction_index);
                 let refracted_ray = Ray::new(intersection_point, refraction_direction);
@@ -214,28 +415,78 @@ impl Material {
         }
     }
 
-    /// Returns the color of `object` at the point given by `incoming_ray.get_point_on_ray(t)`.
+    /// Returns the color of this material where `incoming_ray` hits it at
+    /// `interaction`.
     ///
     /// All arguments are in world space coordinates.
     pub fn get_color(
         &self,
         incoming_ray: &Ray,
-        t: f32,
-        object: &Object,
+        interaction: &SurfaceInteraction,
         lights: Vec<&Light>,
         world: &World,
         max_depth: u16,
     ) -> Color {
-        let intersection_point = incoming_ray.get_point_on_ray(t).into();
-        let surface_color = self.texture_type.sample(object, intersection_point);
+        let surface_color = self.texture_type.sample(interaction.uv);
         self.material_type.get_color(
             surface_color,
-            &incoming_ray,
-            t,
-            object,
+            incoming_ray,
+            interaction,
             lights,
             world,
             max_depth,
         )
     }
+
+    /// Returns `bsdf * cos(theta)` where `incoming_ray` hits this material at
+    /// `interaction`, for light arriving from `dir_to_light`.
+    ///
+    /// All arguments are in world space coordinates.
+    pub fn eval_bsdf(
+        &self,
+        incoming_ray: &Ray,
+        interaction: &SurfaceInteraction,
+        dir_to_light: Vector3<f32>,
+    ) -> Vector3<f32> {
+        let surface_color = self.texture_type.sample(interaction.uv);
+        self.material_type.eval_bsdf(
+            surface_color.to_vec().truncate(),
+            incoming_ray.get_direction(),
+            interaction.shading_normal,
+            dir_to_light,
+        )
+    }
+
+    /// Samples the direction that `incoming_ray` continues in after hitting this
+    /// material at `interaction`. Returns `None` if the ray is absorbed.
+    ///
+    /// All arguments are in world space coordinates.
+    pub fn sample_bsdf<R: Rng>(
+        &self,
+        incoming_ray: &Ray,
+        interaction: &SurfaceInteraction,
+        rng: &mut R,
+    ) -> Option<BsdfSample> {
+        let surface_color = self.texture_type.sample(interaction.uv);
+        // Refraction needs to know which side of the surface is outside, and the other
+        // materials flip the normal to face the ray themselves.
+        self.material_type.sample_bsdf(
+            surface_color.to_vec().truncate(),
+            incoming_ray.get_direction(),
+            interaction.get_outward_normal(),
+            rng,
+        )
+    }
+
+    /// Returns true if no light passes through this material anywhere.
+    pub fn is_opaque(&self) -> bool {
+        self.material_type.is_opaque()
+    }
+
+    /// Returns the fraction of each color channel of light that passes straight
+    /// through this material at `interaction`. Opaque materials return black.
+    pub fn get_transmittance(&self, interaction: &SurfaceInteraction) -> Color {
+        let surface_color = self.texture_type.sample(interaction.uv);
+        self.material_type.get_transmittance(surface_color)
+    }
 }
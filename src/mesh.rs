@@ -0,0 +1,500 @@
+use cgmath::{InnerSpace, Point2, Point3, Vector3};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::Hasher;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use super::bvh::{build_or_load, BvhBuildOptions, BvhStats, LinearBvh};
+use super::object::{Hit, ObjectType};
+use super::packet::{self, RayPacket, PACKET_SIZE};
+use super::ray::Ray;
+use super::utils::{component_wise_range, Fnv1aHasher};
+
+/// A face of a mesh, given by the indices of its vertices. When back faces are culled,
+/// see `Object::two_sided`, faces are only hit from the side where the vertices are in
+/// counterclockwise order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Face {
+    Triangle([u32; 3]),
+    /// Hit as the triangles `a, b, c` and `a, c, d`.
+    Quad([u32; 4]),
+}
+
+impl Face {
+    fn get_vertex_indices(&self) -> &[u32] {
+        match self {
+            Face::Triangle(indices) => indices,
+            Face::Quad(indices) => indices,
+        }
+    }
+}
+
+/// Geometry that any number of objects can share. See `Object::new_instance`.
+///
+/// The faces index into a single list of vertex positions and have their own bvh,
+/// so each instance of a mesh only adds a transform and a material.
+pub struct Mesh {
+    positions: Vec<Point3<f32>>,
+    /// One normal for each vertex, or none to shade faces with their own normals.
+    normals: Vec<Vector3<f32>>,
+    /// One uv coordinate for each vertex, or none to use the default ones of triangles
+    /// and quads.
+    uvs: Vec<Point2<f32>>,
+    faces: Vec<Face>,
+    bounding_box: (Point3<f32>, Point3<f32>),
+    geometry_hash: u64,
+    /// Built along with the bvh of the first scene that uses this mesh, or with the
+    /// default options on first use.
+    bvh: OnceLock<MeshBvh>,
+}
+
+struct MeshBvh {
+    tree: LinearBvh,
+    stats: BvhStats,
+}
+
+impl Mesh {
+    /// Creates a mesh from a list of vertex positions and the faces between them.
+    ///
+    /// Panics if a face refers to a vertex that does not exist.
+    pub fn new(positions: Vec<Point3<f32>>, faces: Vec<Face>) -> Self {
+        assert!(
+            faces
+                .iter()
+                .flat_map(Face::get_vertex_indices)
+                .all(|&index| (index as usize) < positions.len()),
+            "A face of the mesh refers to a vertex that does not exist."
+        );
+
+        let mut hasher = Fnv1aHasher::default();
+        hasher.write_usize(positions.len());
+        for position in &positions {
+            let coordinates: &[f32; 3] = position.as_ref();
+            for x in coordinates {
+                hasher.write_u32(x.to_bits());
+            }
+        }
+        hasher.write_usize(faces.len());
+        for face in &faces {
+            let indices = face.get_vertex_indices();
+            hasher.write_usize(indices.len());
+            for &index in indices {
+                hasher.write_u32(index);
+            }
+        }
+
+        let bounding_box = if positions.is_empty() {
+            (Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 0.0, 0.0))
+        } else {
+            component_wise_range(&positions)
+        };
+        Mesh {
+            positions,
+            normals: vec![],
+            uvs: vec![],
+            faces,
+            bounding_box,
+            geometry_hash: hasher.finish(),
+            bvh: OnceLock::new(),
+        }
+    }
+
+    /// Gives each vertex of this mesh a normal, which faces interpolate between their
+    /// vertices to look smooth.
+    ///
+    /// Panics if there is not one normal for each vertex.
+    pub fn with_normals(mut self, normals: Vec<Vector3<f32>>) -> Self {
+        assert_eq!(
+            normals.len(),
+            self.positions.len(),
+            "A mesh needs one normal for each vertex."
+        );
+        self.normals = normals;
+        self
+    }
+
+    /// Gives each vertex of this mesh uv texture coordinates, which faces interpolate
+    /// between their vertices.
+    ///
+    /// Panics if there are not uv coordinates for each vertex.
+    pub fn with_uvs(mut self, uvs: Vec<Point2<f32>>) -> Self {
+        assert_eq!(
+            uvs.len(),
+            self.positions.len(),
+            "A mesh needs uv coordinates for each vertex."
+        );
+        self.uvs = uvs;
+        self
+    }
+
+    /// Loads a mesh from a .obj file. Only triangles and quads are supported.
+    ///
+    /// Normals and texture coordinates are loaded if every vertex of every face has
+    /// them. A .obj file indexes them separately from positions, so a position is
+    /// split into several vertices if the faces around it use different normals or
+    /// texture coordinates for it.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let obj = obj::Obj::<obj::SimplePolygon>::load(path)?;
+        let polys: Vec<&obj::SimplePolygon> = obj
+            .objects
+            .iter()
+            .inspect(|object| debug!("Reading {} from {}", object.name, path.display()))
+            .flat_map(|object| &object.groups)
+            .flat_map(|group| &group.polys)
+            .collect();
+        let corners = || polys.iter().flat_map(|poly| poly.iter());
+        let has_uvs = corners().all(|tuple| tuple.1.is_some());
+        let has_normals = corners().all(|tuple| tuple.2.is_some());
+
+        let mut positions = vec![];
+        let mut normals = vec![];
+        let mut uvs = vec![];
+        let mut vertices = HashMap::new();
+        let mut faces = vec![];
+        for poly in polys {
+            let mut vertex_indices = vec![];
+            for tuple in poly.iter() {
+                let uv_index = if has_uvs { tuple.1 } else { None };
+                let normal_index = if has_normals { tuple.2 } else { None };
+                let index = *vertices
+                    .entry((tuple.0, uv_index, normal_index))
+                    .or_insert_with(|| {
+                        positions.push(obj.position[tuple.0].into());
+                        if let Some(uv_index) = uv_index {
+                            let [u, v] = obj.texture[uv_index];
+                            uvs.push(Point2::new(u, v));
+                        }
+                        if let Some(normal_index) = normal_index {
+                            normals.push(obj.normal[normal_index].into());
+                        }
+                        positions.len() as u32 - 1
+                    });
+                vertex_indices.push(index);
+            }
+            let face = match vertex_indices[..] {
+                [a, b, c] => Face::Triangle([a, b, c]),
+                [a, b, c, d] => Face::Quad([a, b, c, d]),
+                _ => {
+                    return Err(format!(
+                        "Mesh has a polygon with {} vertices which is not supported.",
+                        vertex_indices.len()
+                    )
+                    .into())
+                }
+            };
+            faces.push(face);
+        }
+        let mut mesh = Mesh::new(positions, faces);
+        if has_normals {
+            mesh = mesh.with_normals(normals);
+        }
+        if has_uvs {
+            mesh = mesh.with_uvs(uvs);
+        }
+        Ok(mesh)
+    }
+
+    pub fn get_num_faces(&self) -> usize {
+        self.faces.len()
+    }
+
+    /// Returns the shape of face `index`.
+    pub(crate) fn get_face(&self, index: usize) -> ObjectType {
+        let p = |i: u32| self.positions[i as usize];
+        match self.faces[index] {
+            Face::Triangle([a, b, c]) => ObjectType::Triangle(p(a), p(b), p(c)),
+            Face::Quad([a, b, c, d]) => ObjectType::Quad(p(a), p(b), p(c), p(d)),
+        }
+    }
+
+    /// Returns the normal at the point on face `index` with `vertex_weights`, as in
+    /// `Hit`, interpolated from the normals of its vertices. Returns `None` if this
+    /// mesh has no vertex normals.
+    pub(crate) fn get_normal(
+        &self,
+        index: usize,
+        vertex_weights: [f32; 4],
+    ) -> Option<Vector3<f32>> {
+        if self.normals.is_empty() {
+            return None;
+        }
+        let normal: Vector3<f32> = self.faces[index]
+            .get_vertex_indices()
+            .iter()
+            .zip(&vertex_weights)
+            .map(|(&i, &weight)| weight * self.normals[i as usize])
+            .sum();
+        if normal.magnitude2() > 0.0 {
+            Some(normal.normalize())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the uv coordinates of the vertices of face `index`, or `None` if this
+    /// mesh has no uv coordinates.
+    pub(crate) fn get_vertex_uvs(&self, index: usize) -> Option<Vec<Point2<f32>>> {
+        if self.uvs.is_empty() {
+            return None;
+        }
+        let indices = self.faces[index].get_vertex_indices();
+        Some(indices.iter().map(|&i| self.uvs[i as usize]).collect())
+    }
+
+    /// Returns a hash of the positions and faces of this mesh.
+    pub(crate) fn get_geometry_hash(&self) -> u64 {
+        self.geometry_hash
+    }
+
+    /// Returns the axis-aligned minimum bounding box of this mesh.
+    pub fn get_bounding_box(&self) -> (Point3<f32>, Point3<f32>) {
+        self.bounding_box
+    }
+
+    /// Builds the bvh of this mesh with `options` using the current rayon thread pool,
+    /// or loads it from `cache_dir` if given. Does nothing if the bvh was already built.
+    pub(crate) fn build_bvh(&self, options: &BvhBuildOptions, cache_dir: Option<&Path>) {
+        self.get_bvh(options, cache_dir);
+    }
+
+    /// Returns statistics about the bvh of this mesh, if it was built.
+    pub fn get_bvh_stats(&self) -> Option<&BvhStats> {
+        self.bvh.get().map(|bvh| &bvh.stats)
+    }
+
+    fn get_bvh(&self, options: &BvhBuildOptions, cache_dir: Option<&Path>) -> &MeshBvh {
+        self.bvh.get_or_init(|| {
+            // The faces themselves rather than their bounding boxes, so that spatial
+            // splits can clip them exactly.
+            let faces = (0..self.faces.len())
+                .into_par_iter()
+                .with_min_len(1024)
+                .map(|face| self.get_face(face))
+                .collect();
+            let (tree, stats) = build_or_load(faces, self.geometry_hash, options, cache_dir);
+            MeshBvh { tree, stats }
+        })
+    }
+
+    /// If `ray` intersects some face of this mesh at some `t` in the range of the
+    /// ray, returns the hit with the smallest such `t`, on the face that was hit. Faces
+    /// are only hit from the back if `two_sided` is true.
+    pub(crate) fn get_intersection(&self, ray: &Ray, two_sided: bool) -> Option<Hit> {
+        let bvh = &self.get_bvh(&BvhBuildOptions::default(), None).tree;
+        bvh.get_closest_intersection(ray, |face, ray| {
+            self.get_face(face)
+                .get_intersection(ray, two_sided)
+                .map(|hit| (hit.t, hit.vertex_weights))
+        })
+        .map(|(face, t, vertex_weights)| Hit {
+            t,
+            face,
+            vertex_weights,
+        })
+    }
+
+    /// Like `get_intersection` for each active ray of `packet`, returning `None` for
+    /// the others.
+    pub(crate) fn get_intersections(
+        &self,
+        packet: &RayPacket,
+        two_sided: bool,
+    ) -> [Option<Hit>; PACKET_SIZE] {
+        let bvh = &self.get_bvh(&BvhBuildOptions::default(), None).tree;
+        bvh.get_closest_intersections(packet, |face, packet| {
+            let hits = self.get_face(face).get_intersections(packet, two_sided);
+            packet::lanes(|lane| hits[lane].map(|hit| (hit.t, hit.vertex_weights)))
+        })
+        .map(|hit| {
+            hit.map(|(face, t, vertex_weights)| Hit {
+                t,
+                face,
+                vertex_weights,
+            })
+        })
+    }
+
+    /// Returns true if `ray` intersects any face of this mesh within its range.
+    pub(crate) fn intersects(&self, ray: &Ray, two_sided: bool) -> bool {
+        let bvh = &self.get_bvh(&BvhBuildOptions::default(), None).tree;
+        bvh.is_occluded(ray, |face, ray| {
+            self.get_face(face)
+                .get_intersection(ray, two_sided)
+                .is_some()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Face, Mesh};
+    use crate::bvh::Bvh;
+    use crate::material::{Material, MaterialType, TextureType};
+    use crate::object::{Object, ObjectType};
+    use crate::ray::Ray;
+    use cgmath::{InnerSpace, Matrix4, Point2, Vector3};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::sync::Arc;
+
+    fn new_material() -> Material {
+        Material::new(MaterialType::None, TextureType::None)
+    }
+
+    #[test]
+    fn test_mesh() {
+        let positions = vec![
+            (0.0, 0.0, 0.0).into(),
+            (1.0, 0.0, 0.0).into(),
+            (1.0, 1.0, 0.0).into(),
+            (0.0, 1.0, 0.0).into(),
+            (2.0, 0.0, 0.0).into(),
+        ];
+        let faces = vec![Face::Quad([0, 1, 2, 3]), Face::Triangle([1, 4, 2])];
+        let mesh = Mesh::new(positions, faces);
+        assert_eq!(
+            mesh.get_bounding_box(),
+            ((0.0, 0.0, 0.0).into(), (2.0, 1.0, 0.0).into())
+        );
+
+        let ray = |x| Ray::new((x, 0.5, 1.0).into(), (0.0, 0.0, -1.0).into());
+        let hit = |x| {
+            mesh.get_intersection(&ray(x), false)
+                .map(|hit| (hit.t, hit.face))
+        };
+        assert_eq!(hit(0.5), Some((1.0, 0)));
+        assert_eq!(hit(1.2), Some((1.0, 1)));
+        assert_eq!(hit(1.8), None);
+        assert!(mesh.intersects(&ray(0.5), false));
+        assert!(!mesh.intersects(&ray(0.5).with_range(0.0, 0.5), false));
+
+        // The backs of the faces are only hit if the mesh is two-sided.
+        let behind = Ray::new((0.5, 0.5, -1.0).into(), (0.0, 0.0, 1.0).into());
+        assert!(mesh.get_intersection(&behind, false).is_none());
+        assert!(!mesh.intersects(&behind, false));
+        let hit = mesh.get_intersection(&behind, true).unwrap();
+        assert_eq!((hit.t, hit.face), (1.0, 0));
+        assert!(mesh.intersects(&behind, true));
+    }
+
+    #[test]
+    fn test_vertex_attributes() {
+        let positions = vec![
+            (0.0, 0.0, 0.0).into(),
+            (1.0, 0.0, 0.0).into(),
+            (1.0, 1.0, 0.0).into(),
+            (0.0, 1.0, 0.0).into(),
+        ];
+        let normals = vec![
+            (-1.0, 0.0, 1.0).into(),
+            (1.0, 0.0, 1.0).into(),
+            (1.0, 0.0, 1.0).into(),
+            (-1.0, 0.0, 1.0).into(),
+        ];
+        let uvs = vec![
+            (0.0, 0.5).into(),
+            (1.0, 0.5).into(),
+            (1.0, 1.0).into(),
+            (0.0, 1.0).into(),
+        ];
+        // Returns the surface of an instance of `mesh` scaled by 2 at `(x, y, 0)`.
+        let get_surface_interaction = |mesh: Mesh, x: f32, y: f32| {
+            let instance =
+                Object::new_instance(Arc::new(mesh), Matrix4::from_scale(2.0), new_material());
+            let ray = Ray::new((x, y, 1.0).into(), (0.0, 0.0, -1.0).into());
+            let hit = instance.get_face_intersection(&ray).unwrap();
+            instance.get_surface_interaction(&ray, &hit)
+        };
+        // Without vertex attributes, faces use their own.
+        let mesh = Mesh::new(positions.clone(), vec![Face::Quad([0, 1, 2, 3])]);
+        let interaction = get_surface_interaction(mesh, 0.5, 1.5);
+        assert_eq!(interaction.shading_normal, (0.0, 0.0, 1.0).into());
+        assert_eq!(interaction.uv, (0.25, 0.75).into());
+
+        for &(x, y) in &[(0.5, 1.5), (1.5, 0.5)] {
+            let mesh = Mesh::new(positions.clone(), vec![Face::Quad([0, 1, 2, 3])])
+                .with_normals(normals.clone())
+                .with_uvs(uvs.clone());
+            let interaction = get_surface_interaction(mesh, x, y);
+            assert_eq!(interaction.geometric_normal, (0.0, 0.0, 1.0).into());
+            let expected = Vector3::new(x - 1.0, 0.0, 1.0).normalize();
+            assert!((interaction.shading_normal - expected).magnitude() < 1e-5);
+            let expected = Point2::new(x / 2.0, 0.5 + y / 4.0);
+            assert!((interaction.uv - expected).magnitude() < 1e-5);
+            // The uv coordinates change twice as fast along v as along u.
+            assert!((interaction.dpdu - Vector3::new(2.0, 0.0, 0.0)).magnitude() < 1e-5);
+            assert!((interaction.dpdv - Vector3::new(0.0, 4.0, 0.0)).magnitude() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_load_vertex_attributes() {
+        let mesh = Mesh::load("media/Suzanne.obj").unwrap();
+        assert_eq!(mesh.normals.len(), mesh.positions.len());
+        assert_eq!(mesh.uvs.len(), mesh.positions.len());
+        // The 507 positions in the file are split between faces with different
+        // normals, since the normals are flat.
+        assert!(mesh.positions.len() > 507);
+        for (index, face) in mesh.faces.iter().enumerate() {
+            let shape = mesh.get_face(index);
+            // The normals of quads that do not lie in a plane are averages.
+            let geometric_normal = match shape {
+                ObjectType::Triangle(a, b, c) => (b - a).cross(c - a).normalize(),
+                ObjectType::Quad(a, b, c, d) => (c - a).cross(d - b).normalize(),
+                _ => unreachable!(),
+            };
+            for &vertex in face.get_vertex_indices() {
+                assert!(mesh.normals[vertex as usize].dot(geometric_normal) > 0.99);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mesh_instances() {
+        let mesh = Arc::new(Mesh::load("media/Suzanne.obj").unwrap());
+        let instances: Vec<Object> = (0..4)
+            .map(|i| {
+                let translation = Matrix4::from_translation((3.0 * i as f32, 0.0, 0.0).into());
+                let rotation = Matrix4::from_angle_y(cgmath::Deg(30.0 * i as f32));
+                Object::new_instance(Arc::clone(&mesh), translation * rotation, new_material())
+            })
+            .collect();
+        assert_eq!(Arc::strong_count(&mesh), 5);
+
+        // The same faces in a single level bvh.
+        let faces: Vec<Object> = instances
+            .iter()
+            .flat_map(|instance| {
+                (0..mesh.get_num_faces()).map(move |face| instance.get_face(face).into_owned())
+            })
+            .collect();
+        let two_level = Bvh::new(instances);
+        let one_level = Bvh::new(faces);
+        assert_eq!(two_level.get_report().meshes.len(), 1);
+
+        let mut rng = StdRng::seed_from_u64(248);
+        let mut num_hits = 0;
+        for _ in 0..200 {
+            let position = (rng.gen_range(-1.0, 10.0), rng.gen_range(-1.0, 1.0), 5.0);
+            let direction = (rng.gen_range(-0.2, 0.2), rng.gen_range(-0.2, 0.2), -1.0);
+            let ray = Ray::new(position.into(), direction.into());
+            let expected = one_level.get_closest_intersection(&ray);
+            let actual = two_level.get_closest_intersection(&ray);
+            assert_eq!(actual.is_some(), expected.is_some());
+            if let (Some((_, actual)), Some((_, expected))) = (actual, expected) {
+                num_hits += 1;
+                assert!((actual.t - expected.t).abs() < 1e-4);
+                assert!((actual.shading_normal - expected.shading_normal).magnitude() < 1e-4);
+                assert!((actual.uv - expected.uv).magnitude() < 1e-4);
+            }
+            assert_eq!(
+                two_level.is_occluded(&ray, 10.0),
+                one_level.is_occluded(&ray, 10.0)
+            );
+        }
+        assert!(num_hits > 20);
+    }
+}
@@ -1,93 +1,636 @@
-use cgmath::{InnerSpace, SquareMatrix, Transform};
+use cgmath::{EuclideanSpace, InnerSpace, Matrix, SquareMatrix, Transform};
 use cgmath::{Matrix4, Point2, Point3, Vector3};
-use obj;
+use rand::Rng;
+use std::borrow::Cow;
 use std::error::Error;
+use std::hash::Hasher;
 use std::path::Path;
+use std::sync::Arc;
 
+use super::bvh::{clip_bounding_box, clip_polygon, Bounded, BvhBuildOptions};
 use super::color::Color;
+use super::interaction::SurfaceInteraction;
 use super::light::Light;
-use super::material::Material;
+use super::material::{BsdfSample, Material};
+use super::mesh::Mesh;
+use super::packet::{self, Lanes, RayPacket, PACKET_SIZE};
 use super::ray::Ray;
-use super::utils::{component_wise_range, get_axis_scaling};
+use super::utils::{component_wise_range, get_axis_scaling, orthonormal_basis};
 use super::world::World;
 
-enum ObjectType {
+#[derive(Clone)]
+pub(crate) enum ObjectType {
     Sphere(Point3<f32>, f32),
     Triangle(Point3<f32>, Point3<f32>, Point3<f32>),
+    /// Hit as the triangles `a, b, c` and `a, c, d`.
     Quad(Point3<f32>, Point3<f32>, Point3<f32>, Point3<f32>),
+    /// An instance of a mesh, which is shaded through the face that was hit. See
+    /// `Object::get_face`.
+    Mesh(Arc<Mesh>),
+    /// Face `usize` of a mesh, which is shaded with the normals and uv coordinates of
+    /// the vertices of the mesh if it has them.
+    MeshFace(Arc<Mesh>, usize),
 }
 
+/// Where a ray hits a shape. See `Object::get_surface_interaction`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct Hit {
+    pub(crate) t: f32,
+    /// The index of the face that was hit, which is zero for shapes other than meshes.
+    pub(crate) face: usize,
+    /// The barycentric coordinates of the hit point, as weights of the vertices of the
+    /// triangle or quad that was hit, which sum to one. Zero for spheres.
+    pub(crate) vertex_weights: [f32; 4],
+}
+
+impl ObjectType {
+    /// If `ray` instersects this shape at some `t` in the range of the ray, returns
+    /// the hit with the smallest such `t`. Triangles and quads are only hit from the
+    /// back if `two_sided` is true.
+    ///
+    /// Both `ray` and `t` are in object space coordinates.
+    pub(crate) fn get_intersection(&self, ray: &Ray, two_sided: bool) -> Option<Hit> {
+        let position: Point3<f32> = ray.get_point_on_ray(0.0).into();
+        let direction = ray.get_direction();
+        match self {
+            ObjectType::Sphere(center, radius) => {
+                let t = (center - position).dot(direction);
+                let closest_point_to_center: Point3<f32> = ray.get_point_on_ray(t).into();
+                let radius_sqrd = radius.powf(2.0);
+                let dist_to_center_sqrd = (center - closest_point_to_center).magnitude2();
+                if dist_to_center_sqrd <= radius_sqrd {
+                    let delta = (radius_sqrd - dist_to_center_sqrd).sqrt();
+                    // Find the smallest t value in range.
+                    [t - delta, t + delta]
+                        .iter()
+                        .cloned()
+                        .find(|t| ray.is_in_range(*t))
+                        .map(|t| Hit {
+                            t,
+                            face: 0,
+                            vertex_weights: [0.0; 4],
+                        })
+                } else {
+                    None
+                }
+            }
+            &ObjectType::Quad(a, b, c, d) => get_quad_intersection(ray, a, b, c, d, two_sided),
+            &ObjectType::Triangle(a, b, c) => get_triangle_intersection(ray, a, b, c, two_sided)
+                .map(|(t, [w0, w1, w2])| Hit {
+                    t,
+                    face: 0,
+                    vertex_weights: [w0, w1, w2, 0.0],
+                }),
+            ObjectType::Mesh(mesh) => mesh.get_intersection(ray, two_sided),
+            ObjectType::MeshFace(mesh, face) => {
+                mesh.get_face(*face).get_intersection(ray, two_sided)
+            }
+        }
+    }
+
+    /// Like `get_intersection` for each active ray of `packet`, returning `None` for
+    /// the others. Gives the same results as testing the rays one at a time.
+    ///
+    /// Both `packet` and `t` are in object space coordinates.
+    pub(crate) fn get_intersections(
+        &self,
+        packet: &RayPacket,
+        two_sided: bool,
+    ) -> [Option<Hit>; PACKET_SIZE] {
+        match self {
+            &ObjectType::Sphere(center, radius) => {
+                let t = packet::dot_lanes(
+                    &packet::sub_from(center, &packet.position),
+                    &packet.direction,
+                );
+                let closest_point_to_center = packet.get_point_on_ray(&t);
+                let radius_sqrd = radius.powf(2.0);
+                let to_center = packet::sub_from(center, &closest_point_to_center);
+                let dist_to_center_sqrd = packet::dot_lanes(&to_center, &to_center);
+                let delta = packet::lanes(|lane| (radius_sqrd - dist_to_center_sqrd[lane]).sqrt());
+                let near = packet::lanes(|lane| t[lane] - delta[lane]);
+                let far = packet::lanes(|lane| t[lane] + delta[lane]);
+                let (near_in_range, far_in_range) =
+                    (packet.is_in_range(&near), packet.is_in_range(&far));
+                packet::lanes(|lane| {
+                    let t = if !packet.active[lane] || dist_to_center_sqrd[lane] > radius_sqrd {
+                        None
+                    } else if near_in_range[lane] {
+                        Some(near[lane])
+                    } else if far_in_range[lane] {
+                        Some(far[lane])
+                    } else {
+                        None
+                    };
+                    t.map(|t| Hit {
+                        t,
+                        face: 0,
+                        vertex_weights: [0.0; 4],
+                    })
+                })
+            }
+            &ObjectType::Triangle(a, b, c) => {
+                let hits = get_triangle_intersections(packet, a, b, c, two_sided);
+                packet::lanes(|lane| {
+                    hits[lane].map(|(t, [w0, w1, w2])| Hit {
+                        t,
+                        face: 0,
+                        vertex_weights: [w0, w1, w2, 0.0],
+                    })
+                })
+            }
+            &ObjectType::Quad(a, b, c, d) => {
+                let first = get_triangle_intersections(packet, a, b, c, two_sided);
+                let second = get_triangle_intersections(packet, a, c, d, two_sided);
+                packet::lanes(|lane| {
+                    let first = first[lane].map(|(t, [w0, w1, w2])| Hit {
+                        t,
+                        face: 0,
+                        vertex_weights: [w0, w1, w2, 0.0],
+                    });
+                    let second = second[lane].map(|(t, [w0, w2, w3])| Hit {
+                        t,
+                        face: 0,
+                        vertex_weights: [w0, 0.0, w2, w3],
+                    });
+                    get_closer_hit(first, second)
+                })
+            }
+            ObjectType::Mesh(mesh) => mesh.get_intersections(packet, two_sided),
+            ObjectType::MeshFace(mesh, face) => {
+                mesh.get_face(*face).get_intersections(packet, two_sided)
+            }
+        }
+    }
+
+    /// Returns the geometry of this shape at `point`, where its vertices, if any, have
+    /// `vertex_weights`. For meshes, this is the geometry of face `face`.
+    ///
+    /// `point` is in object space coordinates.
+    fn get_surface_geometry(
+        &self,
+        point: Point3<f32>,
+        face: usize,
+        vertex_weights: [f32; 4],
+    ) -> SurfaceGeometry {
+        match *self {
+            ObjectType::Sphere(center, radius) => {
+                let normal = (point - center).normalize();
+                let (x, y, z) = normal.into();
+                let theta = z.acos();
+                let phi = y.atan2(x);
+                let uv = Point2::new(
+                    theta / (2.0 * std::f32::consts::PI),
+                    phi / std::f32::consts::PI,
+                );
+                // The derivatives of `center + radius * normal` with `theta = 2 pi u`
+                // and `phi = pi v`.
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                let (sin_phi, cos_phi) = phi.sin_cos();
+                let dpdtheta =
+                    radius * Vector3::new(cos_theta * cos_phi, cos_theta * sin_phi, -sin_theta);
+                let dpdphi = radius * Vector3::new(-sin_theta * sin_phi, sin_theta * cos_phi, 0.0);
+                SurfaceGeometry {
+                    geometric_normal: normal,
+                    shading_normal: normal,
+                    uv,
+                    dpdu: 2.0 * std::f32::consts::PI * dpdtheta,
+                    dpdv: std::f32::consts::PI * dpdphi,
+                }
+            }
+            ObjectType::Triangle(..) | ObjectType::Quad(..) => {
+                self.get_polygon_geometry(None, vertex_weights)
+            }
+            ObjectType::Mesh(ref mesh) => get_mesh_face_geometry(mesh, face, vertex_weights),
+            ObjectType::MeshFace(ref mesh, face) => {
+                get_mesh_face_geometry(mesh, face, vertex_weights)
+            }
+        }
+    }
+
+    /// Returns the geometry of this triangle or quad at the point where its vertices
+    /// have `vertex_weights`. Its vertices have the uv coordinates `uvs` if given, and
+    /// otherwise those of `TRIANGLE_UVS` or `QUAD_UVS`.
+    fn get_polygon_geometry(
+        &self,
+        uvs: Option<&[Point2<f32>]>,
+        vertex_weights: [f32; 4],
+    ) -> SurfaceGeometry {
+        let (vertices, default_uvs): (Vec<Point3<f32>>, &[Point2<f32>]) = match *self {
+            ObjectType::Triangle(a, b, c) => (vec![a, b, c], &TRIANGLE_UVS),
+            ObjectType::Quad(a, b, c, d) => (vec![a, b, c, d], &QUAD_UVS),
+            _ => unreachable!("Only triangles and quads have vertices."),
+        };
+        let uvs = uvs.unwrap_or(default_uvs);
+        // Quads are hit as the triangles `a, b, c` and `a, c, d`, and only the second
+        // gives `d` any weight.
+        let corners = if vertex_weights[3] > 0.0 {
+            [0, 2, 3]
+        } else {
+            [0, 1, 2]
+        };
+        let [p0, p1, p2] = corners.map(|i| vertices[i]);
+        let [uv0, uv1, uv2] = corners.map(|i| uvs[i]);
+        let [w0, w1, w2] = corners.map(|i| vertex_weights[i]);
+        let geometric_normal = (p1 - p0).cross(p2 - p0).normalize();
+        let uv = Point2::from_vec(w0 * uv0.to_vec() + w1 * uv1.to_vec() + w2 * uv2.to_vec());
+
+        // Solve `p0 - p2 = (u0 - u2) dpdu + (v0 - v2) dpdv` and the same for `p1 - p2`.
+        let (duv02, duv12) = (uv0 - uv2, uv1 - uv2);
+        let (dp02, dp12) = (p0 - p2, p1 - p2);
+        let det = duv02.x * duv12.y - duv02.y * duv12.x;
+        let (dpdu, dpdv) = if det.abs() < 1e-8 {
+            // The uv coordinates do not span the triangle, so any tangents will do.
+            orthonormal_basis(geometric_normal)
+        } else {
+            (
+                (duv12.y * dp02 - duv02.y * dp12) / det,
+                (duv02.x * dp12 - duv12.x * dp02) / det,
+            )
+        };
+        SurfaceGeometry {
+            geometric_normal,
+            shading_normal: geometric_normal,
+            uv,
+            dpdu,
+            dpdv,
+        }
+    }
+
+    /// Returns the triangle or quad of a face of a mesh, or else this shape itself.
+    fn get_shape(&self) -> Cow<'_, ObjectType> {
+        match self {
+            ObjectType::MeshFace(mesh, face) => Cow::Owned(mesh.get_face(*face)),
+            _ => Cow::Borrowed(self),
+        }
+    }
+}
+
+/// If `ray` hits the triangle `a, b, c` at some `t` in the range of the ray, returns
+/// `t` along with the barycentric coordinates of the hit point. Unless `two_sided` is
+/// true, only the front of the triangle is hit, which is the side from which its
+/// vertices are in counterclockwise order.
+///
+/// This is the watertight test of Woop, Benthin, and Wald, "Watertight Ray/Triangle
+/// Intersection". The vertices are moved into a space where the ray starts at the
+/// origin and points along the z axis, so whether the ray hits is decided by the
+/// signs of three edge functions in the xy plane. Triangles that share an edge
+/// compute the same edge function for it, only with the opposite sign, so rays never
+/// slip through cracks between them. Rays through an edge or vertex hit each of the
+/// triangles around it that are hit from their side.
+fn get_triangle_intersection(
+    ray: &Ray,
+    a: Point3<f32>,
+    b: Point3<f32>,
+    c: Point3<f32>,
+    two_sided: bool,
+) -> Option<(f32, [f32; 3])> {
+    let position: Point3<f32> = ray.get_point_on_ray(0.0).into();
+    let d: [f32; 3] = ray.get_direction().into();
+    // The ray points mostly along the new z axis, so the shear is well conditioned.
+    let (dx, dy, dz) = (d[0].abs(), d[1].abs(), d[2].abs());
+    let kz = if dx > dy && dx > dz {
+        0
+    } else if dy > dz {
+        1
+    } else {
+        2
+    };
+    let kx = (kz + 1) % 3;
+    let ky = (kx + 1) % 3;
+    let shear_x = -d[kx] / d[kz];
+    let shear_y = -d[ky] / d[kz];
+    let scale_z = 1.0 / d[kz];
+    let to_ray_space = |vertex: Point3<f32>| {
+        let p: [f32; 3] = (vertex - position).into();
+        [
+            p[kx] + shear_x * p[kz],
+            p[ky] + shear_y * p[kz],
+            p[kz] * scale_z,
+        ]
+    };
+    let (p0, p1, p2) = (to_ray_space(a), to_ray_space(b), to_ray_space(c));
+
+    let edge_function = |p: [f32; 3], q: [f32; 3]| p[0] * q[1] - p[1] * q[0];
+    let mut e = [
+        edge_function(p1, p2),
+        edge_function(p2, p0),
+        edge_function(p0, p1),
+    ];
+    if e.contains(&0.0) {
+        // The ray may pass exactly through an edge, which is decided in double
+        // precision so that rounding cannot put it outside both triangles.
+        let edge_function = |p: [f32; 3], q: [f32; 3]| {
+            (p[0] as f64 * q[1] as f64 - p[1] as f64 * q[0] as f64) as f32
+        };
+        e = [
+            edge_function(p1, p2),
+            edge_function(p2, p0),
+            edge_function(p0, p1),
+        ];
+    }
+    let is_outside = e.iter().any(|&e| e < 0.0) && e.iter().any(|&e| e > 0.0);
+    let det = e[0] + e[1] + e[2];
+    // The determinant has the sign of `(b - a).cross(c - a).dot(direction)` divided by
+    // the z component of the direction, which is negative from the front.
+    if is_outside || det == 0.0 || (!two_sided && det * d[kz] > 0.0) {
+        return None;
+    }
+    let inverse_det = 1.0 / det;
+    let t = (e[0] * p0[2] + e[1] * p1[2] + e[2] * p2[2]) * inverse_det;
+    if ray.is_in_range(t) {
+        Some((
+            t,
+            [e[0] * inverse_det, e[1] * inverse_det, e[2] * inverse_det],
+        ))
+    } else {
+        None
+    }
+}
+
+/// Returns the closer hit of `ray` on the triangles `a, b, c` and `a, c, d`, with the
+/// weights of all four vertices. Splitting quads this way keeps them watertight with
+/// the faces around them even when their vertices do not quite lie in a plane.
+fn get_quad_intersection(
+    ray: &Ray,
+    a: Point3<f32>,
+    b: Point3<f32>,
+    c: Point3<f32>,
+    d: Point3<f32>,
+    two_sided: bool,
+) -> Option<Hit> {
+    let first = get_triangle_intersection(ray, a, b, c, two_sided).map(|(t, [w0, w1, w2])| Hit {
+        t,
+        face: 0,
+        vertex_weights: [w0, w1, w2, 0.0],
+    });
+    let second = get_triangle_intersection(ray, a, c, d, two_sided).map(|(t, [w0, w2, w3])| Hit {
+        t,
+        face: 0,
+        vertex_weights: [w0, 0.0, w2, w3],
+    });
+    get_closer_hit(first, second)
+}
+
+/// Returns whichever of the hits on the two halves of a quad is closer, preferring
+/// `first` when they are as close.
+fn get_closer_hit(first: Option<Hit>, second: Option<Hit>) -> Option<Hit> {
+    match (first, second) {
+        (Some(first), Some(second)) if second.t < first.t => Some(second),
+        (Some(first), _) => Some(first),
+        (None, second) => second,
+    }
+}
+
+/// Like `get_triangle_intersection` for each active ray of `packet`, returning `None`
+/// for the others. Each step is done for all of the rays at once, and rays that miss
+/// are masked out at the end, so the results are exactly those of testing the rays
+/// one at a time.
+fn get_triangle_intersections(
+    packet: &RayPacket,
+    a: Point3<f32>,
+    b: Point3<f32>,
+    c: Point3<f32>,
+    two_sided: bool,
+) -> [Option<(f32, [f32; 3])>; PACKET_SIZE] {
+    let d = &packet.direction;
+    let (dx, dy, dz) = (
+        packet::lanes(|lane| d[0][lane].abs()),
+        packet::lanes(|lane| d[1][lane].abs()),
+        packet::lanes(|lane| d[2][lane].abs()),
+    );
+    // Each ray is permuted along its own largest axis, as in the single ray test.
+    // Inactive rays take the axis of an active one, so that coherent packets pick the
+    // same components in every lane.
+    let largest_axis = |lane: usize| {
+        if dx[lane] > dy[lane] && dx[lane] > dz[lane] {
+            0
+        } else if dy[lane] > dz[lane] {
+            1
+        } else {
+            2
+        }
+    };
+    let first_active = packet.active.iter().position(|&active| active).unwrap_or(0);
+    let kz: [usize; PACKET_SIZE] = packet::lanes(|lane| {
+        largest_axis(if packet.active[lane] {
+            lane
+        } else {
+            first_active
+        })
+    });
+    let kx = packet::lanes(|lane| (kz[lane] + 1) % 3);
+    let ky = packet::lanes(|lane| (kx[lane] + 1) % 3);
+    let is_uniform = kz.iter().all(|&axis| axis == kz[0]);
+    let select_axis = |v: &[Lanes; 3], axis: &[usize; PACKET_SIZE]| {
+        if is_uniform {
+            v[axis[0]]
+        } else {
+            packet::lanes(|lane| v[axis[lane]][lane])
+        }
+    };
+    let (d_kx, d_ky, d_kz) = (
+        select_axis(d, &kx),
+        select_axis(d, &ky),
+        select_axis(d, &kz),
+    );
+    let shear_x = packet::lanes(|lane| -d_kx[lane] / d_kz[lane]);
+    let shear_y = packet::lanes(|lane| -d_ky[lane] / d_kz[lane]);
+    let scale_z = packet::lanes(|lane| 1.0 / d_kz[lane]);
+    let to_ray_space = |vertex: Point3<f32>| {
+        let p = packet::sub_from(vertex, &packet.position);
+        let (p_kx, p_ky, p_kz) = (
+            select_axis(&p, &kx),
+            select_axis(&p, &ky),
+            select_axis(&p, &kz),
+        );
+        [
+            packet::lanes(|lane| p_kx[lane] + shear_x[lane] * p_kz[lane]),
+            packet::lanes(|lane| p_ky[lane] + shear_y[lane] * p_kz[lane]),
+            packet::lanes(|lane| p_kz[lane] * scale_z[lane]),
+        ]
+    };
+    let (p0, p1, p2) = (to_ray_space(a), to_ray_space(b), to_ray_space(c));
+
+    let edge_function = |p: &[Lanes; 3], q: &[Lanes; 3]| {
+        packet::lanes(|lane| p[0][lane] * q[1][lane] - p[1][lane] * q[0][lane])
+    };
+    let mut e = [
+        edge_function(&p1, &p2),
+        edge_function(&p2, &p0),
+        edge_function(&p0, &p1),
+    ];
+    let on_edge = packet::lanes(|lane| e.iter().any(|e| e[lane] == 0.0));
+    if on_edge.contains(&true) {
+        let edge_function = |p: &[Lanes; 3], q: &[Lanes; 3], lane: usize| {
+            (p[0][lane] as f64 * q[1][lane] as f64 - p[1][lane] as f64 * q[0][lane] as f64) as f32
+        };
+        for lane in (0..PACKET_SIZE).filter(|&lane| on_edge[lane]) {
+            e[0][lane] = edge_function(&p1, &p2, lane);
+            e[1][lane] = edge_function(&p2, &p0, lane);
+            e[2][lane] = edge_function(&p0, &p1, lane);
+        }
+    }
+    let det = packet::lanes(|lane| e[0][lane] + e[1][lane] + e[2][lane]);
+    let inverse_det = packet::lanes(|lane| 1.0 / det[lane]);
+    let t = packet::lanes(|lane| {
+        (e[0][lane] * p0[2][lane] + e[1][lane] * p1[2][lane] + e[2][lane] * p2[2][lane])
+            * inverse_det[lane]
+    });
+    let in_range = packet.is_in_range(&t);
+    let hits = packet::lanes(|lane| {
+        let (e0, e1, e2) = (e[0][lane], e[1][lane], e[2][lane]);
+        let is_outside = (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0);
+        let is_back = !two_sided && det[lane] * d_kz[lane] > 0.0;
+        packet.active[lane] && !is_outside && det[lane] != 0.0 && !is_back && in_range[lane]
+    });
+    packet::lanes(|lane| {
+        if hits[lane] {
+            let inverse_det = inverse_det[lane];
+            Some((
+                t[lane],
+                [
+                    e[0][lane] * inverse_det,
+                    e[1][lane] * inverse_det,
+                    e[2][lane] * inverse_det,
+                ],
+            ))
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the geometry of face `face` of `mesh`, which is shaded with the normals
+/// and uv coordinates of the vertices of the mesh if it has them.
+fn get_mesh_face_geometry(mesh: &Mesh, face: usize, vertex_weights: [f32; 4]) -> SurfaceGeometry {
+    let uvs = mesh.get_vertex_uvs(face);
+    let mut geometry = mesh
+        .get_face(face)
+        .get_polygon_geometry(uvs.as_deref(), vertex_weights);
+    if let Some(normal) = mesh.get_normal(face, vertex_weights) {
+        geometry.shading_normal = normal;
+    }
+    geometry
+}
+
+/// The uv coordinates of the vertices of triangles without their own.
+const TRIANGLE_UVS: [Point2<f32>; 3] = [
+    Point2::new(0.0, 0.0),
+    Point2::new(1.0, 0.0),
+    Point2::new(0.0, 1.0),
+];
+
+/// The uv coordinates of the vertices of quads without their own, at the corners of
+/// the unit square.
+const QUAD_UVS: [Point2<f32>; 4] = [
+    Point2::new(0.0, 0.0),
+    Point2::new(1.0, 0.0),
+    Point2::new(1.0, 1.0),
+    Point2::new(0.0, 1.0),
+];
+
+/// The parts of a `SurfaceInteraction` that depend on the shape that was hit, in
+/// object space coordinates.
+struct SurfaceGeometry {
+    geometric_normal: Vector3<f32>,
+    shading_normal: Vector3<f32>,
+    uv: Point2<f32>,
+    dpdu: Vector3<f32>,
+    dpdv: Vector3<f32>,
+}
+
+/// A shape in object space, such as a face of a mesh.
+impl Bounded for ObjectType {
+    fn get_bounding_box(&self) -> (Point3<f32>, Point3<f32>) {
+        match self {
+            &ObjectType::Sphere(center, radius) => {
+                let radius = Vector3::new(radius, radius, radius);
+                (center - radius, center + radius)
+            }
+            &ObjectType::Triangle(a, b, c) => component_wise_range(&[a, b, c]),
+            &ObjectType::Quad(a, b, c, d) => component_wise_range(&[a, b, c, d]),
+            ObjectType::Mesh(mesh) => mesh.get_bounding_box(),
+            ObjectType::MeshFace(mesh, face) => mesh.get_face(*face).get_bounding_box(),
+        }
+    }
+
+    fn get_clipped_bounding_box(
+        &self,
+        axis: usize,
+        min: f32,
+        max: f32,
+    ) -> Option<(Point3<f32>, Point3<f32>)> {
+        match *self {
+            ObjectType::Triangle(a, b, c) => clip_polygon(&[a, b, c], axis, min, max),
+            ObjectType::Quad(a, b, c, d) => {
+                let halves = [
+                    clip_polygon(&[a, b, c], axis, min, max),
+                    clip_polygon(&[a, c, d], axis, min, max),
+                ];
+                let points: Vec<Point3<f32>> = halves
+                    .iter()
+                    .flatten()
+                    .flat_map(|&(min, max)| vec![min, max])
+                    .collect();
+                if points.is_empty() {
+                    None
+                } else {
+                    Some(component_wise_range(&points))
+                }
+            }
+            ObjectType::MeshFace(ref mesh, face) => {
+                mesh.get_face(face).get_clipped_bounding_box(axis, min, max)
+            }
+            _ => clip_bounding_box(self.get_bounding_box(), axis, min, max),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Object {
     object_type: ObjectType,
-    // TODO: Make `object_to_world` a reference to save memory
     object_to_world: Matrix4<f32>,
     world_to_object: Matrix4<f32>,
-    material: Material,
+    /// Shared between the faces of a mesh so that they are cheap to create.
+    material: Arc<Material>,
+    /// Whether triangles and quads are hit from the back as well as the front. See
+    /// `Object::two_sided`.
+    two_sided: bool,
 }
 
 impl Object {
-    /// Returns a list of triangles built from a .obj file.
+    /// Returns an instance of the mesh in a .obj file. See `Object::new_instance`.
     pub fn new_mesh<P>(
         path: P,
         object_to_world: Matrix4<f32>,
         material: Material,
-    ) -> Result<Vec<Self>, Box<dyn Error>>
+    ) -> Result<Self, Box<dyn Error>>
     where
         P: AsRef<Path>,
     {
-        let path = path.as_ref();
-        let obj = obj::Obj::<obj::SimplePolygon>::load(path)?;
-        let triangles: Vec<Object> = obj
-            .objects
-            .iter()
-            .flat_map(|object| {
-                debug!("Reading {} from {}", object.name, path.display());
-                let triangles: Vec<Object> = object
-                    .groups
-                    .iter()
-                    .flat_map(|group| {
-                        let triangles: Vec<Object> = group
-                            .polys
-                            .iter()
-                            .map(|poly| {
-                                let vertex_indices: Vec<usize> =
-                                    poly.iter().map(|tuple| tuple.0).collect();
-                                // TODO: .obj files also hold normal and material information.
-                                // let texture_indices: Vec<Option<usize>> = poly.iter().map(|tuple| tuple.1).collect();
-                                // let normal_indices: Vec<Option<usize>> = poly.iter().map(|tuple| tuple.2).collect();
-                                let vertices: Vec<[f32; 3]> = vertex_indices
-                                    .into_iter()
-                                    .map(|i| obj.position[i])
-                                    .collect();
-                                let object_type = if vertices.len() == 3 {
-                                    let a = vertices[0].into();
-                                    let b = vertices[1].into();
-                                    let c = vertices[2].into();
-                                    ObjectType::Triangle(a, b, c)
-                                } else if vertices.len() == 4 {
-                                    let a = vertices[0].into();
-                                    let b = vertices[1].into();
-                                    let c = vertices[2].into();
-                                    let d = vertices[3].into();
-                                    ObjectType::Quad(a, b, c, d)
-                                } else {
-                                    panic!("Mesh has a polygon with {} vertices which is not supported.", vertices.len())
-                                };
-                                Object {
-                                    object_type,
-                                    object_to_world,
-                                    world_to_object: object_to_world.inverse_transform().unwrap(),
-                                    material: material.clone(),
-                                }
-                            })
-                            .collect();
-                        triangles
-                    })
-                    .collect();
-                triangles
-            })
-            .collect();
-        Ok(triangles)
+        let mesh = Mesh::load(path)?;
+        Ok(Object::new_instance(
+            Arc::new(mesh),
+            object_to_world,
+            material,
+        ))
+    }
+
+    /// Returns an instance of `mesh` with its own transform and material. Instances
+    /// share the faces and bvh of the mesh, so each one takes little memory.
+    pub fn new_instance(
+        mesh: Arc<Mesh>,
+        object_to_world: Matrix4<f32>,
+        material: Material,
+    ) -> Self {
+        Object {
+            object_type: ObjectType::Mesh(mesh),
+            object_to_world,
+            world_to_object: object_to_world.inverse_transform().unwrap(),
+            material: Arc::new(material),
+            two_sided: true,
+        }
     }
 
     pub fn new_sphere(center: Point3<f32>, radius: f32, material: Material) -> Self {
@@ -95,7 +638,8 @@ impl Object {
             object_type: ObjectType::Sphere(center, radius),
             object_to_world: Matrix4::identity(),
             world_to_object: Matrix4::identity(),
-            material,
+            material: Arc::new(material),
+            two_sided: true,
         }
     }
 
@@ -110,7 +654,8 @@ impl Object {
             object_type: ObjectType::Quad(a, b, c, d),
             object_to_world: Matrix4::identity(),
             world_to_object: Matrix4::identity(),
-            material,
+            material: Arc::new(material),
+            two_sided: true,
         }
     }
 
@@ -124,7 +669,8 @@ impl Object {
             object_type: ObjectType::Triangle(a, b, c),
             object_to_world: Matrix4::identity(),
             world_to_object: Matrix4::identity(),
-            material,
+            material: Arc::new(material),
+            two_sided: true,
         }
     }
 
@@ -132,149 +678,281 @@ impl Object {
         let object_to_world = object_to_world * self.get_object_to_world();
         Object {
             object_type: self.object_type,
-            object_to_world: object_to_world,
+            object_to_world,
             world_to_object: object_to_world.inverse_transform().unwrap(),
             material: self.material,
+            two_sided: self.two_sided,
         }
     }
 
-    /// If `ray` instersects this object, returns `t` such that the
-    /// intersection point is at `ray.get_point_on_ray(t)`.
+    /// Sets whether the triangles and quads of this object are hit from both sides,
+    /// which is the default. Otherwise rays only hit their front, the side from which
+    /// their vertices are in counterclockwise order, and pass through their back.
     ///
-    /// Both `ray` and `t` are in world space coordinates.
-    pub fn get_intersection(&self, ray: &Ray) -> Option<f32> {
-        let object_space_ray = ray.transform_using(self.get_world_to_object());
-        let position: Point3<f32> = object_space_ray.get_point_on_ray(0.0).into();
-        let direction = object_space_ray.get_direction();
-        let t = match self.object_type {
+    /// Culling back faces like this is mostly useful for closed meshes seen from the
+    /// outside. Spheres are always hit from both sides.
+    pub fn two_sided(self, two_sided: bool) -> Self {
+        Object { two_sided, ..self }
+    }
+
+    /// Returns true if the triangles and quads of this object are hit from both sides.
+    pub fn is_two_sided(&self) -> bool {
+        self.two_sided
+    }
+
+    /// Replaces the transform of this object, such as to move it for the next frame
+    /// of an animation.
+    pub fn set_object_to_world(&mut self, object_to_world: Matrix4<f32>) {
+        self.object_to_world = object_to_world;
+        self.world_to_object = object_to_world.inverse_transform().unwrap();
+    }
+
+    /// Feeds the shape and transform of this object into `state`, so that objects
+    /// with the same geometry hash the same regardless of their materials.
+    pub fn hash_geometry<H: Hasher>(&self, state: &mut H) {
+        let points = match *self.object_type.get_shape() {
             ObjectType::Sphere(center, radius) => {
-                let t = (center - position).dot(direction);
-                let closest_point_to_center: Point3<f32> =
-                    object_space_ray.get_point_on_ray(t).into();
-                let radius_sqrd = radius.powf(2.0);
-                let dist_to_center_sqrd = (center - closest_point_to_center).magnitude2();
-                if dist_to_center_sqrd <= radius_sqrd {
-                    let delta = (radius_sqrd - dist_to_center_sqrd).sqrt();
-                    // Find the smallest positive t value.
-                    vec![t - delta, t + delta]
-                        .into_iter()
-                        .filter(|t| t.is_sign_positive())
-                        .min_by(|x, y| x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal))
-                } else {
-                    None
-                }
+                state.write_u8(0);
+                state.write_u32(radius.to_bits());
+                vec![center]
+            }
+            ObjectType::Triangle(a, b, c) => {
+                state.write_u8(1);
+                vec![a, b, c]
             }
             ObjectType::Quad(a, b, c, d) => {
-                let normal = (b - a).cross(d - a).normalize();
-                if direction.dot(normal) < 0.0 {
-                    let t = (a - position).dot(normal) / direction.dot(normal);
-                    if t > 0.0 {
-                        let intersection_point: Point3<f32> =
-                            object_space_ray.get_point_on_ray(t).into();
-                        let inside_quad = vec![
-                            (b - a).cross(intersection_point - a),
-                            (c - b).cross(intersection_point - b),
-                            (d - c).cross(intersection_point - c),
-                            (a - d).cross(intersection_point - d),
-                        ]
-                        .iter()
-                        .map(|v| v.dot(normal))
-                        .all(|x| x.is_sign_positive());
-                        if inside_quad {
-                            return Some(t);
-                        }
-                    }
-                }
-                None
+                state.write_u8(2);
+                vec![a, b, c, d]
             }
-            ObjectType::Triangle(a, b, c) => {
-                let normal = (b - a).cross(c - a).normalize();
-                if direction.dot(normal) < 0.0 {
-                    let t = (a - position).dot(normal) / direction.dot(normal);
-                    if t > 0.0 {
-                        let intersection_point: Point3<f32> =
-                            object_space_ray.get_point_on_ray(t).into();
-                        let inside_triangle = vec![
-                            (b - a).cross(intersection_point - a),
-                            (c - b).cross(intersection_point - b),
-                            (a - c).cross(intersection_point - c),
-                        ]
-                        .iter()
-                        .map(|v| v.dot(normal))
-                        .all(|x| x.is_sign_positive());
-                        if inside_triangle {
-                            return Some(t);
-                        }
-                    }
-                }
-                None
+            ObjectType::Mesh(ref mesh) => {
+                state.write_u8(3);
+                state.write_u64(mesh.get_geometry_hash());
+                vec![]
             }
+            ObjectType::MeshFace(..) => unreachable!(),
         };
-        t.map(|t| {
-            let object_space_intersection_point = object_space_ray.get_point_on_ray(t).into();
-            let object_to_world = self.get_object_to_world();
-            let intersection_point =
-                object_to_world.transform_point(object_space_intersection_point);
-            ray.get_t(intersection_point)
+        let matrix: &[f32; 16] = self.object_to_world.as_ref();
+        for x in points
+            .iter()
+            .flat_map(|p| p.as_ref() as &[f32; 3])
+            .chain(matrix)
+        {
+            state.write_u32(x.to_bits());
+        }
+    }
+
+    /// If `ray` instersects this object at some `t` in the range of the ray, returns
+    /// the smallest such `t`. The intersection point is at `ray.get_point_on_ray(t)`.
+    ///
+    /// Both `ray` and `t` are in world space coordinates.
+    pub fn get_intersection(&self, ray: &Ray) -> Option<f32> {
+        self.get_face_intersection(ray).map(|hit| hit.t)
+    }
+
+    /// Like `get_intersection`, but returns the whole hit, whose face can be passed to
+    /// `Object::get_face` for meshes and which `Object::get_surface_interaction`
+    /// shades.
+    ///
+    /// Both `ray` and the `t` of the hit are in world space coordinates.
+    pub(crate) fn get_face_intersection(&self, ray: &Ray) -> Option<Hit> {
+        if self.is_in_world_space() {
+            return self.object_type.get_intersection(ray, self.two_sided);
+        }
+        // The range of the ray is scaled along with it, so it can be checked in object space.
+        let object_space_ray = ray.transform_using(self.get_world_to_object());
+        self.object_type
+            .get_intersection(&object_space_ray, self.two_sided)
+            .map(|hit| {
+                let object_space_intersection_point =
+                    object_space_ray.get_point_on_ray(hit.t).into();
+                let object_to_world = self.get_object_to_world();
+                let intersection_point =
+                    object_to_world.transform_point(object_space_intersection_point);
+                Hit {
+                    t: ray.get_t(intersection_point),
+                    ..hit
+                }
+            })
+    }
+
+    /// Like `get_face_intersection` for each active ray of `packet`, returning `None`
+    /// for the others. Gives the same results as testing the rays one at a time.
+    pub(crate) fn get_face_intersections(&self, packet: &RayPacket) -> [Option<Hit>; PACKET_SIZE] {
+        if self.is_in_world_space() {
+            return self.object_type.get_intersections(packet, self.two_sided);
+        }
+        // The same object space rays as `get_face_intersection` would use.
+        let rays: Vec<Ray> = (0..packet.len())
+            .map(|lane| {
+                let ray = packet.get_ray(lane);
+                if packet.active[lane] {
+                    ray.transform_using(self.get_world_to_object())
+                } else {
+                    ray
+                }
+            })
+            .collect();
+        let object_space_packet = RayPacket::new(&rays).with_active(packet.active);
+        let hits = self
+            .object_type
+            .get_intersections(&object_space_packet, self.two_sided);
+        packet::lanes(|lane| {
+            hits[lane].map(|hit| {
+                let object_space_intersection_point = rays[lane].get_point_on_ray(hit.t).into();
+                let intersection_point = self
+                    .get_object_to_world()
+                    .transform_point(object_space_intersection_point);
+                Hit {
+                    t: packet.get_ray(lane).get_t(intersection_point),
+                    ..hit
+                }
+            })
         })
     }
 
-    /// Returns the normal of the object at `point` in world space coordinates.
+    /// Returns true if `ray` intersects this object within the range of the ray.
+    /// Meshes stop looking at the first face that is hit.
     ///
-    /// `point` is in world space coordinates.
-    pub fn get_normal(&self, point: Point3<f32>) -> Vector3<f32> {
-        let point = self.get_world_to_object().transform_point(point);
-        let normal = match self.object_type {
-            ObjectType::Sphere(center, _) => (point - center).normalize(),
-            ObjectType::Quad(a, b, _c, d) => (b - a).cross(d - a).normalize(),
-            ObjectType::Triangle(a, b, c) => (b - a).cross(c - a).normalize(),
-        };
-        self.get_object_to_world()
-            .transform_vector(normal)
-            .normalize()
+    /// `ray` is in world space coordinates.
+    pub fn intersects(&self, ray: &Ray) -> bool {
+        match &self.object_type {
+            ObjectType::Mesh(mesh) => mesh.intersects(
+                &ray.transform_using(self.get_world_to_object()),
+                self.two_sided,
+            ),
+            _ => self.get_intersection(ray).is_some(),
+        }
     }
 
-    /// Returns the color of the object at the point given by `incoming_ray.get_point_on_ray(t)`.
+    /// Returns face `face` of this mesh instance as an object with the transform and
+    /// material of the instance. Other objects only have the one face, themselves.
+    pub fn get_face(&self, face: usize) -> Cow<'_, Object> {
+        match &self.object_type {
+            ObjectType::Mesh(mesh) => Cow::Owned(Object {
+                object_type: ObjectType::MeshFace(Arc::clone(mesh), face),
+                object_to_world: self.object_to_world,
+                world_to_object: self.world_to_object,
+                material: Arc::clone(&self.material),
+                two_sided: self.two_sided,
+            }),
+            _ => Cow::Borrowed(self),
+        }
+    }
+
+    /// Returns the mesh that this object is an instance of, if any.
+    pub fn get_mesh(&self) -> Option<&Arc<Mesh>> {
+        match &self.object_type {
+            ObjectType::Mesh(mesh) => Some(mesh),
+            _ => None,
+        }
+    }
+
+    /// Builds the bvh of this object's mesh, if it has one. See `Mesh::build_bvh`.
+    pub(crate) fn build_mesh_bvh(&self, options: &BvhBuildOptions, cache_dir: Option<&Path>) {
+        if let ObjectType::Mesh(mesh) = &self.object_type {
+            mesh.build_bvh(options, cache_dir);
+        }
+    }
+
+    /// Returns the surface of this object where `ray` makes `hit`, as found by
+    /// `get_face_intersection`.
+    ///
+    /// `ray` is in world space coordinates.
+    pub(crate) fn get_surface_interaction(&self, ray: &Ray, hit: &Hit) -> SurfaceInteraction {
+        let point: Point3<f32> = ray.get_point_on_ray(hit.t).into();
+        let geometry = self.object_type.get_surface_geometry(
+            self.get_world_to_object().transform_point(point),
+            hit.face,
+            hit.vertex_weights,
+        );
+        // Normals are transformed by the inverse transpose so that they stay
+        // perpendicular to the surface when it is scaled unevenly.
+        let normal_to_world = self.get_world_to_object().transpose();
+        let mut geometric_normal = normal_to_world
+            .transform_vector(geometry.geometric_normal)
+            .normalize();
+        let mut shading_normal = normal_to_world
+            .transform_vector(geometry.shading_normal)
+            .normalize();
+        // Backs of two-sided faces and insides of spheres are shaded as if they were
+        // the front, so both normals are flipped to face the ray.
+        let front_face = ray.get_direction().dot(geometric_normal) < 0.0;
+        if !front_face {
+            geometric_normal = -geometric_normal;
+            shading_normal = -shading_normal;
+        }
+        SurfaceInteraction {
+            t: hit.t,
+            point,
+            geometric_normal,
+            shading_normal,
+            uv: geometry.uv,
+            dpdu: self.get_object_to_world().transform_vector(geometry.dpdu),
+            dpdv: self.get_object_to_world().transform_vector(geometry.dpdv),
+            front_face,
+            primitive_id: hit.face,
+        }
+    }
+
+    /// Returns the color of the object where `incoming_ray` hits it at `interaction`.
     ///
     /// All arguments are in world space coordinates.
     pub fn get_color(
         &self,
         incoming_ray: &Ray,
-        t: f32,
+        interaction: &SurfaceInteraction,
         lights: Vec<&Light>,
         world: &World,
         max_depth: u16,
     ) -> Color {
         self.material
-            .get_color(&incoming_ray, t, self, lights, world, max_depth)
+            .get_color(incoming_ray, interaction, lights, world, max_depth)
     }
 
-    /// Returns the uv texture coordinates of the object at `point`.
+    /// Returns `bsdf * cos(theta)` where `incoming_ray` hits this object at
+    /// `interaction`, for light arriving from `dir_to_light`.
     ///
-    /// `point` is in world space coordinates.
-    pub fn get_uv(&self, point: Point3<f32>) -> Point2<f32> {
-        let point = self.get_world_to_object().transform_point(point);
-        match self.object_type {
-            ObjectType::Sphere(center, _) => {
-                let (x, y, z) = (point - center).normalize().into();
-                let theta = z.acos();
-                let phi = y.atan2(x);
-                Point2 {
-                    x: theta / (2.0 * std::f32::consts::PI),
-                    y: phi / std::f32::consts::PI,
-                }
-            }
-            ObjectType::Quad(a, b, _c, d) => {
-                let u = (b - a).dot(point - a) / (b - a).magnitude2();
-                let v = (d - a).dot(point - a) / (d - a).magnitude2();
-                Point2 { x: u, y: v }
-            }
-            ObjectType::Triangle(a, b, c) => {
-                let u = (b - a).dot(point - a) / (b - a).magnitude2();
-                let v = (c - a).dot(point - a) / (c - a).magnitude2();
-                Point2 { x: u, y: v }
-            }
-        }
+    /// All arguments are in world space coordinates.
+    pub fn eval_bsdf(
+        &self,
+        incoming_ray: &Ray,
+        interaction: &SurfaceInteraction,
+        dir_to_light: Vector3<f32>,
+    ) -> Vector3<f32> {
+        self.material
+            .eval_bsdf(incoming_ray, interaction, dir_to_light)
+    }
+
+    /// Samples the direction that `incoming_ray` continues in after hitting this object
+    /// at `interaction`.
+    ///
+    /// All arguments are in world space coordinates.
+    pub fn sample_bsdf<R: Rng>(
+        &self,
+        incoming_ray: &Ray,
+        interaction: &SurfaceInteraction,
+        rng: &mut R,
+    ) -> Option<BsdfSample> {
+        self.material.sample_bsdf(incoming_ray, interaction, rng)
+    }
+
+    /// Returns true if no light passes through this object.
+    pub fn is_opaque(&self) -> bool {
+        self.material.is_opaque()
+    }
+
+    /// Returns the fraction of each color channel of light that passes straight
+    /// through this object at `interaction`.
+    pub fn get_transmittance(&self, interaction: &SurfaceInteraction) -> Color {
+        self.material.get_transmittance(interaction)
+    }
+
+    /// Returns true if this object can be intersected in world space, skipping the
+    /// round trip through object space. Meshes always go through object space, since
+    /// their faces are shaded with the transform of the instance.
+    fn is_in_world_space(&self) -> bool {
+        !matches!(self.object_type, ObjectType::Mesh(_))
+            && self.object_to_world == Matrix4::identity()
     }
 
     fn get_object_to_world(&self) -> &Matrix4<f32> {
@@ -289,36 +967,55 @@ impl Object {
     /// in world space coordinates.
     pub fn get_bounding_box(&self) -> (Point3<f32>, Point3<f32>) {
         let object_to_world = self.get_object_to_world();
-        match self.object_type {
+        match *self.object_type.get_shape() {
             ObjectType::Sphere(center, radius) => {
                 let center = object_to_world.transform_point(center);
                 let radius: Vector3<f32> = radius * get_axis_scaling(object_to_world);
                 (center - radius, center + radius)
             }
             ObjectType::Quad(a, b, c, d) => {
-                let points = vec![a, b, c, d]
+                let points: Vec<Point3<f32>> = vec![a, b, c, d]
                     .into_iter()
                     .map(|point| object_to_world.transform_point(point))
                     .collect();
                 component_wise_range(&points)
             }
             ObjectType::Triangle(a, b, c) => {
-                let points = vec![a, b, c]
+                let points: Vec<Point3<f32>> = vec![a, b, c]
                     .into_iter()
                     .map(|point| object_to_world.transform_point(point))
                     .collect();
                 component_wise_range(&points)
             }
+            ObjectType::Mesh(ref mesh) => {
+                let (min, max) = mesh.get_bounding_box();
+                let corners: Vec<Point3<f32>> = (0..8)
+                    .map(|i| {
+                        let corner = Point3::new(
+                            if i & 1 == 0 { min.x } else { max.x },
+                            if i & 2 == 0 { min.y } else { max.y },
+                            if i & 4 == 0 { min.z } else { max.z },
+                        );
+                        object_to_world.transform_point(corner)
+                    })
+                    .collect();
+                component_wise_range(&corners)
+            }
+            ObjectType::MeshFace(..) => unreachable!(),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Object;
+    use super::{Object, ObjectType};
+    use crate::interaction::SurfaceInteraction;
     use crate::material::{Material, MaterialType, TextureType};
+    use crate::packet::{RayPacket, PACKET_SIZE};
     use crate::ray::Ray;
-    use cgmath::{InnerSpace, Point3};
+    use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point2, Point3, Vector3};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     #[test]
     fn test_sphere() {
@@ -346,8 +1043,10 @@ mod tests {
         assert!(quad.get_intersection(&ray).is_none());
         let ray = Ray::new((0.0, 1.0, 0.0).into(), (0.0, 0.0, 1.0).into());
         assert!(quad.get_intersection(&ray).is_none());
+        // Only two-sided quads are hit from behind.
         let ray = Ray::new((0.0, -1.0, 0.0).into(), (0.0, 1.0, 0.0).into());
-        assert!(quad.get_intersection(&ray).is_none());
+        assert!(quad.get_intersection(&ray).is_some());
+        assert!(quad.two_sided(false).get_intersection(&ray).is_none());
     }
 
     #[test]
@@ -371,9 +1070,277 @@ mod tests {
             m.clone(),
         );
         let ray = Ray::new((0.1, 0.1, 1.0).into(), (0.0, 0.0, -1.0).into());
-        assert!(triangle.get_intersection(&ray).is_none());
+        assert!(triangle.get_intersection(&ray).is_some());
         let ray = Ray::new((1.0, 1.0, -1.0).into(), (0.0, 0.0, 1.0).into());
         assert!(triangle.get_intersection(&ray).is_none());
+
+        // With back faces culled, rays only hit the side from which the vertices are
+        // in counterclockwise order.
+        let triangle = triangle.two_sided(false);
+        assert!(!triangle.is_two_sided());
+        let ray = Ray::new((0.1, 0.1, 1.0).into(), (0.0, 0.0, -1.0).into());
+        assert!(triangle.get_intersection(&ray).is_none());
+        let ray = Ray::new((0.1, 0.1, -1.0).into(), (0.0, 0.0, 1.0).into());
+        assert!(triangle.get_intersection(&ray).is_some());
+    }
+
+    #[test]
+    fn test_intersection_range() {
+        let m = Material::new(MaterialType::None, TextureType::None);
+        let sphere = Object::new_sphere((0.0, 0.0, 0.0).into(), 1.0, m.clone());
+        let ray = Ray::new((0.0, 0.0, 3.0).into(), (0.0, 0.0, -1.0).into());
+        assert_eq!(sphere.get_intersection(&ray), Some(2.0));
+        // Hits before `t_min` are skipped, so the far side of the sphere is hit.
+        let far_side = ray.clone().with_range(2.5, f32::INFINITY);
+        assert_eq!(sphere.get_intersection(&far_side), Some(4.0));
+        assert!(sphere.get_intersection(&ray.with_range(0.0, 1.5)).is_none());
+
+        // The range is in world space even when the object is scaled.
+        let triangle = Object::new_triangle(
+            (0.0, 0.0, 0.0).into(),
+            (1.0, 0.0, 0.0).into(),
+            (0.0, 1.0, 0.0).into(),
+            m,
+        )
+        .transform(Matrix4::from_scale(2.0));
+        let ray = Ray::new((0.5, 0.5, 4.0).into(), (0.0, 0.0, -1.0).into());
+        assert_eq!(triangle.get_intersection(&ray), Some(4.0));
+        assert!(triangle
+            .get_intersection(&ray.with_range(0.0, 3.0))
+            .is_none());
+    }
+
+    #[test]
+    fn test_shared_edges_and_vertices() {
+        // A fan of eight triangles around the origin, seen from +z.
+        let rim = [
+            (1.0, 0.0),
+            (1.0, 1.0),
+            (0.0, 1.0),
+            (-1.0, 1.0),
+            (-1.0, 0.0),
+            (-1.0, -1.0),
+            (0.0, -1.0),
+            (1.0, -1.0),
+        ];
+        let triangles: Vec<ObjectType> = (0..rim.len())
+            .map(|i| {
+                let (x0, y0) = rim[i];
+                let (x1, y1) = rim[(i + 1) % rim.len()];
+                ObjectType::Triangle(
+                    (0.0, 0.0, 0.0).into(),
+                    (x0, y0, 0.0).into(),
+                    (x1, y1, 0.0).into(),
+                )
+            })
+            .collect();
+        let num_hits = |ray: &Ray, two_sided| {
+            triangles
+                .iter()
+                .filter_map(|triangle| triangle.get_intersection(ray, two_sided))
+                .inspect(|hit| assert_eq!(hit.t, 1.0))
+                .count()
+        };
+        // A ray through a vertex hits every triangle around it, and a ray through an
+        // edge hits both triangles on either side of it, from either side if they are
+        // two-sided.
+        for &z in &[1.0, -1.0] {
+            let ray = |x, y| Ray::new((x, y, z).into(), (0.0, 0.0, -z).into());
+            assert_eq!(num_hits(&ray(0.0, 0.0), true), 8);
+            for &(x, y) in &rim {
+                assert_eq!(num_hits(&ray(0.5 * x, 0.5 * y), true), 2);
+            }
+        }
+        let ray = Ray::new((0.0, 0.0, 1.0).into(), (0.0, 0.0, -1.0).into());
+        assert_eq!(num_hits(&ray, false), 8);
+        // But one-sided triangles are not hit from behind.
+        let ray = Ray::new((0.0, 0.0, -1.0).into(), (0.0, 0.0, 1.0).into());
+        assert_eq!(num_hits(&ray, false), 0);
+    }
+
+    #[test]
+    fn test_watertight_fan() {
+        // An uneven fan of triangles that do not lie in a plane.
+        let center = Point3::new(0.3, -0.2, 0.05);
+        let rim: Vec<Point3<f32>> = (0..7)
+            .map(|i| {
+                let angle = i as f32 * 2.0 * std::f32::consts::PI / 7.0;
+                let radius = 1.0 + 0.1 * i as f32;
+                center + radius * cgmath::vec3(angle.cos(), angle.sin(), 0.02 * i as f32)
+            })
+            .collect();
+        let triangles: Vec<ObjectType> = (0..rim.len())
+            .map(|i| ObjectType::Triangle(center, rim[i], rim[(i + 1) % rim.len()]))
+            .collect();
+        let origin = Point3::new(-0.4, 0.7, 3.0);
+        let hits = |target: Point3<f32>| {
+            let ray = Ray::new(origin, target - origin);
+            let hits: Vec<_> = triangles
+                .iter()
+                .filter_map(|triangle| {
+                    triangle
+                        .get_intersection(&ray, false)
+                        .map(|hit| (triangle, hit))
+                })
+                .collect();
+            for &(triangle, hit) in &hits {
+                // The barycentric coordinates give the hit point.
+                let (a, b, c) = match *triangle {
+                    ObjectType::Triangle(a, b, c) => (a, b, c),
+                    _ => unreachable!(),
+                };
+                let [w0, w1, w2, w3] = hit.vertex_weights;
+                assert!(w0 >= 0.0 && w1 >= 0.0 && w2 >= 0.0 && w3 == 0.0);
+                assert!((w0 + w1 + w2 - 1.0).abs() < 1e-5);
+                let point: Point3<f32> = ray.get_point_on_ray(hit.t).into();
+                let interpolated = a.to_vec() * w0 + b.to_vec() * w1 + c.to_vec() * w2;
+                assert!((point.to_vec() - interpolated).magnitude() < 1e-4);
+            }
+            hits.len()
+        };
+        // Rays aimed at the shared vertex and along the shared edges never slip
+        // between the triangles.
+        assert!(hits(center) >= 1);
+        for &vertex in &rim {
+            for i in 1..200 {
+                let s = i as f32 / 200.0;
+                assert!(hits(center + s * (vertex - center)) >= 1);
+            }
+        }
+        let mut rng = StdRng::seed_from_u64(248);
+        for _ in 0..200 {
+            let i = rng.gen_range(0, rim.len());
+            let (b, c) = (rim[i], rim[(i + 1) % rim.len()]);
+            let (u, v) = (rng.gen_range(0.0, 0.5), rng.gen_range(0.0, 0.5));
+            assert!(hits(center + u * (b - center) + v * (c - center)) >= 1);
+        }
+    }
+
+    #[test]
+    fn test_packet_intersections() {
+        let shapes = [
+            ObjectType::Triangle(
+                (0.0, 0.0, 0.0).into(),
+                (1.0, 0.0, 0.0).into(),
+                (0.0, 1.0, 0.0).into(),
+            ),
+            ObjectType::Triangle(
+                (0.3, -0.2, 0.05).into(),
+                (1.2, 0.4, -0.3).into(),
+                (-0.5, 0.9, 0.6).into(),
+            ),
+            ObjectType::Quad(
+                (-1.0, 0.0, 1.0).into(),
+                (1.0, 0.0, 1.0).into(),
+                (1.0, 0.0, -1.0).into(),
+                (-1.0, 0.0, -1.0).into(),
+            ),
+        ];
+        // Rays along the axes, through vertices and edges, and in random directions,
+        // so that the lanes of a packet permute their axes differently.
+        let mut rays = vec![
+            Ray::new((0.0, 0.0, 1.0).into(), (0.0, 0.0, -1.0).into()),
+            Ray::new((0.5, 0.0, -1.0).into(), (0.0, 0.0, 1.0).into()),
+            Ray::new((0.5, 0.5, 1.0).into(), (0.0, 0.0, -1.0).into()),
+            Ray::new((0.0, 1.0, 0.0).into(), (0.0, -1.0, 0.0).into()),
+            Ray::new((1.0, -1.0, 1.0).into(), (-1.0, 1.0, 0.0).into()),
+            Ray::new((0.2, 0.2, 1.0).into(), (0.0, 0.0, -1.0).into()).with_range(0.0, 0.5),
+        ];
+        let mut rng = StdRng::seed_from_u64(316);
+        // The last packet is only partly full.
+        for _ in 0..253 {
+            let origin = Point3::new(
+                rng.gen_range(-2.0, 2.0),
+                rng.gen_range(-2.0, 2.0),
+                rng.gen_range(-2.0, 2.0),
+            );
+            let target = Point3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), 0.0);
+            rays.push(Ray::new(origin, target - origin));
+        }
+        for shape in &shapes {
+            for &two_sided in &[true, false] {
+                for rays in rays.chunks(PACKET_SIZE) {
+                    let packet = RayPacket::new(rays);
+                    let hits = shape.get_intersections(&packet, two_sided);
+                    for (lane, hit) in hits.iter().enumerate() {
+                        let expected = rays
+                            .get(lane)
+                            .and_then(|ray| shape.get_intersection(ray, two_sided));
+                        assert_eq!(*hit, expected);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the surface of `object` where `ray` hits it, if it does.
+    fn get_surface_interaction(object: &Object, ray: &Ray) -> Option<SurfaceInteraction> {
+        object
+            .get_face_intersection(ray)
+            .map(|hit| object.get_surface_interaction(ray, &hit))
+    }
+
+    #[test]
+    fn test_surface_interaction() {
+        let m = Material::new(MaterialType::None, TextureType::None);
+        let (a, b, c, d) = (
+            (-1.0, 0.0, 1.0).into(),
+            (1.0, 0.0, 1.0).into(),
+            (1.0, 0.0, -1.0).into(),
+            (-1.0, 0.0, -1.0).into(),
+        );
+        let quad = Object::new_quad(a, b, c, d, m.clone());
+        let triangle = Object::new_triangle(a, b, c, m.clone());
+        let close = |v: Vector3<f32>, expected: (f32, f32, f32)| {
+            (v - Vector3::from(expected)).magnitude() < 1e-5
+        };
+        let uv_close = |object: &Object, point: Point3<f32>, expected: (f32, f32)| {
+            let ray = Ray::new(point + Vector3::unit_y(), -Vector3::unit_y());
+            let uv = get_surface_interaction(object, &ray).unwrap().uv;
+            (uv - Point2::from(expected)).magnitude() < 1e-5
+        };
+        assert!(uv_close(&quad, a, (0.0, 0.0)));
+        assert!(uv_close(&quad, c, (1.0, 1.0)));
+        assert!(uv_close(&quad, d, (0.0, 1.0)));
+        assert!(uv_close(&quad, (0.5, 0.0, -0.5).into(), (0.75, 0.75)));
+        assert!(uv_close(&triangle, b, (1.0, 0.0)));
+        assert!(uv_close(&triangle, (0.0, 0.0, 0.0).into(), (0.0, 0.5)));
+
+        let ray = Ray::new((0.5, 1.0, -0.5).into(), (0.0, -1.0, 0.0).into());
+        let interaction = get_surface_interaction(&quad, &ray).unwrap();
+        assert_eq!(interaction.t, 1.0);
+        assert_eq!(interaction.point, (0.5, 0.0, -0.5).into());
+        assert!(close(interaction.geometric_normal, (0.0, 1.0, 0.0)));
+        assert_eq!(interaction.shading_normal, interaction.geometric_normal);
+        // u runs from `a` to `b`, and v from `a` to `d`.
+        assert!(close(interaction.dpdu, (2.0, 0.0, 0.0)));
+        assert!(close(interaction.dpdv, (0.0, 0.0, -2.0)));
+        assert!(interaction.front_face);
+        assert_eq!(interaction.primitive_id, 0);
+
+        // The back of the quad is shaded with its normals flipped to face the ray.
+        let ray = Ray::new((0.5, -1.0, -0.5).into(), (0.0, 1.0, 0.0).into());
+        let interaction = get_surface_interaction(&quad, &ray).unwrap();
+        assert!(close(interaction.geometric_normal, (0.0, -1.0, 0.0)));
+        assert_eq!(interaction.shading_normal, interaction.geometric_normal);
+        assert!(close(interaction.get_outward_normal(), (0.0, 1.0, 0.0)));
+        assert!(!interaction.front_face);
+        assert!(get_surface_interaction(&quad.two_sided(false), &ray).is_none());
+
+        // Rays from inside a sphere hit its back.
+        let sphere = Object::new_sphere((0.0, 0.0, 0.0).into(), 1.0, m)
+            .transform(Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0));
+        let ray = Ray::new((0.0, 0.0, 0.0).into(), (1.0, 1.0, 0.0).into());
+        let interaction = get_surface_interaction(&sphere, &ray).unwrap();
+        assert!(!interaction.front_face);
+        // The normals stay perpendicular to the surface even though it is scaled unevenly.
+        let normal = interaction.geometric_normal;
+        assert!((normal.magnitude() - 1.0).abs() < 1e-5);
+        assert!(normal.dot(interaction.dpdu).abs() < 1e-4);
+        assert!(normal.dot(interaction.dpdv).abs() < 1e-4);
+        assert!(normal.dot(interaction.point - Point3::new(0.0, 0.0, 0.0)) < 0.0);
+        let outward_normal = interaction.get_outward_normal();
+        assert!(outward_normal.dot(interaction.point - Point3::new(0.0, 0.0, 0.0)) > 0.0);
     }
 
     #[test]
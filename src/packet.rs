@@ -0,0 +1,178 @@
+use cgmath::{Point3, Vector3};
+
+use super::ray::Ray;
+
+/// The number of rays that are traced together in a `RayPacket`.
+pub const PACKET_SIZE: usize = 8;
+
+/// One value for each ray of a packet.
+pub(crate) type Lanes = [f32; PACKET_SIZE];
+
+/// Which rays of a packet something applies to.
+pub(crate) type Mask = [bool; PACKET_SIZE];
+
+/// Up to `PACKET_SIZE` rays stored as a structure of arrays, so that each step of an
+/// intersection test can be done for all of them at once.
+///
+/// Rays that start close together and point in similar directions, like those of
+/// neighbouring pixels, mostly visit the same nodes of a bvh, so a packet visits
+/// each node once for all of them. Tests are written as loops over the lanes of
+/// fixed size arrays, which the compiler turns into SIMD instructions.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct RayPacket {
+    pub(crate) position: [Lanes; 3],
+    pub(crate) direction: [Lanes; 3],
+    /// `1 / direction`, except that zero components are treated as tiny positive
+    /// ones so that slab tests need no special cases.
+    pub(crate) inverse_direction: [Lanes; 3],
+    pub(crate) t_min: Lanes,
+    pub(crate) t_max: Lanes,
+    /// The rays that tests should be done for. The lanes past the number of rays in
+    /// the packet are never active.
+    pub(crate) active: Mask,
+    len: usize,
+}
+
+impl RayPacket {
+    /// Creates a packet of `rays`, with every ray active.
+    ///
+    /// Panics if there are more than `PACKET_SIZE` rays.
+    pub(crate) fn new(rays: &[Ray]) -> Self {
+        assert!(
+            rays.len() <= PACKET_SIZE,
+            "A packet holds at most {} rays",
+            PACKET_SIZE
+        );
+        let mut packet = RayPacket {
+            position: [[0.0; PACKET_SIZE]; 3],
+            direction: [[0.0; PACKET_SIZE]; 3],
+            inverse_direction: [[0.0; PACKET_SIZE]; 3],
+            t_min: [0.0; PACKET_SIZE],
+            t_max: [0.0; PACKET_SIZE],
+            active: [false; PACKET_SIZE],
+            len: rays.len(),
+        };
+        for (lane, ray) in rays.iter().enumerate() {
+            let position: [f32; 3] = ray.get_point_on_ray(0.0).into();
+            let direction: [f32; 3] = ray.get_direction().into();
+            for axis in 0..3 {
+                packet.position[axis][lane] = position[axis];
+                packet.direction[axis][lane] = direction[axis];
+                let d = if direction[axis] == 0.0 {
+                    f32::MIN_POSITIVE
+                } else {
+                    direction[axis]
+                };
+                packet.inverse_direction[axis][lane] = 1.0 / d;
+            }
+            packet.t_min[lane] = ray.get_t_min();
+            packet.t_max[lane] = ray.get_t_max();
+            packet.active[lane] = true;
+        }
+        packet
+    }
+
+    /// Returns the number of rays in this packet, active or not.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns this packet with only the rays in `mask` active.
+    pub(crate) fn with_active(mut self, mask: Mask) -> Self {
+        self.active = lanes(|lane| self.active[lane] && mask[lane]);
+        self
+    }
+
+    /// Returns ray `lane` of this packet, with its current range.
+    pub(crate) fn get_ray(&self, lane: usize) -> Ray {
+        debug_assert!(lane < self.len);
+        Ray::from_parts(
+            Point3::new(
+                self.position[0][lane],
+                self.position[1][lane],
+                self.position[2][lane],
+            ),
+            Vector3::new(
+                self.direction[0][lane],
+                self.direction[1][lane],
+                self.direction[2][lane],
+            ),
+            self.t_min[lane],
+            self.t_max[lane],
+        )
+    }
+
+    /// Returns true for the rays that have `t` in their range.
+    pub(crate) fn is_in_range(&self, t: &Lanes) -> Mask {
+        lanes(|lane| self.t_min[lane] < t[lane] && t[lane] <= self.t_max[lane])
+    }
+
+    /// Returns the point `t` along each ray, as in `Ray::get_point_on_ray`.
+    pub(crate) fn get_point_on_ray(&self, t: &Lanes) -> [Lanes; 3] {
+        let mut point = self.position;
+        for (p, d) in point.iter_mut().zip(&self.direction) {
+            *p = lanes(|lane| p[lane] + t[lane] * d[lane]);
+        }
+        point
+    }
+}
+
+/// Returns `[f(0), f(1), ..]`, one value for each lane of a packet.
+#[inline(always)]
+pub(crate) fn lanes<T, F: FnMut(usize) -> T>(f: F) -> [T; PACKET_SIZE] {
+    std::array::from_fn(f)
+}
+
+/// Returns the dot product of `v` and `w` in each lane, summed in the same order as
+/// `InnerSpace::dot` so that packets get the same results as single rays.
+#[inline(always)]
+pub(crate) fn dot_lanes(v: &[Lanes; 3], w: &[Lanes; 3]) -> Lanes {
+    lanes(|lane| v[0][lane] * w[0][lane] + v[1][lane] * w[1][lane] + v[2][lane] * w[2][lane])
+}
+
+/// Returns `v - p` in each lane.
+#[inline(always)]
+pub(crate) fn sub_from(v: Point3<f32>, p: &[Lanes; 3]) -> [Lanes; 3] {
+    [
+        lanes(|lane| v.x - p[0][lane]),
+        lanes(|lane| v.y - p[1][lane]),
+        lanes(|lane| v.z - p[2][lane]),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RayPacket, PACKET_SIZE};
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_ray_packet() {
+        let rays: Vec<Ray> = (0..3)
+            .map(|i| {
+                Ray::new(
+                    (i as f32, 0.0, 1.0).into(),
+                    (0.1 * i as f32, 0.3, -1.0).into(),
+                )
+                .with_range(0.5, 2.0 + i as f32)
+            })
+            .collect();
+        let packet = RayPacket::new(&rays);
+        assert_eq!(packet.len(), 3);
+        assert_eq!(packet.active.iter().filter(|&&active| active).count(), 3);
+        for (lane, ray) in rays.iter().enumerate() {
+            let lane_ray = packet.get_ray(lane);
+            assert_eq!(lane_ray.get_point_on_ray(1.5), ray.get_point_on_ray(1.5));
+            assert_eq!(lane_ray.get_direction(), ray.get_direction());
+            assert_eq!(lane_ray.get_t_max(), ray.get_t_max());
+        }
+        // The x direction of the first ray is zero.
+        assert!(packet.inverse_direction[0][0].is_finite());
+
+        let mut mask = [true; PACKET_SIZE];
+        mask[1] = false;
+        assert_eq!(
+            &packet.with_active(mask).active[..4],
+            &[true, false, true, false]
+        );
+    }
+}
@@ -1,25 +1,71 @@
 use cgmath::{InnerSpace, Transform};
 use cgmath::{Matrix4, Point3, Vector3};
 
+/// A ray that only hits objects at `t` in the range `(t_min, t_max]`.
 #[derive(Debug, Clone)]
 pub struct Ray {
     position: Point3<f32>,
     direction: Vector3<f32>,
+    t_min: f32,
+    t_max: f32,
 }
 
 impl Ray {
+    /// Creates a ray that hits anything in front of `position`.
     pub fn new(position: Point3<f32>, direction: Vector3<f32>) -> Ray {
         Ray {
             position,
             direction: direction.normalize(),
+            t_min: 0.0,
+            t_max: f32::INFINITY,
         }
     }
 
+    /// Creates a ray from parts that are already valid, without normalizing
+    /// `direction` again so that the ray is exactly the one they came from.
+    pub(crate) fn from_parts(
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+        t_min: f32,
+        t_max: f32,
+    ) -> Ray {
+        Ray {
+            position,
+            direction,
+            t_min,
+            t_max,
+        }
+    }
+
+    /// Returns this ray restricted to hits at `t` in `(t_min, t_max]`.
+    pub fn with_range(self, t_min: f32, t_max: f32) -> Ray {
+        debug_assert!(t_min <= t_max);
+        Ray {
+            t_min,
+            t_max,
+            ..self
+        }
+    }
+
+    pub fn get_t_min(&self) -> f32 {
+        self.t_min
+    }
+
+    pub fn get_t_max(&self) -> f32 {
+        self.t_max
+    }
+
+    /// Returns true if a hit at `t` is within the range of this ray.
+    pub fn is_in_range(&self, t: f32) -> bool {
+        self.t_min < t && t <= self.t_max
+    }
+
+    /// Transforms the ray, scaling its range so that it still covers the same points.
     pub fn transform_using(&self, transform: &Matrix4<f32>) -> Ray {
-        Ray::new(
-            transform.transform_point(self.position),
-            transform.transform_vector(self.direction),
-        )
+        let direction = transform.transform_vector(self.direction);
+        let scale = direction.magnitude();
+        Ray::new(transform.transform_point(self.position), direction)
+            .with_range(self.t_min * scale, self.t_max * scale)
     }
 
     pub fn get_point_on_ray(&self, t: f32) -> (f32, f32, f32) {
@@ -39,9 +85,32 @@ impl Ray {
     /// Move the ray forward by `epsilon` units.
     ///
     /// Useful if we want to make sure that the new ray does not
-    /// intersect an object at its origin.
+    /// intersect an object at its origin. The range of the ray is
+    /// shifted so that it ends at the same point.
     pub fn offset(self, epsilon: f32) -> Ray {
         let position = self.position + epsilon * self.direction;
-        Ray::new(position, self.direction)
+        let t_min = (self.t_min - epsilon).max(0.0);
+        let t_max = (self.t_max - epsilon).max(t_min);
+        Ray::new(position, self.direction).with_range(t_min, t_max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ray;
+    use cgmath::Matrix4;
+
+    #[test]
+    fn test_ray_range() {
+        let ray = Ray::new((0.0, 0.0, 0.0).into(), (0.0, 0.0, 1.0).into()).with_range(1.0, 2.0);
+        assert!(!ray.is_in_range(0.5));
+        assert!(ray.is_in_range(1.5));
+        assert!(!ray.is_in_range(2.5));
+
+        let ray = ray.offset(0.5);
+        assert_eq!((ray.get_t_min(), ray.get_t_max()), (0.5, 1.5));
+
+        let scaled = ray.transform_using(&Matrix4::from_scale(2.0));
+        assert_eq!((scaled.get_t_min(), scaled.get_t_max()), (1.0, 3.0));
     }
 }
@@ -1,8 +1,8 @@
-use cgmath::{Deg, Matrix4, SquareMatrix};
+use cgmath::{Deg, Matrix4, MetricSpace, Point3, SquareMatrix};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 
-use super::camera::Camera;
+use super::camera::{Camera, DEFAULT_VERTICAL_FOV};
 use super::color::Color;
 use super::light::Light;
 use super::material::{Material, MaterialType, TextureType};
@@ -206,21 +206,34 @@ pub fn load_suzanne() -> (Vec<Object>, Vec<Light>) {
         ),
     )
     .unwrap();
-    for triangle in mesh {
-        objects.push(triangle);
-    }
+    objects.push(mesh);
 
     (objects, vec![])
 }
 
-/// Creates a camera in a default location with a square viewport
-/// with side length `pixel_width`.
-pub fn default_camera(pixel_width: u32) -> Camera {
+/// Returns the objects and lights of the built-in scene called `name`, if it exists.
+pub fn load_builtin(name: &str) -> Option<(Vec<Object>, Vec<Light>)> {
+    match name {
+        "basic" => Some(load_basic()),
+        "suzanne" => Some(load_suzanne()),
+        "random_spheres" => Some(load_random_spheres(1000)),
+        _ => None,
+    }
+}
+
+/// Creates a pinhole camera in a default location with a viewport of
+/// `pixel_width` by `pixel_height` pixels.
+pub fn default_camera(pixel_width: u32, pixel_height: u32) -> Camera {
+    let eye = Point3::new(0.0, 1.5, 5.0);
+    let at = Point3::new(0.0, 0.0, 0.0);
     Camera::new(
         pixel_width,
-        pixel_width,
-        (0.0, 1.5, 5.0).into(),
-        (0.0, 0.0, 0.0).into(),
+        pixel_height,
+        eye,
+        at,
         (0.0, 1.0, 0.0).into(),
+        DEFAULT_VERTICAL_FOV,
+        0.0,
+        eye.distance(at),
     )
 }
@@ -0,0 +1,615 @@
+use cgmath::{Deg, Matrix4, MetricSpace, Point3, SquareMatrix};
+use serde::Deserialize;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::camera::{Camera, Projection, DEFAULT_VERTICAL_FOV};
+use super::color::Color;
+use super::light::Light;
+use super::material::{Material, MaterialType, TextureType};
+use super::mesh::Mesh;
+use super::object::Object;
+use super::scene::default_camera;
+
+/// Everything needed to render a scene loaded from a scene file.
+pub struct Scene {
+    pub camera: Camera,
+    pub objects: Vec<Object>,
+    pub lights: Vec<Light>,
+    pub background_color: Color,
+}
+
+#[derive(Deserialize)]
+struct SceneDescription {
+    #[serde(default = "default_background")]
+    background: [f32; 3],
+    camera: Option<CameraDescription>,
+    #[serde(default)]
+    textures: HashMap<String, TextureDescription>,
+    #[serde(default)]
+    materials: HashMap<String, MaterialDescription>,
+    #[serde(default)]
+    objects: Vec<ObjectDescription>,
+    #[serde(default)]
+    lights: Vec<LightDescription>,
+}
+
+fn default_background() -> [f32; 3] {
+    [0.2, 0.2, 0.2]
+}
+
+#[derive(Deserialize)]
+struct CameraDescription {
+    eye: [f32; 3],
+    at: [f32; 3],
+    #[serde(default = "default_up")]
+    up: [f32; 3],
+    /// The vertical field of view in degrees.
+    #[serde(default = "default_fov")]
+    fov: f32,
+    #[serde(default)]
+    projection: ProjectionDescription,
+    #[serde(default)]
+    aperture: f32,
+    /// Defaults to the distance from `eye` to `at`.
+    focus_distance: Option<f32>,
+}
+
+fn default_up() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_fov() -> f32 {
+    DEFAULT_VERTICAL_FOV.0
+}
+
+#[derive(Deserialize, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ProjectionDescription {
+    #[default]
+    Perspective,
+    /// `height` defaults to the height a perspective view covers at the focus distance.
+    Orthographic {
+        height: Option<f32>,
+    },
+    Equirectangular,
+    Fisheye,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TextureDescription {
+    Image { path: PathBuf },
+    Flat { color: [f32; 3] },
+}
+
+#[derive(Deserialize)]
+struct MaterialDescription {
+    #[serde(flatten)]
+    material_type: MaterialTypeDescription,
+    /// The name of an entry in `textures`.
+    texture: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MaterialTypeDescription {
+    Phong {
+        diffuse: f32,
+        specular: f32,
+        shininess: f32,
+    },
+    Reflective,
+    Refractive {
+        index: f32,
+    },
+    Composition {
+        components: Vec<ComponentDescription>,
+    },
+    None,
+}
+
+#[derive(Deserialize)]
+struct ComponentDescription {
+    weight: f32,
+    #[serde(flatten)]
+    material_type: MaterialTypeDescription,
+}
+
+#[derive(Deserialize)]
+struct ObjectDescription {
+    #[serde(flatten)]
+    shape: ShapeDescription,
+    /// The name of an entry in `materials`.
+    material: String,
+    #[serde(default)]
+    transform: Vec<TransformDescription>,
+    /// Whether triangles and quads are hit from behind. See `Object::two_sided`.
+    #[serde(default = "default_two_sided")]
+    two_sided: bool,
+}
+
+fn default_two_sided() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ShapeDescription {
+    Sphere { center: [f32; 3], radius: f32 },
+    Triangle { vertices: [[f32; 3]; 3] },
+    Quad { vertices: [[f32; 3]; 4] },
+    Mesh { path: PathBuf },
+}
+
+/// A single step of an object's transform. Angles are in degrees.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TransformDescription {
+    Translate([f32; 3]),
+    Scale([f32; 3]),
+    RotateX(f32),
+    RotateY(f32),
+    RotateZ(f32),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LightDescription {
+    Ambient {
+        color: [f32; 3],
+    },
+    Point {
+        position: [f32; 3],
+        color: [f32; 3],
+    },
+    Directional {
+        direction: [f32; 3],
+        color: [f32; 3],
+    },
+    Cone {
+        position: [f32; 3],
+        direction: [f32; 3],
+        angle: f32,
+        color: [f32; 3],
+    },
+    Rectangle {
+        corner: [f32; 3],
+        edges: [[f32; 3]; 2],
+        color: [f32; 3],
+        #[serde(default = "default_light_samples")]
+        samples: u16,
+    },
+    Disk {
+        center: [f32; 3],
+        normal: [f32; 3],
+        radius: f32,
+        color: [f32; 3],
+        #[serde(default = "default_light_samples")]
+        samples: u16,
+    },
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+        color: [f32; 3],
+        #[serde(default = "default_light_samples")]
+        samples: u16,
+    },
+}
+
+fn default_light_samples() -> u16 {
+    16
+}
+
+impl MaterialTypeDescription {
+    fn build(&self) -> MaterialType {
+        match self {
+            MaterialTypeDescription::Phong {
+                diffuse,
+                specular,
+                shininess,
+            } => MaterialType::new_phong(*diffuse, *specular, *shininess),
+            MaterialTypeDescription::Reflective => MaterialType::Reflective,
+            MaterialTypeDescription::Refractive { index } => MaterialType::Refractive(*index),
+            MaterialTypeDescription::Composition { components } => MaterialType::Composition(
+                components
+                    .iter()
+                    .map(|component| (component.material_type.build(), component.weight))
+                    .collect(),
+            ),
+            MaterialTypeDescription::None => MaterialType::None,
+        }
+    }
+}
+
+impl TransformDescription {
+    fn build(&self) -> Matrix4<f32> {
+        match self {
+            TransformDescription::Translate(v) => Matrix4::from_translation((*v).into()),
+            TransformDescription::Scale([x, y, z]) => Matrix4::from_nonuniform_scale(*x, *y, *z),
+            TransformDescription::RotateX(angle) => Matrix4::from_angle_x(Deg(*angle)),
+            TransformDescription::RotateY(angle) => Matrix4::from_angle_y(Deg(*angle)),
+            TransformDescription::RotateZ(angle) => Matrix4::from_angle_z(Deg(*angle)),
+        }
+    }
+}
+
+impl LightDescription {
+    fn build(&self) -> Result<Light, Box<dyn Error>> {
+        // Area lights average their samples, so they need at least one.
+        if let LightDescription::Rectangle { samples: 0, .. }
+        | LightDescription::Disk { samples: 0, .. }
+        | LightDescription::Sphere { samples: 0, .. } = self
+        {
+            return Err("Area lights need at least one sample".into());
+        }
+        let light = match self {
+            LightDescription::Ambient { color } => Light::new_ambient((*color).into()),
+            LightDescription::Point { position, color } => {
+                Light::new_point((*position).into(), (*color).into())
+            }
+            LightDescription::Directional { direction, color } => {
+                Light::new_directional((*direction).into(), (*color).into())
+            }
+            LightDescription::Cone {
+                position,
+                direction,
+                angle,
+                color,
+            } => Light::new_cone(
+                (*position).into(),
+                (*direction).into(),
+                Deg(*angle),
+                (*color).into(),
+            ),
+            LightDescription::Rectangle {
+                corner,
+                edges: [edge_u, edge_v],
+                color,
+                samples,
+            } => Light::new_rectangle(
+                (*corner).into(),
+                (*edge_u).into(),
+                (*edge_v).into(),
+                (*color).into(),
+                *samples,
+            ),
+            LightDescription::Disk {
+                center,
+                normal,
+                radius,
+                color,
+                samples,
+            } => Light::new_disk(
+                (*center).into(),
+                (*normal).into(),
+                *radius,
+                (*color).into(),
+                *samples,
+            ),
+            LightDescription::Sphere {
+                center,
+                radius,
+                color,
+                samples,
+            } => Light::new_sphere((*center).into(), *radius, (*color).into(), *samples),
+        };
+        Ok(light)
+    }
+}
+
+/// Returns an error unless `fov`, in degrees, is a valid vertical field of view for a
+/// camera, which must be below 360 degrees for a fisheye lens and below 180 for other
+/// projections. Used for cameras from scene files and for values given on the
+/// command line.
+pub fn check_fov(fov: f32, is_fisheye: bool) -> Result<(), Box<dyn Error>> {
+    // Only a fisheye lens can see behind the camera.
+    let max_fov = if is_fisheye { 360.0 } else { 180.0 };
+    if fov <= 0.0 || fov >= max_fov {
+        return Err(format!("Camera fov must be between 0 and {}, got {}", max_fov, fov).into());
+    }
+    Ok(())
+}
+
+/// Returns an error unless a camera with `aperture` and `focus_distance` can be
+/// built. Used for cameras from scene files and for values given on the command line.
+pub fn check_lens(aperture: f32, focus_distance: f32) -> Result<(), Box<dyn Error>> {
+    if aperture < 0.0 || focus_distance <= 0.0 {
+        return Err(
+            "Camera aperture must not be negative and focus_distance must be positive".into(),
+        );
+    }
+    Ok(())
+}
+
+/// Loads a scene from a TOML scene file, see `scenes/basic.toml` for an example.
+///
+/// Relative paths to textures and meshes are resolved from the directory
+/// containing the scene file. If the file has no camera, `default_camera` is used.
+pub fn load_scene_file<P>(
+    path: P,
+    pixel_width: u32,
+    pixel_height: u32,
+) -> Result<Scene, Box<dyn Error>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    parse_scene(&contents, base_dir, pixel_width, pixel_height)
+}
+
+fn parse_scene(
+    contents: &str,
+    base_dir: &Path,
+    pixel_width: u32,
+    pixel_height: u32,
+) -> Result<Scene, Box<dyn Error>> {
+    let description: SceneDescription = toml::from_str(contents)?;
+
+    let camera = match description.camera {
+        Some(camera) => {
+            let eye = Point3::from(camera.eye);
+            let at = Point3::from(camera.at);
+            let focus_distance = camera.focus_distance.unwrap_or_else(|| eye.distance(at));
+            let is_fisheye = matches!(camera.projection, ProjectionDescription::Fisheye);
+            check_fov(camera.fov, is_fisheye)?;
+            check_lens(camera.aperture, focus_distance)?;
+            let mut new_camera = Camera::new(
+                pixel_width,
+                pixel_height,
+                eye,
+                at,
+                camera.up.into(),
+                Deg(camera.fov),
+                camera.aperture,
+                focus_distance,
+            );
+            new_camera.projection = match camera.projection {
+                ProjectionDescription::Perspective => Projection::Perspective,
+                ProjectionDescription::Orthographic { height } => Projection::Orthographic(
+                    height.unwrap_or_else(|| new_camera.get_view_height_at(focus_distance)),
+                ),
+                ProjectionDescription::Equirectangular => Projection::Equirectangular,
+                ProjectionDescription::Fisheye => Projection::Fisheye,
+            };
+            new_camera
+        }
+        None => default_camera(pixel_width, pixel_height),
+    };
+
+    let mut textures = HashMap::new();
+    for (name, texture) in &description.textures {
+        let texture = match texture {
+            TextureDescription::Image { path } => TextureType::new_texture(base_dir.join(path))?,
+            TextureDescription::Flat { color } => TextureType::new_flat((*color).into()),
+        };
+        textures.insert(name.as_str(), texture);
+    }
+
+    let mut materials = HashMap::new();
+    for (name, material) in &description.materials {
+        let texture_type = match &material.texture {
+            Some(texture) => textures
+                .get(texture.as_str())
+                .ok_or_else(|| format!("Material {} uses unknown texture {}", name, texture))?
+                .clone(),
+            None => TextureType::None,
+        };
+        let material = Material::new(material.material_type.build(), texture_type);
+        materials.insert(name.as_str(), material);
+    }
+
+    let mut objects = vec![];
+    let mut meshes = HashMap::new();
+    for object in &description.objects {
+        let material = materials
+            .get(object.material.as_str())
+            .ok_or_else(|| format!("Object uses unknown material {}", object.material))?
+            .clone();
+        // Transforms are applied in the order they are listed.
+        let object_to_world = object
+            .transform
+            .iter()
+            .fold(Matrix4::identity(), |acc, transform| {
+                transform.build() * acc
+            });
+        match &object.shape {
+            ShapeDescription::Sphere { center, radius } => {
+                let sphere = Object::new_sphere((*center).into(), *radius, material);
+                objects.push(
+                    sphere
+                        .transform(object_to_world)
+                        .two_sided(object.two_sided),
+                );
+            }
+            ShapeDescription::Triangle {
+                vertices: [a, b, c],
+            } => {
+                let triangle =
+                    Object::new_triangle((*a).into(), (*b).into(), (*c).into(), material);
+                objects.push(
+                    triangle
+                        .transform(object_to_world)
+                        .two_sided(object.two_sided),
+                );
+            }
+            ShapeDescription::Quad {
+                vertices: [a, b, c, d],
+            } => {
+                let quad =
+                    Object::new_quad((*a).into(), (*b).into(), (*c).into(), (*d).into(), material);
+                objects.push(quad.transform(object_to_world).two_sided(object.two_sided));
+            }
+            ShapeDescription::Mesh { path } => {
+                // Objects that load the same file are instances of a single mesh.
+                let mesh = match meshes.entry(base_dir.join(path)) {
+                    Entry::Occupied(entry) => Arc::clone(entry.get()),
+                    Entry::Vacant(entry) => {
+                        let mesh = Arc::new(Mesh::load(entry.key())?);
+                        Arc::clone(entry.insert(mesh))
+                    }
+                };
+                let instance = Object::new_instance(mesh, object_to_world, material);
+                objects.push(instance.two_sided(object.two_sided));
+            }
+        }
+    }
+
+    let lights = description
+        .lights
+        .iter()
+        .map(|light| light.build())
+        .collect::<Result<_, _>>()?;
+
+    Ok(Scene {
+        camera,
+        objects,
+        lights,
+        background_color: description.background.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_fov, check_lens, load_scene_file, parse_scene};
+    use crate::camera::Projection;
+    use cgmath::Deg;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_scene() {
+        let contents = r#"
+            background = [0.0, 0.0, 0.0]
+
+            [camera]
+            eye = [0.0, 1.0, 5.0]
+            at = [0.0, 0.0, 0.0]
+            fov = 60.0
+            projection = { type = "orthographic", height = 8.0 }
+
+            [textures]
+            red = { type = "flat", color = [1.0, 0.0, 0.0] }
+
+            [materials]
+            matte = { type = "phong", diffuse = 1.0, specular = 0.0, shininess = 1.0, texture = "red" }
+
+            [materials.glass]
+            type = "composition"
+            components = [
+                { weight = 0.8, type = "refractive", index = 1.3 },
+                { weight = 0.2, type = "reflective" },
+            ]
+
+            [[objects]]
+            type = "sphere"
+            center = [0.0, 0.0, 0.0]
+            radius = 1.0
+            material = "glass"
+            transform = [{ scale = [2.0, 2.0, 2.0] }, { translate = [0.0, 1.0, 0.0] }]
+
+            [[objects]]
+            type = "triangle"
+            vertices = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]
+            material = "matte"
+            two_sided = false
+
+            [[lights]]
+            type = "point"
+            position = [0.0, 5.0, 0.0]
+            color = [1.0, 1.0, 1.0]
+
+            [[lights]]
+            type = "cone"
+            position = [0.0, 5.0, 0.0]
+            direction = [0.0, -1.0, 0.0]
+            angle = 30.0
+            color = [1.0, 1.0, 1.0]
+
+            [[lights]]
+            type = "rectangle"
+            corner = [-1.0, 5.0, -1.0]
+            edges = [[2.0, 0.0, 0.0], [0.0, 0.0, 2.0]]
+            color = [1.0, 1.0, 1.0]
+            samples = 4
+        "#;
+        let scene = parse_scene(contents, Path::new("."), 100, 50).unwrap();
+        assert_eq!(scene.objects.len(), 2);
+        assert_eq!(scene.lights.len(), 3);
+        assert_eq!(scene.lights[2].samples, 4);
+        assert_eq!(scene.camera.width, 100);
+        assert_eq!(scene.camera.height, 50);
+        assert_eq!(scene.camera.vertical_fov, Deg(60.0));
+        assert_eq!(scene.camera.projection, Projection::Orthographic(8.0));
+
+        let (min, max) = scene.objects[0].get_bounding_box();
+        assert_eq!(min, (-2.0, -1.0, -2.0).into());
+        assert_eq!(max, (2.0, 3.0, 2.0).into());
+        assert!(scene.objects[0].is_two_sided());
+        assert!(!scene.objects[1].is_two_sided());
+    }
+
+    #[test]
+    fn test_parse_scene_errors() {
+        let unknown_material = r#"
+            [[objects]]
+            type = "sphere"
+            center = [0.0, 0.0, 0.0]
+            radius = 1.0
+            material = "missing"
+        "#;
+        assert!(parse_scene(unknown_material, Path::new("."), 100, 50).is_err());
+
+        let unknown_shape = r#"
+            [materials]
+            none = { type = "none" }
+
+            [[objects]]
+            type = "cylinder"
+            material = "none"
+        "#;
+        assert!(parse_scene(unknown_shape, Path::new("."), 100, 50).is_err());
+
+        let no_light_samples = r#"
+            [[lights]]
+            type = "sphere"
+            center = [0.0, 5.0, 0.0]
+            radius = 1.0
+            color = [1.0, 1.0, 1.0]
+            samples = 0
+        "#;
+        assert!(parse_scene(no_light_samples, Path::new("."), 100, 50).is_err());
+
+        let negative_aperture = r#"
+            [camera]
+            eye = [0.0, 1.0, 5.0]
+            at = [0.0, 0.0, 0.0]
+            aperture = -1.0
+        "#;
+        assert!(parse_scene(negative_aperture, Path::new("."), 100, 50).is_err());
+        assert!(check_lens(0.0, 1.0).is_ok());
+        assert!(check_fov(0.0, false).is_err());
+        assert!(check_fov(200.0, false).is_err());
+        assert!(check_fov(200.0, true).is_ok());
+        assert!(check_lens(0.5, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_load_example_scenes() {
+        let scene = load_scene_file("scenes/basic.toml", 10, 10).unwrap();
+        assert_eq!(scene.objects.len(), 5);
+        assert_eq!(scene.lights.len(), 5);
+
+        // Both objects are instances of the same mesh.
+        let scene = load_scene_file("scenes/suzanne.toml", 10, 10).unwrap();
+        assert_eq!(scene.objects.len(), 2);
+
+        let scene = load_scene_file("scenes/soft_shadows.toml", 10, 10).unwrap();
+        assert_eq!(scene.lights.len(), 3);
+    }
+}
@@ -0,0 +1,97 @@
+//! Scenes and rays shared by the tests of the accelerators and bvhs.
+
+use cgmath::{Matrix4, Vector3};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::Arc;
+
+use super::material::{Material, MaterialType, TextureType};
+use super::mesh::Mesh;
+use super::object::Object;
+use super::ray::Ray;
+use super::scene::load_random_spheres;
+
+/// Random spheres, with a few instances of a mesh and some large triangles that
+/// overlap many cells and nodes among them.
+pub(crate) fn objects() -> Vec<Object> {
+    let mut rng = StdRng::seed_from_u64(248);
+    let m = Material::new(MaterialType::None, TextureType::None);
+    let mut objects = load_random_spheres(300).0;
+    let mesh = Arc::new(Mesh::load("media/Suzanne.obj").unwrap());
+    for _ in 0..3 {
+        let position = (rng.gen_range(-5.0, 5.0), 1.0, rng.gen_range(-5.0, 5.0));
+        let transform = Matrix4::from_translation(position.into());
+        objects.push(Object::new_instance(
+            Arc::clone(&mesh),
+            transform,
+            m.clone(),
+        ));
+    }
+    for _ in 0..20 {
+        let mut point = || {
+            (
+                rng.gen_range(-8.0, 8.0),
+                rng.gen_range(0.0, 3.0),
+                rng.gen_range(-8.0, 8.0),
+            )
+                .into()
+        };
+        objects.push(Object::new_triangle(point(), point(), point(), m.clone()));
+    }
+    // Shapes with transforms of their own.
+    let transform = Matrix4::from_translation((2.0, 1.0, -3.0).into())
+        * Matrix4::from_nonuniform_scale(1.0, 0.5, 2.0);
+    objects.push(Object::new_sphere((0.0, 0.0, 0.0).into(), 0.8, m.clone()).transform(transform));
+    objects.push(
+        Object::new_quad(
+            (-1.0, 0.0, 1.0).into(),
+            (1.0, 0.0, 1.0).into(),
+            (1.0, 0.0, -1.0).into(),
+            (-1.0, 0.0, -1.0).into(),
+            m,
+        )
+        .transform(Matrix4::from_translation((-3.0, 2.0, 2.0).into())),
+    );
+    objects
+}
+
+/// Returns batches of rays from above the scene and from inside it, some with a
+/// limited range and some parallel to the slabs of an axis. The rays of a batch start close together and point in similar
+/// directions, like the camera rays of neighbouring pixels.
+pub(crate) fn ray_batches() -> Vec<Vec<Ray>> {
+    let mut rng = StdRng::seed_from_u64(248);
+    (0..100)
+        .map(|i| {
+            let position = (
+                rng.gen_range(-10.0, 10.0),
+                rng.gen_range(0.5, 5.0),
+                rng.gen_range(-10.0, 10.0),
+            );
+            let direction = Vector3::new(
+                rng.gen_range(-1.0, 1.0),
+                rng.gen_range(-1.0, 0.5),
+                rng.gen_range(-1.0, 1.0),
+            );
+            // Batches that do not fill a packet too.
+            (0..(5 + i % 7))
+                .map(|_| {
+                    let spread = Vector3::new(
+                        rng.gen_range(-0.05, 0.05),
+                        rng.gen_range(-0.05, 0.05),
+                        rng.gen_range(-0.05, 0.05),
+                    );
+                    let mut direction = direction + spread;
+                    if i % 5 == 0 {
+                        direction[i % 3] = 0.0;
+                    }
+                    let ray = Ray::new(position.into(), direction);
+                    if i % 3 == 0 {
+                        ray.with_range(rng.gen_range(0.0, 2.0), rng.gen_range(2.0, 8.0))
+                    } else {
+                        ray
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
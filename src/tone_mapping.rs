@@ -0,0 +1,158 @@
+use std::str::FromStr;
+
+use super::color::Color;
+use super::utils::clamp;
+
+/// Compresses linear colors of any brightness into the [0, 1] range of a display.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapping {
+    /// Clips colors brighter than white.
+    Clamp,
+    /// `c / (1 + c)`, which keeps detail in highlights but washes out colors.
+    Reinhard,
+    /// An approximation of the ACES filmic curve by Krzysztof Narkowicz.
+    Aces,
+}
+
+impl ToneMapping {
+    fn apply(self, x: f32) -> f32 {
+        match self {
+            ToneMapping::Clamp => x,
+            ToneMapping::Reinhard => x / (1.0 + x),
+            ToneMapping::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        }
+    }
+}
+
+impl FromStr for ToneMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(ToneMapping::Clamp),
+            "reinhard" => Ok(ToneMapping::Reinhard),
+            "aces" => Ok(ToneMapping::Aces),
+            _ => Err(format!("Unknown tone mapping {}", s)),
+        }
+    }
+}
+
+/// The transfer function used to encode display colors into 8-bit values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Stores the linear values directly.
+    Linear,
+    /// The sRGB opto-electronic transfer function expected by most displays and viewers.
+    Srgb,
+}
+
+impl Encoding {
+    fn apply(self, x: f32) -> f32 {
+        match self {
+            Encoding::Linear => x,
+            Encoding::Srgb => {
+                if x <= 0.003_130_8 {
+                    12.92 * x
+                } else {
+                    1.055 * x.powf(1.0 / 2.4) - 0.055
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Encoding::Linear),
+            "srgb" => Ok(Encoding::Srgb),
+            _ => Err(format!("Unknown encoding {}", s)),
+        }
+    }
+}
+
+/// Converts linear rendered colors into 8-bit colors for LDR images.
+///
+/// Colors are scaled by the exposure, tone mapped, and then encoded, in that order.
+#[derive(Debug, Clone, Copy)]
+pub struct DisplayTransform {
+    /// Exposure adjustment in stops. Each stop doubles the brightness.
+    pub exposure: f32,
+    pub tone_mapping: ToneMapping,
+    pub encoding: Encoding,
+}
+
+impl Default for DisplayTransform {
+    fn default() -> Self {
+        DisplayTransform {
+            exposure: 0.0,
+            tone_mapping: ToneMapping::Clamp,
+            encoding: Encoding::Srgb,
+        }
+    }
+}
+
+impl DisplayTransform {
+    /// Returns the 8-bit red, green, and blue channels to display for `color`.
+    pub fn apply(&self, color: Color) -> [u8; 3] {
+        let scale = self.exposure.exp2();
+        let (r, g, b) = color.get_rgb_f32();
+        let transform = |x: f32| {
+            let x = self.tone_mapping.apply(scale * x);
+            let x = self.encoding.apply(clamp(x, 0.0, 1.0));
+            (x * 255.0).round() as u8
+        };
+        [transform(r), transform(g), transform(b)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DisplayTransform, Encoding, ToneMapping};
+    use crate::color::Color;
+
+    fn new_transform(
+        exposure: f32,
+        tone_mapping: ToneMapping,
+        encoding: Encoding,
+    ) -> DisplayTransform {
+        DisplayTransform {
+            exposure,
+            tone_mapping,
+            encoding,
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let linear = new_transform(0.0, ToneMapping::Clamp, Encoding::Linear);
+        assert_eq!(linear.apply(Color::rgb(0.0, 0.5, 2.0)), [0, 128, 255]);
+        let srgb = new_transform(0.0, ToneMapping::Clamp, Encoding::Srgb);
+        assert_eq!(srgb.apply(Color::rgb(0.0, 0.5, 1.0)), [0, 188, 255]);
+        assert_eq!(srgb.apply(Color::grayscale(0.002)), [7, 7, 7]);
+    }
+
+    #[test]
+    fn test_exposure() {
+        let brighter = new_transform(1.0, ToneMapping::Clamp, Encoding::Linear);
+        assert_eq!(brighter.apply(Color::rgb(0.25, 0.5, 1.0)), [128, 255, 255]);
+        let darker = new_transform(-2.0, ToneMapping::Clamp, Encoding::Linear);
+        assert_eq!(darker.apply(Color::rgb(2.0, 4.0, 8.0)), [128, 255, 255]);
+    }
+
+    #[test]
+    fn test_tone_mapping() {
+        let reinhard = new_transform(0.0, ToneMapping::Reinhard, Encoding::Linear);
+        assert_eq!(reinhard.apply(Color::rgb(0.0, 1.0, 3.0)), [0, 128, 191]);
+
+        // Unlike clamping, tone mapping keeps bright colors distinguishable.
+        for tone_mapping in [ToneMapping::Reinhard, ToneMapping::Aces] {
+            let transform = new_transform(0.0, tone_mapping, Encoding::Srgb);
+            let [dark, bright, brighter] = transform.apply(Color::rgb(0.0, 1.0, 4.0));
+            assert_eq!(dark, 0);
+            assert!(bright < brighter && brighter < 255);
+        }
+    }
+}
@@ -1,4 +1,7 @@
 use cgmath::{Bounded, InnerSpace, Matrix4, Point3, Transform, Vector3};
+use rand::Rng;
+use std::f32::consts::PI;
+use std::hash::Hasher;
 
 /// Clamps a value x to be in the range (low, high)
 // `f32.clamp` is nightly-only :(
@@ -17,7 +20,7 @@ pub fn reflect(v: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
 }
 
 /// Returns the (min, max) of each dimension for the collection of points.
-pub fn component_wise_range(points: &Vec<Point3<f32>>) -> (Point3<f32>, Point3<f32>) {
+pub fn component_wise_range(points: &[Point3<f32>]) -> (Point3<f32>, Point3<f32>) {
     debug_assert!(!points.is_empty());
     let min = points.iter().fold(Point3::<f32>::max_value(), |a, b| {
         (f32::min(a.x, b.x), f32::min(a.y, b.y), f32::min(a.z, b.z)).into()
@@ -58,10 +61,79 @@ pub fn refract(v: Vector3<f32>, normal: Vector3<f32>, refraction_index: f32) ->
     (v * n + (n * cos_theta_in - cos_theta_out) * normal).normalize()
 }
 
+/// Returns two unit vectors that, together with the unit vector `n`, form an
+/// orthonormal basis.
+pub fn orthonormal_basis(n: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    debug_assert!(f32::abs(n.magnitude() - 1.) < 1e-4);
+    // Cross with whichever axis is least parallel to `n`.
+    let axis = if n.x.abs() < 0.9 {
+        Vector3::unit_x()
+    } else {
+        Vector3::unit_y()
+    };
+    let u = n.cross(axis).normalize();
+    let v = n.cross(u);
+    (u, v)
+}
+
+/// Returns a uniformly distributed random point `(x, y)` on a disk of the given
+/// `radius` centered at the origin.
+pub fn sample_disk<R: Rng>(radius: f32, rng: &mut R) -> (f32, f32) {
+    let r = radius * rng.gen::<f32>().sqrt();
+    let theta = 2.0 * PI * rng.gen::<f32>();
+    (r * theta.cos(), r * theta.sin())
+}
+
+/// Returns a direction around the unit vector `axis` whose angle `alpha` to the
+/// axis is distributed with density proportional to `cos(alpha)^exponent`.
+///
+/// The pdf of the returned direction is `(exponent + 1) / (2 * PI) * cos(alpha)^exponent`.
+pub fn sample_cosine_power<R: Rng>(axis: Vector3<f32>, exponent: f32, rng: &mut R) -> Vector3<f32> {
+    let (u, v) = orthonormal_basis(axis);
+    let cos_alpha = rng.gen::<f32>().powf(1. / (exponent + 1.));
+    let sin_alpha = (1. - cos_alpha * cos_alpha).max(0.).sqrt();
+    let phi = 2. * PI * rng.gen::<f32>();
+    (sin_alpha * phi.cos() * u + sin_alpha * phi.sin() * v + cos_alpha * axis).normalize()
+}
+
+/// Returns a cosine weighted direction in the hemisphere around the unit vector `normal`.
+///
+/// The pdf of the returned direction is `cos(theta) / PI`.
+pub fn sample_cosine_hemisphere<R: Rng>(normal: Vector3<f32>, rng: &mut R) -> Vector3<f32> {
+    sample_cosine_power(normal, 1., rng)
+}
+
+/// The 64-bit FNV-1a hash. Unlike `DefaultHasher`, its output is stable between
+/// builds, which cache keys need.
+pub struct Fnv1aHasher(u64);
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Fnv1aHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1aHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{clamp, component_wise_range, get_axis_scaling, reflect};
-    use cgmath::{assert_abs_diff_eq, Deg, Matrix4, MetricSpace, Transform, Vector3};
+    use super::{
+        clamp, component_wise_range, get_axis_scaling, orthonormal_basis, reflect,
+        sample_cosine_power,
+    };
+    use cgmath::{assert_abs_diff_eq, Deg, InnerSpace, Matrix4, MetricSpace, Transform, Vector3};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     #[test]
     fn test_clamp() {
@@ -106,4 +178,39 @@ mod tests {
         let scale = Matrix4::from_nonuniform_scale(10., -0.5, 1.);
         assert_abs_diff_eq!(get_axis_scaling(&scale), (10., 0.5, 1.).into());
     }
+
+    #[test]
+    fn test_orthonormal_basis() {
+        let normals: Vec<Vector3<f32>> = vec![
+            Vector3::unit_x(),
+            Vector3::unit_y(),
+            -Vector3::unit_z(),
+            Vector3::new(1., 2., 3.).normalize(),
+        ];
+        for n in normals {
+            let (u, v) = orthonormal_basis(n);
+            assert_abs_diff_eq!(u.magnitude(), 1., epsilon = 1e-5);
+            assert_abs_diff_eq!(v.magnitude(), 1., epsilon = 1e-5);
+            assert_abs_diff_eq!(u.dot(v), 0., epsilon = 1e-5);
+            assert_abs_diff_eq!(u.dot(n), 0., epsilon = 1e-5);
+            assert_abs_diff_eq!(v.dot(n), 0., epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_sample_cosine_power() {
+        let mut rng = StdRng::seed_from_u64(248);
+        let axis = Vector3::new(1., -1., 0.5).normalize();
+        for exponent in &[1., 10., 1000.] {
+            let mean_cos = (0..1000)
+                .map(|_| sample_cosine_power(axis, *exponent, &mut rng))
+                .inspect(|direction| assert!(direction.dot(axis) >= 0.))
+                .map(|direction| direction.dot(axis))
+                .sum::<f32>()
+                / 1000.;
+            // The expected value of cos(alpha) is (exponent + 1) / (exponent + 2).
+            let expected = (exponent + 1.) / (exponent + 2.);
+            assert_abs_diff_eq!(mean_cos, expected, epsilon = 0.02);
+        }
+    }
 }
@@ -0,0 +1,615 @@
+use super::bvh::{LinearBvh, LinearBvhNodeType};
+use super::packet::{self, Lanes, Mask, RayPacket, PACKET_SIZE};
+use super::ray::Ray;
+
+/// A bvh whose nodes have up to `N` children, made by collapsing the levels of a
+/// binary `LinearBvh`. Rays visit several levels of the binary tree at once, and the
+/// bounding boxes of all the children of a node are tested together.
+///
+/// The boxes of the children are stored as a structure of arrays, one array of `N`
+/// values for each side of the boxes, so that the slab test for all of them is a few
+/// loops over the children that the compiler turns into SIMD instructions.
+///
+/// The primitives of leaves are those of the binary tree, so the wide tree refers to
+/// its `primitive_indices`.
+pub(crate) struct WideBvh<const N: usize> {
+    /// The root is the first node.
+    nodes: Vec<WideBvhNode<N>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum WideBvhChild {
+    Node(usize),
+    /// Refers to the primitives `primitives_offset..primitives_offset + num_primitives`
+    /// of the binary tree.
+    Leaf {
+        primitives_offset: usize,
+        num_primitives: usize,
+    },
+}
+
+/// A node of a `WideBvh`. Only the first `num_children` children are used.
+#[derive(Debug, Copy, Clone)]
+struct WideBvhNode<const N: usize> {
+    /// `min[axis][i]` is the minimum of the bounding box of child `i` along `axis`.
+    min: [[f32; N]; 3],
+    max: [[f32; N]; 3],
+    children: [WideBvhChild; N],
+    num_children: usize,
+}
+
+impl<const N: usize> WideBvhNode<N> {
+    /// Returns which children `ray` hits within its range, along with the `t` at which
+    /// it enters each of them. The results are the same as `AABB::get_ray_range` for
+    /// the bounding box of each child.
+    #[inline(always)]
+    fn intersect_children(&self, ray: &RayData) -> ([bool; N], [f32; N]) {
+        let mut is_inside: [bool; N] = std::array::from_fn(|child| child < self.num_children);
+        let mut t_near = [ray.t_min; N];
+        let mut t_far = [ray.t_max; N];
+        for axis in 0..3 {
+            let (min, max) = (&self.min[axis], &self.max[axis]);
+            let (x, d) = (ray.position[axis], ray.direction[axis]);
+            if d == 0.0 {
+                // The ray is parallel to the slabs of this axis.
+                for child in 0..N {
+                    is_inside[child] &= min[child] <= x && x <= max[child];
+                }
+            } else {
+                for child in 0..N {
+                    let t0 = (min[child] - x) / d;
+                    let t1 = (max[child] - x) / d;
+                    t_near[child] = t_near[child].max(t0.min(t1));
+                    t_far[child] = t_far[child].min(t0.max(t1));
+                }
+            }
+        }
+        let hits = std::array::from_fn(|child| is_inside[child] && t_near[child] <= t_far[child]);
+        (hits, t_near)
+    }
+
+    /// Returns which rays of `packet` in `mask` hit each child within their ranges, as
+    /// `AABB::intersect_packet` would, along with the `t` at which each enters it.
+    ///
+    /// Every child is tested against every ray in the same loops, so that the compiler
+    /// can keep all `N * PACKET_SIZE` slab tests in SIMD registers.
+    #[inline(always)]
+    fn intersect_children_packet(
+        &self,
+        packet: &RayPacket,
+        mask: &Mask,
+    ) -> ([Mask; N], [Lanes; N]) {
+        let mut t_near = [packet.t_min; N];
+        let mut t_far = [packet.t_max; N];
+        for axis in 0..3 {
+            let (min, max) = (&self.min[axis], &self.max[axis]);
+            let position = &packet.position[axis];
+            let inverse_direction = &packet.inverse_direction[axis];
+            for child in 0..N {
+                for lane in 0..PACKET_SIZE {
+                    let t0 = (min[child] - position[lane]) * inverse_direction[lane];
+                    let t1 = (max[child] - position[lane]) * inverse_direction[lane];
+                    t_near[child][lane] = t_near[child][lane].max(t0.min(t1));
+                    t_far[child][lane] = t_far[child][lane].min(t0.max(t1));
+                }
+            }
+        }
+        let hits = std::array::from_fn(|child| {
+            packet::lanes(|lane| {
+                child < self.num_children && mask[lane] && t_near[child][lane] <= t_far[child][lane]
+            })
+        });
+        (hits, t_near)
+    }
+}
+
+/// The parts of a ray that the slab test needs, as arrays that can be indexed by axis.
+struct RayData {
+    position: [f32; 3],
+    direction: [f32; 3],
+    t_min: f32,
+    t_max: f32,
+}
+
+impl RayData {
+    fn new(ray: &Ray) -> Self {
+        RayData {
+            position: ray.get_point_on_ray(0.0).into(),
+            direction: ray.get_direction().into(),
+            t_min: ray.get_t_min(),
+            t_max: ray.get_t_max(),
+        }
+    }
+}
+
+impl<const N: usize> WideBvh<N> {
+    /// Collapses `bvh` into a tree with up to `N` children per node.
+    ///
+    /// Starting from the children of a binary node, the interior child with the
+    /// largest surface area is repeatedly replaced by its own two children until
+    /// there are `N` of them or all of them are leaves. Rays are most likely to hit
+    /// large nodes, so those are the ones worth testing together with their siblings.
+    pub(crate) fn new(bvh: &LinearBvh) -> Self {
+        assert!(N >= 2, "A wide bvh needs at least 2 children per node");
+        let mut wide = WideBvh { nodes: vec![] };
+        if !bvh.nodes.is_empty() {
+            wide.add_node(bvh, 0);
+        }
+        wide
+    }
+
+    /// Adds a node whose children are the descendants of the binary node at `index`
+    /// chosen as described in `new`, and the subtrees under them, and returns the
+    /// index of the new node.
+    fn add_node(&mut self, bvh: &LinearBvh, index: usize) -> usize {
+        let get_children = |index: usize| match bvh.nodes[index].node_type {
+            LinearBvhNodeType::Interior {
+                second_child_offset,
+                ..
+            } => Some((index + 1, second_child_offset)),
+            LinearBvhNodeType::Leaf { .. } => None,
+        };
+        // A binary tree with a single leaf becomes a node with that leaf as its only
+        // child.
+        let mut binary_children = vec![index];
+        while binary_children.len() < N {
+            let largest = binary_children
+                .iter()
+                .enumerate()
+                .filter(|(_, &child)| get_children(child).is_some())
+                .max_by(|(_, &a), (_, &b)| {
+                    let area = |child: usize| bvh.nodes[child].aabb.surface_area();
+                    area(a).total_cmp(&area(b))
+                })
+                .map(|(i, _)| i);
+            match largest {
+                Some(i) => {
+                    let (first, second) = get_children(binary_children[i]).unwrap();
+                    binary_children[i] = first;
+                    binary_children.insert(i + 1, second);
+                }
+                None => break,
+            }
+        }
+
+        // The children are filled in once their subtrees are added.
+        let node_index = self.nodes.len();
+        let mut node = WideBvhNode {
+            min: [[0.0; N]; 3],
+            max: [[0.0; N]; 3],
+            children: [WideBvhChild::Node(0); N],
+            num_children: binary_children.len(),
+        };
+        for (i, &child) in binary_children.iter().enumerate() {
+            let aabb = &bvh.nodes[child].aabb;
+            for axis in 0..3 {
+                node.min[axis][i] = aabb.min[axis];
+                node.max[axis][i] = aabb.max[axis];
+            }
+        }
+        self.nodes.push(node);
+        for (i, &child) in binary_children.iter().enumerate() {
+            self.nodes[node_index].children[i] = match bvh.nodes[child].node_type {
+                LinearBvhNodeType::Interior { .. } => WideBvhChild::Node(self.add_node(bvh, child)),
+                LinearBvhNodeType::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => WideBvhChild::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                },
+            };
+        }
+        node_index
+    }
+
+    /// Returns the number of nodes in this tree.
+    pub(crate) fn get_num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Like `LinearBvh::get_closest_intersection`, where `primitive_indices` are the
+    /// primitives of the leaves of the binary tree that this tree was collapsed from.
+    ///
+    /// The children that the ray hits are visited from the nearest to the farthest,
+    /// and are skipped once a closer hit has been found.
+    pub(crate) fn get_closest_intersection<F, H>(
+        &self,
+        primitive_indices: &[usize],
+        ray: &Ray,
+        mut intersect: F,
+    ) -> Option<(usize, f32, H)>
+    where
+        F: FnMut(usize, &Ray) -> Option<(f32, H)>,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut ray_data = RayData::new(ray);
+        let mut ray = ray.clone();
+        let mut closest: Option<(usize, f32, H)> = None;
+        // Children that still need to be visited, along with the `t` at which the ray
+        // enters them.
+        let mut stack = vec![(WideBvhChild::Node(0), ray_data.t_min)];
+        while let Some((child, t_near)) = stack.pop() {
+            if t_near > ray_data.t_max {
+                continue;
+            }
+            match child {
+                WideBvhChild::Node(index) => {
+                    let (hits, t_near) = self.nodes[index].intersect_children(&ray_data);
+                    let mut order: [usize; N] = std::array::from_fn(|child| child);
+                    let num_hits = partition(&mut order, |&child| hits[child]);
+                    // Push the farthest child first so that the nearest is visited first.
+                    order[..num_hits].sort_unstable_by(|&a, &b| t_near[b].total_cmp(&t_near[a]));
+                    let children = &self.nodes[index].children;
+                    stack.extend(order[..num_hits].iter().map(|&i| (children[i], t_near[i])));
+                }
+                WideBvhChild::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => {
+                    let primitives = primitives_offset..primitives_offset + num_primitives;
+                    for &primitive in &primitive_indices[primitives] {
+                        if let Some((t, hit)) = intersect(primitive, &ray) {
+                            if closest
+                                .as_ref()
+                                .is_none_or(|(_, closest_t, _)| t < *closest_t)
+                            {
+                                closest = Some((primitive, t, hit));
+                                ray = ray.with_range(ray_data.t_min, t);
+                                ray_data.t_max = t;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    /// Like `LinearBvh::get_closest_intersections`, where `primitive_indices` are the
+    /// primitives of the leaves of the binary tree that this tree was collapsed from.
+    ///
+    /// All the children of a node are tested against the whole packet at once. The
+    /// children that any ray hits are visited from the one that the nearest ray enters
+    /// first, and are skipped once every ray that hit them has found a closer hit.
+    pub(crate) fn get_closest_intersections<F, H>(
+        &self,
+        primitive_indices: &[usize],
+        packet: &RayPacket,
+        mut intersect: F,
+    ) -> [Option<(usize, f32, H)>; PACKET_SIZE]
+    where
+        F: FnMut(usize, &RayPacket) -> [Option<(f32, H)>; PACKET_SIZE],
+    {
+        let mut packet = *packet;
+        let mut closest: [Option<(usize, f32, H)>; PACKET_SIZE] = packet::lanes(|_| None);
+        if self.nodes.is_empty() {
+            return closest;
+        }
+        // Children that still need to be visited, along with the rays that hit them
+        // and the `t` at which each of those enters them.
+        let mut stack = vec![(WideBvhChild::Node(0), packet.active, packet.t_min)];
+        while let Some((child, mask, t_near)) = stack.pop() {
+            let mask = packet::lanes(|lane| mask[lane] && t_near[lane] <= packet.t_max[lane]);
+            if !mask.contains(&true) {
+                continue;
+            }
+            match child {
+                WideBvhChild::Node(index) => {
+                    let node = &self.nodes[index];
+                    let (hits, t_near) = node.intersect_children_packet(&packet, &mask);
+                    // The `t` at which the nearest ray enters each child, or infinity
+                    // if no ray hits it.
+                    let nearest: [f32; N] = std::array::from_fn(|child| {
+                        (0..PACKET_SIZE).fold(f32::INFINITY, |nearest, lane| {
+                            if hits[child][lane] {
+                                nearest.min(t_near[child][lane])
+                            } else {
+                                nearest
+                            }
+                        })
+                    });
+                    let mut order: [usize; N] = std::array::from_fn(|child| child);
+                    let num_hits = partition(&mut order, |&child| hits[child].contains(&true));
+                    // Push the farthest child first so that the nearest is visited first.
+                    order[..num_hits].sort_unstable_by(|&a, &b| nearest[b].total_cmp(&nearest[a]));
+                    stack.extend(
+                        order[..num_hits]
+                            .iter()
+                            .map(|&i| (node.children[i], hits[i], t_near[i])),
+                    );
+                }
+                WideBvhChild::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => {
+                    let primitives = primitives_offset..primitives_offset + num_primitives;
+                    let mut leaf_packet = packet.with_active(mask);
+                    for &primitive in &primitive_indices[primitives] {
+                        let hits = intersect(primitive, &leaf_packet);
+                        for (lane, hit) in IntoIterator::into_iter(hits).enumerate() {
+                            if let Some((t, hit)) = hit {
+                                if closest[lane]
+                                    .as_ref()
+                                    .is_none_or(|(_, closest_t, _)| t < *closest_t)
+                                {
+                                    closest[lane] = Some((primitive, t, hit));
+                                    packet.t_max[lane] = t;
+                                    leaf_packet.t_max[lane] = t;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    /// Like `LinearBvh::is_occluded`, where `primitive_indices` are the primitives of
+    /// the leaves of the binary tree that this tree was collapsed from.
+    pub(crate) fn is_occluded<F>(
+        &self,
+        primitive_indices: &[usize],
+        ray: &Ray,
+        mut intersects: F,
+    ) -> bool
+    where
+        F: FnMut(usize, &Ray) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let ray_data = RayData::new(ray);
+        let mut stack = vec![WideBvhChild::Node(0)];
+        while let Some(child) = stack.pop() {
+            match child {
+                WideBvhChild::Node(index) => {
+                    let node = &self.nodes[index];
+                    let (hits, _) = node.intersect_children(&ray_data);
+                    for (i, &child) in node.children.iter().enumerate().rev() {
+                        if hits[i] {
+                            stack.push(child);
+                        }
+                    }
+                }
+                WideBvhChild::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => {
+                    let primitives = primitives_offset..primitives_offset + num_primitives;
+                    if primitive_indices[primitives]
+                        .iter()
+                        .any(|&primitive| intersects(primitive, ray))
+                    {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Moves the elements of `values` for which `predicate` is true to the front, keeping
+/// their order, and returns how many there are.
+fn partition<T: Copy, P: Fn(&T) -> bool>(values: &mut [T], predicate: P) -> usize {
+    let mut count = 0;
+    for i in 0..values.len() {
+        if predicate(&values[i]) {
+            values.swap(count, i);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// A `LinearBvh` collapsed into a tree of one of the supported widths. See
+/// `BvhBuildOptions::width`.
+pub(crate) enum WideTree {
+    Four(WideBvh<4>),
+    Eight(WideBvh<8>),
+}
+
+impl WideTree {
+    /// Collapses `bvh` into a tree with up to `width` children per node, or returns
+    /// `None` for a width of 2, where the binary tree is used as it is.
+    ///
+    /// Panics if `width` is not 2, 4, or 8.
+    pub(crate) fn new(bvh: &LinearBvh, width: usize) -> Option<Self> {
+        match width {
+            2 => None,
+            4 => Some(WideTree::Four(WideBvh::new(bvh))),
+            8 => Some(WideTree::Eight(WideBvh::new(bvh))),
+            _ => panic!("bvh width must be 2, 4, or 8, not {}", width),
+        }
+    }
+
+    pub(crate) fn get_width(&self) -> usize {
+        match self {
+            WideTree::Four(_) => 4,
+            WideTree::Eight(_) => 8,
+        }
+    }
+
+    pub(crate) fn get_num_nodes(&self) -> usize {
+        match self {
+            WideTree::Four(tree) => tree.get_num_nodes(),
+            WideTree::Eight(tree) => tree.get_num_nodes(),
+        }
+    }
+
+    /// See `WideBvh::get_closest_intersection`.
+    pub(crate) fn get_closest_intersection<F, H>(
+        &self,
+        primitive_indices: &[usize],
+        ray: &Ray,
+        intersect: F,
+    ) -> Option<(usize, f32, H)>
+    where
+        F: FnMut(usize, &Ray) -> Option<(f32, H)>,
+    {
+        match self {
+            WideTree::Four(tree) => {
+                tree.get_closest_intersection(primitive_indices, ray, intersect)
+            }
+            WideTree::Eight(tree) => {
+                tree.get_closest_intersection(primitive_indices, ray, intersect)
+            }
+        }
+    }
+
+    /// See `WideBvh::get_closest_intersections`.
+    pub(crate) fn get_closest_intersections<F, H>(
+        &self,
+        primitive_indices: &[usize],
+        packet: &RayPacket,
+        intersect: F,
+    ) -> [Option<(usize, f32, H)>; PACKET_SIZE]
+    where
+        F: FnMut(usize, &RayPacket) -> [Option<(f32, H)>; PACKET_SIZE],
+    {
+        match self {
+            WideTree::Four(tree) => {
+                tree.get_closest_intersections(primitive_indices, packet, intersect)
+            }
+            WideTree::Eight(tree) => {
+                tree.get_closest_intersections(primitive_indices, packet, intersect)
+            }
+        }
+    }
+
+    /// See `WideBvh::is_occluded`.
+    pub(crate) fn is_occluded<F>(
+        &self,
+        primitive_indices: &[usize],
+        ray: &Ray,
+        intersects: F,
+    ) -> bool
+    where
+        F: FnMut(usize, &Ray) -> bool,
+    {
+        match self {
+            WideTree::Four(tree) => tree.is_occluded(primitive_indices, ray, intersects),
+            WideTree::Eight(tree) => tree.is_occluded(primitive_indices, ray, intersects),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WideBvh;
+    use crate::bvh::{BvhBuildOptions, LinearBvh, LinearBvhNodeType, SplitType};
+    use crate::object::Object;
+    use crate::packet::{self, RayPacket, PACKET_SIZE};
+    use crate::ray::Ray;
+    use crate::test_scenes::{objects, ray_batches};
+
+    /// Checks that every node of `wide` has at most `N` children, and that its leaves
+    /// are exactly those of `binary`.
+    fn assert_collapsed<const N: usize>(binary: &LinearBvh, wide: &WideBvh<N>) {
+        let mut leaves = vec![];
+        for node in &wide.nodes {
+            assert!(node.num_children >= 1 && node.num_children <= N);
+            for child in &node.children[..node.num_children] {
+                if let super::WideBvhChild::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } = *child
+                {
+                    leaves.push((primitives_offset, num_primitives));
+                }
+            }
+        }
+        let mut binary_leaves: Vec<(usize, usize)> = binary
+            .nodes
+            .iter()
+            .filter_map(|node| match node.node_type {
+                LinearBvhNodeType::Leaf {
+                    primitives_offset,
+                    num_primitives,
+                } => Some((primitives_offset, num_primitives)),
+                LinearBvhNodeType::Interior { .. } => None,
+            })
+            .collect();
+        leaves.sort_unstable();
+        binary_leaves.sort_unstable();
+        assert_eq!(leaves, binary_leaves);
+        // Each wide node replaces at least one binary interior node.
+        assert!(wide.nodes.len() < binary.nodes.len().max(2));
+    }
+
+    #[test]
+    fn test_wide_bvh_matches_binary() {
+        let objects = objects();
+        let ray_batches = ray_batches();
+        let rays: Vec<Ray> = ray_batches.concat();
+
+        for &split_type in &[SplitType::SAH, SplitType::Spatial] {
+            let options = |width| BvhBuildOptions {
+                split_type,
+                width,
+                ..Default::default()
+            };
+            let binary = LinearBvh::new(objects.clone(), &options(2));
+            assert_collapsed(&binary, &WideBvh::<4>::new(&binary));
+            assert_collapsed(&binary, &WideBvh::<8>::new(&binary));
+            for &width in &[4, 8] {
+                let wide = LinearBvh::new(objects.clone(), &options(width));
+                let mut num_hits = 0;
+                for ray in &rays {
+                    let closest = |bvh: &LinearBvh| {
+                        bvh.get_closest_intersection(ray, |index, ray| {
+                            objects[index]
+                                .get_face_intersection(ray)
+                                .map(|hit| (hit.t, hit.face))
+                        })
+                    };
+                    let expected = closest(&binary);
+                    assert_eq!(closest(&wide), expected);
+                    num_hits += expected.is_some() as usize;
+
+                    let is_occluded = |bvh: &LinearBvh| {
+                        bvh.is_occluded(ray, |index, ray| objects[index].intersects(ray))
+                    };
+                    assert_eq!(is_occluded(&wide), is_occluded(&binary));
+                }
+                assert!(num_hits > 200);
+
+                for rays in ray_batches.iter().flat_map(|rays| rays.chunks(PACKET_SIZE)) {
+                    let hits =
+                        wide.get_closest_intersections(&RayPacket::new(rays), |index, packet| {
+                            let hits = objects[index].get_face_intersections(packet);
+                            packet::lanes(|lane| hits[lane].map(|hit| (hit.t, hit.face)))
+                        });
+                    for (ray, hit) in rays.iter().zip(&hits) {
+                        let expected = binary.get_closest_intersection(ray, |index, ray| {
+                            objects[index]
+                                .get_face_intersection(ray)
+                                .map(|hit| (hit.t, hit.face))
+                        });
+                        assert_eq!(*hit, expected);
+                    }
+                }
+            }
+        }
+
+        // A tree that is a single leaf, and an empty one.
+        let single = LinearBvh::new(objects[..2].to_vec(), &BvhBuildOptions::default());
+        let wide = WideBvh::<8>::new(&single);
+        assert_collapsed(&single, &wide);
+        assert_eq!(wide.nodes.len(), 1);
+        assert_eq!(wide.nodes[0].num_children, 1);
+        let options = BvhBuildOptions {
+            width: 8,
+            ..Default::default()
+        };
+        let empty = LinearBvh::new(Vec::<Object>::new(), &options);
+        assert!(empty
+            .get_closest_intersection(&rays[0], |_, _| Some((1.0, ())))
+            .is_none());
+        assert!(!empty.is_occluded(&rays[0], |_, _| true));
+    }
+}
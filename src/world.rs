@@ -1,132 +1,529 @@
-use cgmath::{Point3, Vector4};
-use image;
+use cgmath::{ElementWise, Vector3, Vector4, Zero};
+use rand::Rng;
+use std::borrow::Cow;
 use std::error::Error;
-use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::{mpsc, Arc};
 use threadpool::ThreadPool;
-use time;
 
-use super::bvh::Bvh;
+use super::accelerator::{Accelerator, AcceleratorType};
+use super::bvh::{BvhBuildOptions, BvhReport};
 use super::camera::Camera;
 use super::color::Color;
+use super::framebuffer::Framebuffer;
+use super::interaction::SurfaceInteraction;
 use super::light::Light;
 use super::object::Object;
+use super::packet::PACKET_SIZE;
 use super::ray::Ray;
 
-/// Render to a png file with the given filename.
-pub fn render<P>(
+/// The algorithm used to compute the color of a ray.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Integrator {
+    /// Whitted-style ray tracing: Phong shading from the lights plus perfect
+    /// reflection and refraction. See `World::trace_ray`.
+    Whitted,
+    /// Monte Carlo path tracing with global illumination. See `World::trace_path`.
+    PathTracing,
+}
+
+impl FromStr for Integrator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "whitted" => Ok(Integrator::Whitted),
+            "path" => Ok(Integrator::PathTracing),
+            _ => Err(format!("Unknown integrator {}", s)),
+        }
+    }
+}
+
+/// Settings that control how a scene is rendered.
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    /// The color of rays that hit nothing.
+    pub background_color: Color,
+    /// The number of rays to cast per pixel. Must not be zero.
+    pub samples_per_pixel: u16,
+    /// The maximum number of times to bounce a ray. Used for reflection and refraction.
+    pub max_ray_bounces: u16,
+    pub num_threads: usize,
+    pub integrator: Integrator,
+    pub accelerator: AcceleratorType,
+    pub bvh_build_options: BvhBuildOptions,
+    /// A directory to save built bvhs in and load them from. See `Bvh::with_cache`.
+    pub bvh_cache_dir: Option<PathBuf>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            background_color: Color::grayscale(0.2),
+            samples_per_pixel: 1,
+            max_ray_bounces: 4,
+            num_threads: 4,
+            integrator: Integrator::Whitted,
+            accelerator: AcceleratorType::Bvh,
+            bvh_build_options: BvhBuildOptions::default(),
+            bvh_cache_dir: None,
+        }
+    }
+}
+
+/// Renders a scene into a `Framebuffer`.
+///
+/// Settings not given to the builder take their values from `RenderSettings::default()`.
+pub struct Renderer {
     camera: Camera,
     objects: Vec<Object>,
     lights: Vec<Light>,
-    background_color: Color,
-    samples_per_pixel: u16,
-    max_ray_bounces: u16,
-    path: P,
-    num_threads: usize,
-) -> Result<(), Box<dyn Error>>
-where
-    P: AsRef<Path>,
-{
-    debug_assert!(samples_per_pixel != 0);
-    let instant = time::Instant::now();
-
-    let world = World::new(camera, objects, lights, background_color);
-    let world = Arc::new(world);
-
-    let (width, height) = (world.camera.width, world.camera.height);
-
-    let pool = ThreadPool::new(num_threads);
-    let (tx, rx) = mpsc::channel();
-    for x in 0..width {
-        let tx = tx.clone();
-        let world = Arc::clone(&world);
-        pool.execute(move || {
-            let colors = (0..height)
-                .map(|y| {
-                    let mut rng = {
-                        if samples_per_pixel == 1 {
-                            None
-                        } else {
-                            Some(rand::thread_rng())
+    settings: RenderSettings,
+}
+
+impl Renderer {
+    pub fn new(camera: Camera, objects: Vec<Object>, lights: Vec<Light>) -> Self {
+        Renderer {
+            camera,
+            objects,
+            lights,
+            settings: RenderSettings::default(),
+        }
+    }
+
+    pub fn settings(mut self, settings: RenderSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn background_color(mut self, background_color: Color) -> Self {
+        self.settings.background_color = background_color;
+        self
+    }
+
+    pub fn samples_per_pixel(mut self, samples_per_pixel: u16) -> Self {
+        self.settings.samples_per_pixel = samples_per_pixel;
+        self
+    }
+
+    pub fn max_ray_bounces(mut self, max_ray_bounces: u16) -> Self {
+        self.settings.max_ray_bounces = max_ray_bounces;
+        self
+    }
+
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.settings.num_threads = num_threads;
+        self
+    }
+
+    pub fn integrator(mut self, integrator: Integrator) -> Self {
+        self.settings.integrator = integrator;
+        self
+    }
+
+    pub fn accelerator(mut self, accelerator: AcceleratorType) -> Self {
+        self.settings.accelerator = accelerator;
+        self
+    }
+
+    pub fn bvh_build_options(mut self, bvh_build_options: BvhBuildOptions) -> Self {
+        self.settings.bvh_build_options = bvh_build_options;
+        self
+    }
+
+    pub fn bvh_cache_dir<P: Into<PathBuf>>(mut self, bvh_cache_dir: P) -> Self {
+        self.settings.bvh_cache_dir = Some(bvh_cache_dir.into());
+        self
+    }
+
+    /// Render the scene and return the resulting image.
+    pub fn render(self) -> Result<Framebuffer, Box<dyn Error>> {
+        self.render_with_bvh_report()
+            .map(|(framebuffer, _)| framebuffer)
+    }
+
+    /// Like `render`, but also returns statistics about the bvhs of the scene.
+    pub fn render_with_bvh_report(self) -> Result<(Framebuffer, BvhReport), Box<dyn Error>> {
+        let RenderSettings {
+            background_color,
+            samples_per_pixel,
+            max_ray_bounces,
+            num_threads,
+            integrator,
+            accelerator,
+            bvh_build_options,
+            bvh_cache_dir,
+        } = self.settings;
+        if samples_per_pixel == 0 {
+            return Err("samples_per_pixel must be at least 1".into());
+        }
+        if bvh_build_options.max_leaf_size == 0 {
+            return Err("max_leaf_size must be at least 1".into());
+        }
+        if bvh_build_options.num_buckets < 2 {
+            return Err("num_buckets must be at least 2".into());
+        }
+        if !matches!(bvh_build_options.width, 2 | 4 | 8) {
+            return Err("bvh width must be 2, 4, or 8".into());
+        }
+        let instant = time::Instant::now();
+
+        let accelerator = accelerator.build(
+            self.objects,
+            num_threads,
+            bvh_build_options,
+            bvh_cache_dir.as_deref(),
+        );
+        let world = World::new(self.camera, accelerator, self.lights, background_color);
+        let world = Arc::new(world);
+
+        let (width, height) = (world.camera.width, world.camera.height);
+
+        let pool = ThreadPool::new(num_threads);
+        let (tx, rx) = mpsc::channel();
+        for x in 0..width {
+            let tx = tx.clone();
+            let world = Arc::clone(&world);
+            pool.execute(move || {
+                let mut rng = {
+                    // A single sample through the center of a pinhole camera
+                    // needs no randomness.
+                    if samples_per_pixel == 1 && world.camera.aperture == 0.0 {
+                        None
+                    } else {
+                        Some(rand::thread_rng())
+                    }
+                };
+
+                let mut rgb_sums = vec![Vector4::new(0., 0., 0., 0.); height as usize];
+                if world.accelerator.traces_packets() {
+                    // Neighbouring pixels of the column are traced together, so that
+                    // their camera rays can share a packet.
+                    let pixels: Vec<u32> = (0..height).collect();
+                    for pixels in pixels.chunks(PACKET_SIZE) {
+                        for _ in 0..samples_per_pixel {
+                            let rays: Vec<Ray> = pixels
+                                .iter()
+                                .map(|&y| world.camera.generate_ray(x, y, rng.as_mut()))
+                                .collect();
+                            let colors = match integrator {
+                                Integrator::Whitted => world.trace_rays(&rays, max_ray_bounces),
+                                Integrator::PathTracing => world.trace_paths(
+                                    &rays,
+                                    max_ray_bounces,
+                                    &mut rand::thread_rng(),
+                                ),
+                            };
+                            for (&y, color) in pixels.iter().zip(colors) {
+                                rgb_sums[y as usize] += color.to_vec();
+                            }
+                        }
+                    }
+                } else {
+                    for (y, rgb_sum) in rgb_sums.iter_mut().enumerate() {
+                        for _ in 0..samples_per_pixel {
+                            let ray = world.camera.generate_ray(x, y as u32, rng.as_mut());
+                            let color = match integrator {
+                                Integrator::Whitted => world.trace_ray(&ray, max_ray_bounces),
+                                Integrator::PathTracing => {
+                                    world.trace_path(&ray, max_ray_bounces, &mut rand::thread_rng())
+                                }
+                            };
+                            *rgb_sum += color.to_vec();
                         }
-                    };
-
-                    let rgb_sum = (0..samples_per_pixel)
-                        .into_iter()
-                        .map(|_| {
-                            let ray = world.camera.generate_ray(x, y, rng.as_mut());
-                            let color = world.trace_ray(&ray, max_ray_bounces);
-                            color.to_vec()
-                        })
-                        .fold(Vector4::new(0., 0., 0., 0.), |acc, x| acc + x);
-                    let res = rgb_sum / samples_per_pixel.into();
-                    Color::rgba(res.x, res.y, res.z, res.w)
-                })
-                .collect();
-            tx.send((x, colors)).unwrap();
-        });
-    }
-
-    let mut pixels = vec![vec![Color::black(); height as usize]; width as usize];
-    for _ in 0..width {
-        let (x, colors) = rx.recv()?;
-        pixels[x as usize] = colors;
-    }
-
-    let image = image::ImageBuffer::from_fn(width, height, |x, y| {
-        let (r, g, b) = pixels[x as usize][y as usize].get_rgb();
-        let pixel: image::Rgb<_> = [r, g, b].into();
-        pixel
-    });
-    image.save(path)?;
-
-    debug!(
-        "Rendered image in {} seconds.",
-        instant.elapsed().as_seconds_f32()
-    );
-    Ok(())
+                    }
+                }
+                let colors: Vec<Color> = rgb_sums
+                    .into_iter()
+                    .map(|rgb_sum| {
+                        let res = rgb_sum / samples_per_pixel.into();
+                        Color::rgba(res.x, res.y, res.z, res.w)
+                    })
+                    .collect();
+                tx.send((x, colors)).unwrap();
+            });
+        }
+
+        let mut framebuffer = Framebuffer::new(width, height);
+        for _ in 0..width {
+            let (x, colors) = rx.recv()?;
+            for (y, color) in colors.into_iter().enumerate() {
+                framebuffer.set_pixel(x, y as u32, color);
+            }
+        }
+
+        debug!(
+            "Rendered image in {} seconds.",
+            instant.elapsed().as_seconds_f32()
+        );
+        Ok((framebuffer, world.accelerator.get_bvh_report()))
+    }
 }
 
 pub struct World {
     camera: Camera,
-    bvh: Bvh,
+    accelerator: Box<dyn Accelerator>,
     lights: Vec<Light>,
     background_color: Color,
 }
 
 impl World {
+    /// Creates a world whose objects are found by `accelerator`. See
+    /// `AcceleratorType::build`.
     pub fn new(
         camera: Camera,
-        objects: Vec<Object>,
+        accelerator: Box<dyn Accelerator>,
         lights: Vec<Light>,
         background_color: Color,
     ) -> World {
-        let bvh = Bvh::new(objects);
         World {
             camera,
-            bvh,
+            accelerator,
             lights,
             background_color,
         }
     }
 
+    pub fn get_accelerator(&self) -> &dyn Accelerator {
+        self.accelerator.as_ref()
+    }
+
     /// Trace a ray in the world and return the color it should produce.
     /// `max_depth` is the maximum number of bounces we should compute for this ray.
     pub fn trace_ray(&self, ray: &Ray, max_depth: u16) -> Color {
         if max_depth == 0 {
-            self.background_color
-        } else if let Some((object, t)) = self.bvh.get_closest_intersection(ray) {
-            // Compute the color of the object that the ray first hits.
-            let intersection_point = Point3::<f32>::from(ray.get_point_on_ray(t));
-            let illuminating_lights = self
-                .lights
-                .iter()
-                .filter(|light| light.reaches_point(intersection_point, &self.bvh))
-                .collect();
-            object.get_color(&ray, t, illuminating_lights, self, max_depth - 1)
+            return self.background_color;
+        }
+        let intersection = self.accelerator.get_closest_intersection(ray);
+        self.shade_ray(ray, intersection, max_depth)
+    }
+
+    /// Like `trace_ray` for each of `rays`, but finds what coherent rays like those
+    /// from the camera hit together. See `Accelerator::get_closest_intersections`.
+    pub fn trace_rays(&self, rays: &[Ray], max_depth: u16) -> Vec<Color> {
+        if max_depth == 0 {
+            return vec![self.background_color; rays.len()];
+        }
+        let intersections = self.accelerator.get_closest_intersections(rays);
+        rays.iter()
+            .zip(intersections)
+            .map(|(ray, intersection)| self.shade_ray(ray, intersection, max_depth))
+            .collect()
+    }
+
+    /// Returns the color that `ray` should produce given the first object it hits,
+    /// if any. See `trace_ray`.
+    fn shade_ray(
+        &self,
+        ray: &Ray,
+        intersection: Option<(Cow<'_, Object>, SurfaceInteraction)>,
+        max_depth: u16,
+    ) -> Color {
+        if let Some((object, interaction)) = intersection {
+            // Compute the color of the object that the ray first hits. Materials test
+            // which lights reach the point themselves, since area lights need a shadow
+            // test for each of their samples.
+            let lights = self.lights.iter().collect();
+            object.get_color(ray, &interaction, lights, self, max_depth - 1)
         } else {
             // If the ray hits nothing, return the background color.
             self.background_color
         }
     }
+
+    /// Trace a path starting with `ray` through the world using Monte Carlo path
+    /// tracing and return an estimate of the color it should produce.
+    /// `max_depth` is the maximum number of bounces of the path.
+    ///
+    /// At every bounce, the light reaching the point directly from each light is added
+    /// (next event estimation), then the path continues in a direction sampled from
+    /// the BSDF of the object it hit. Ambient lights are ignored since paths that
+    /// leave the scene already collect the background color.
+    pub fn trace_path<R: Rng>(&self, ray: &Ray, max_depth: u16, rng: &mut R) -> Color {
+        if max_depth == 0 {
+            return self.background_color;
+        }
+        let intersection = self.accelerator.get_closest_intersection(ray);
+        self.continue_path(ray, intersection, max_depth, rng)
+    }
+
+    /// Like `trace_path` for each of `rays`, but finds what coherent rays like those
+    /// from the camera hit first together. Later bounces are traced one at a time.
+    pub fn trace_paths<R: Rng>(&self, rays: &[Ray], max_depth: u16, rng: &mut R) -> Vec<Color> {
+        if max_depth == 0 {
+            return vec![self.background_color; rays.len()];
+        }
+        let intersections = self.accelerator.get_closest_intersections(rays);
+        rays.iter()
+            .zip(intersections)
+            .map(|(ray, intersection)| self.continue_path(ray, intersection, max_depth, rng))
+            .collect()
+    }
+
+    /// Traces the rest of the path that starts with `ray`, given the first object it
+    /// hits, if any. See `trace_path`.
+    fn continue_path<R: Rng>(
+        &self,
+        ray: &Ray,
+        intersection: Option<(Cow<'_, Object>, SurfaceInteraction)>,
+        max_depth: u16,
+        rng: &mut R,
+    ) -> Color {
+        let mut intersection = intersection;
+        let background_color = self.background_color.to_vec().truncate();
+        // BSDF weights are given per channel as vectors, so keep the throughput and
+        // radiance as vectors too.
+        let mut throughput = Vector3::new(1.0, 1.0, 1.0);
+        let mut radiance = Vector3::zero();
+        let mut ray = ray.clone();
+        for depth in 0..max_depth {
+            let (object, interaction) = match intersection.take() {
+                Some(intersection) => intersection,
+                None => {
+                    radiance += throughput.mul_element_wise(background_color);
+                    break;
+                }
+            };
+            let intersection_point = interaction.point;
+
+            for light in &self.lights {
+                for sample in light.get_samples(intersection_point, self.get_accelerator(), rng) {
+                    let bsdf = object.eval_bsdf(&ray, &interaction, sample.direction);
+                    let light_color = sample.color.to_vec().truncate();
+                    radiance += throughput
+                        .mul_element_wise(bsdf)
+                        .mul_element_wise(light_color);
+                }
+            }
+
+            let sample = match object.sample_bsdf(&ray, &interaction, rng) {
+                Some(sample) => sample,
+                None => break,
+            };
+            throughput.mul_assign_element_wise(sample.weight);
+
+            if depth + 1 >= RUSSIAN_ROULETTE_DEPTH {
+                // Randomly terminate paths that carry little light, and reweight the
+                // survivors so that the estimate stays unbiased.
+                let survival_probability =
+                    throughput.x.max(throughput.y).max(throughput.z).min(1.0);
+                if rng.gen::<f32>() >= survival_probability {
+                    break;
+                }
+                throughput /= survival_probability;
+            }
+
+            // We move the ray forward slightly so that we don't intersect the same location.
+            ray = Ray::new(intersection_point, sample.direction).offset(1e-4);
+            intersection = self.accelerator.get_closest_intersection(&ray);
+        }
+        Color::rgb(radiance.x, radiance.y, radiance.z)
+    }
+}
+
+/// The number of bounces after which paths may be terminated by Russian roulette.
+const RUSSIAN_ROULETTE_DEPTH: u16 = 3;
+
+#[cfg(test)]
+mod tests {
+    use super::{Integrator, Renderer, World};
+    use crate::bvh::{Bvh, BvhBuildOptions};
+    use crate::color::Color;
+    use crate::light::Light;
+    use crate::material::{Material, MaterialType, TextureType};
+    use crate::object::Object;
+    use crate::ray::Ray;
+    use crate::scene::default_camera;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_render() {
+        let material = Material::new(MaterialType::None, TextureType::None);
+        let sphere = Object::new_sphere((0.0, 0.0, 0.0).into(), 1.0, material);
+        let light = Light::new_ambient(Color::white());
+        let framebuffer = Renderer::new(default_camera(16, 8), vec![sphere], vec![light])
+            .background_color(Color::black())
+            .num_threads(2)
+            .render()
+            .unwrap();
+        assert_eq!(framebuffer.get_width(), 16);
+        assert_eq!(framebuffer.get_height(), 8);
+        assert_eq!(framebuffer.get_pixel(0, 0).get_rgb(), (0, 0, 0));
+        assert_eq!(framebuffer.get_pixel(0, 4).get_rgb(), (0, 0, 0));
+        assert_ne!(framebuffer.get_pixel(8, 4).get_rgb(), (0, 0, 0));
+
+        let framebuffer = Renderer::new(default_camera(16, 16), vec![], vec![])
+            .background_color(Color::white())
+            .integrator(Integrator::PathTracing)
+            .render()
+            .unwrap();
+        assert_eq!(framebuffer.get_pixel(8, 8).get_rgb(), (255, 255, 255));
+
+        let renderer = Renderer::new(default_camera(16, 16), vec![], vec![]).samples_per_pixel(0);
+        assert!(renderer.render().is_err());
+        let renderer = Renderer::new(default_camera(16, 16), vec![], vec![]).bvh_build_options(
+            BvhBuildOptions {
+                width: 3,
+                ..Default::default()
+            },
+        );
+        assert!(renderer.render().is_err());
+    }
+
+    #[test]
+    fn test_trace_path() {
+        let white = Material::new(
+            MaterialType::new_phong(1.0, 0.0, 1.0),
+            TextureType::new_flat(Color::white()),
+        );
+        let red = Material::new(
+            MaterialType::new_phong(1.0, 0.0, 1.0),
+            TextureType::new_flat(Color::red()),
+        );
+        // A white floor next to a red wall, lit from above.
+        let floor = Object::new_quad(
+            (-1.0, 0.0, 1.0).into(),
+            (1.0, 0.0, 1.0).into(),
+            (1.0, 0.0, -1.0).into(),
+            (-1.0, 0.0, -1.0).into(),
+            white,
+        );
+        let wall = Object::new_quad(
+            (-1.0, 0.0, -1.0).into(),
+            (1.0, 0.0, -1.0).into(),
+            (1.0, 2.0, -1.0).into(),
+            (-1.0, 2.0, -1.0).into(),
+            red,
+        );
+        let light = Light::new_point((0.0, 2.0, 0.0).into(), Color::grayscale(0.5));
+        let world = World::new(
+            default_camera(16, 16),
+            Box::new(Bvh::new(vec![floor, wall])),
+            vec![light],
+            Color::black(),
+        );
+        let mut rng = StdRng::seed_from_u64(248);
+
+        let ray = Ray::new((0.0, 1.0, 1.0).into(), (0.0, 1.0, 1.0).into());
+        let color = world.trace_path(&ray, 4, &mut rng).to_vec();
+        assert_eq!(color.truncate(), (0.0, 0.0, 0.0).into());
+
+        // Light bounces off the red wall onto the floor, so the floor near the wall
+        // should be tinted red with indirect lighting but not with direct lighting.
+        let ray = Ray::new((0.0, 1.0, 0.0).into(), (0.0, -1.0, -0.9).into());
+        let n = 1000;
+        let mut average = |max_depth| {
+            (0..n)
+                .map(|_| world.trace_path(&ray, max_depth, &mut rng).to_vec())
+                .sum::<cgmath::Vector4<f32>>()
+                / n as f32
+        };
+        let direct = average(1);
+        assert!((direct.x - direct.y).abs() < 1e-4);
+        let indirect = average(4);
+        assert!(indirect.x > indirect.y + 0.01);
+        assert!(indirect.y >= direct.y - 1e-4);
+    }
 }